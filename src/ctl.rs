@@ -0,0 +1,511 @@
+//! Control socket, which drives the running Koto without knowing its mount point or PID.
+//!
+//! A request is a line like `get /src0.seq/osc.saw/freq.val`, and `load` is followed by
+//! lines of a configuration and a line `.`. A response is `ok` or `error MESSAGE`, lines of
+//! the result and a line `.`. Lines starting with `.` are escaped with one more `.`.
+//!
+//! - `save`: saves the configuration like SIGUSR1, and returns the file name
+//! - `load`: replaces the unit graph with the following configuration
+//! - `get PATH`: contents of a file, or names in a directory
+//! - `set PATH VALUE`: writes VALUE into a file, or touches a directory
+//! - `status`: PID, mount point, transport and so on
+//! - `tree`: the node tree except `.koto`
+//! - `eval`: evaluates the following TapirLisp code in the running environment, like the REPL
+
+use std::fs::{DirBuilder, Permissions};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use fuse::FileType;
+use users::get_current_uid;
+
+use tapirus::musical_time::time::Transport;
+use tapirus::tapirlisp as tlisp;
+use tapirus::tapirlisp::types::{Env, Value};
+use tapirus::ugens::core::Aug;
+
+use crate::graph::Graph;
+use crate::kotofs::KotoFS;
+use crate::kotonode::{KotoNode, Ugen};
+use crate::save;
+
+/// The directory for the socket in the temporary directory, if `$XDG_RUNTIME_DIR` is not set.
+fn private_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("koto-{}", get_current_uid()))
+}
+
+/// Returns the socket path used if it's not specified.
+///
+/// It's in `$XDG_RUNTIME_DIR`, or in a directory only the user can access in the temporary
+/// directory, so other users cannot take the path first.
+pub fn default_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if dir.len() > 0 => PathBuf::from(dir).join("koto.sock"),
+        _ => private_dir().join("koto.sock"),
+    }
+}
+
+fn permission_denied(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::PermissionDenied, message)
+}
+
+/// Makes `dir` which only the user can access, or checks it if it exists.
+fn make_private_dir(dir: &Path) -> std::io::Result<()> {
+    if !dir.exists() {
+        return DirBuilder::new().mode(0o700).create(dir);
+    }
+    let meta = std::fs::metadata(dir)?;
+    if meta.uid() != get_current_uid() || meta.mode() & 0o077 != 0 {
+        return Err(permission_denied(
+            "the socket directory is accessible by others",
+        ));
+    }
+    Ok(())
+}
+
+/// Writes `text` and the terminating line `.`.
+fn write_lines<W: Write>(w: &mut W, text: &str) -> std::io::Result<()> {
+    for line in text.lines() {
+        if line.starts_with('.') {
+            w.write_all(b".")?;
+        }
+        w.write_all(line.as_bytes())?;
+        w.write_all(b"\n")?;
+    }
+    w.write_all(b".\n")
+}
+
+/// Reads lines until the terminating line `.`.
+fn read_lines<R: BufRead>(r: &mut R) -> std::io::Result<String> {
+    let mut text = String::new();
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if line == "." {
+            return Ok(text);
+        }
+        text.push_str(if line.starts_with('.') {
+            &line[1..]
+        } else {
+            line
+        });
+        text.push_str("\n");
+    }
+}
+
+fn root_ug(fs: &KotoFS) -> Option<Aug> {
+    match &fs.root.lock().unwrap().ug {
+        Ugen::Mapped(aug) => Some(aug.clone()),
+        _ => None,
+    }
+}
+
+fn tree(node: Arc<Mutex<KotoNode>>, indent: &str, text: &mut String) {
+    for (name, child) in KotoNode::get_children(node) {
+        if name.starts_with('.') {
+            continue;
+        }
+        let (kind, link) = {
+            let child = child.lock().unwrap();
+            (child.attr.kind, child.link.clone())
+        };
+        match kind {
+            FileType::Directory => {
+                text.push_str(&format!("{}{}/\n", indent, name));
+                tree(child, &format!("{}  ", indent), text);
+            }
+            FileType::Symlink => {
+                let link = link.map(|l| l.to_string_lossy().to_string());
+                let link = link.unwrap_or("".to_string());
+                text.push_str(&format!("{}{} -> {}\n", indent, name, link));
+            }
+            _ => text.push_str(&format!("{}{}\n", indent, name)),
+        }
+    }
+}
+
+/// The running Koto which the control socket and the REPL drive.
+#[derive(Clone)]
+pub struct Control {
+    pub fs: Arc<Mutex<KotoFS>>,
+    /// The environment of the running configuration, to save `$vars` with their names.
+    pub env: Arc<Mutex<Env>>,
+    pub mountpoint: String,
+    pub canonical: bool,
+}
+
+impl Control {
+    fn save(&self) -> Result<String, String> {
+        let (ug, lock, saved, transport) = {
+            let fs = self.fs.lock().unwrap();
+            let ug = root_ug(&fs).ok_or("no unit graph".to_string())?;
+            (ug, fs.lock.clone(), fs.saved.clone(), fs.transport.clone())
+        };
+        let mut env = self.env.lock().unwrap();
+        env.transport = transport.lock().unwrap().clone();
+        match save::save_file(ug, &env, lock, saved, self.canonical) {
+            Ok(filename) => Ok(format!("{}\n", filename)),
+            Err(err) => Err(format!("cannot save: {:?}", err)),
+        }
+    }
+
+    fn load(&self, config: String) -> Result<String, String> {
+        let transport = self.fs.lock().unwrap().transport.clone();
+        let sample_rate = transport.lock().unwrap().sample_rate;
+        let mut env = Env::init(Transport::new(sample_rate));
+
+        let forms = match tlisp::sexp::read(config) {
+            Ok(forms) => forms,
+            Err(err) => return Err(format!("cannot read: {:?}", err)),
+        };
+        let ug = match tlisp::eval_all(forms, &mut env) {
+            Ok(Value::Unit(ug)) => ug,
+            Ok(_) => return Err("the configuration is not a unit".to_string()),
+            Err(err) => return Err(format!("cannot evaluate: {:?}", err)),
+        };
+
+        {
+            let mut fs = self.fs.lock().unwrap();
+            if !fs.set_root(ug.clone()) {
+                return Err("the root unit is not the same type as the running one".to_string());
+            }
+            *fs.saved.lock().unwrap() = Graph::build(ug);
+        }
+        {
+            let mut transport = transport.lock().unwrap();
+            transport.bpm = env.transport.bpm;
+            transport.measure.beat = env.transport.measure.beat;
+            transport.measure.note = env.transport.measure.note;
+        }
+        *self.env.lock().unwrap() = env;
+        Ok("".to_string())
+    }
+
+    fn get(&self, path: &str) -> Result<String, String> {
+        let mut fs = self.fs.lock().unwrap();
+        let node = fs
+            .lookup_path(path)
+            .ok_or(format!("{} is not found", path))?;
+        let kind = node.lock().unwrap().attr.kind;
+        let node = match kind {
+            FileType::Symlink => KotoNode::resolve_symlink(node),
+            _ => Some(node),
+        };
+        let node = node.ok_or(format!("{} is a broken link", path))?;
+
+        let kind = node.lock().unwrap().attr.kind;
+        match kind {
+            FileType::Directory => {
+                let mut text = String::new();
+                for (name, child) in KotoNode::get_children(node) {
+                    match child.lock().unwrap().attr.kind {
+                        FileType::Directory => text.push_str(&format!("{}/\n", name)),
+                        _ => text.push_str(&format!("{}\n", name)),
+                    }
+                }
+                Ok(text)
+            }
+            _ => {
+                fs.refresh(node.clone());
+                Ok(String::from_utf8_lossy(&node.lock().unwrap().data).to_string())
+            }
+        }
+    }
+
+    fn set(&self, path: &str, value: &str) -> Result<String, String> {
+        let mut fs = self.fs.lock().unwrap();
+        let node = fs
+            .lookup_path(path)
+            .ok_or(format!("{} is not found", path))?;
+        let kind = node.lock().unwrap().attr.kind;
+        match kind {
+            FileType::RegularFile => match fs.replace(node, format!("{}\n", value).into_bytes()) {
+                Ok(()) => Ok("".to_string()),
+                Err(_) => Err(format!("{} is read-only", path)),
+            },
+            FileType::Directory => {
+                fs.apply(node);
+                Ok("".to_string())
+            }
+            _ => Err(format!("cannot write {}", path)),
+        }
+    }
+
+    fn status(&self) -> Result<String, String> {
+        let fs = self.fs.lock().unwrap();
+        let modules = match root_ug(&fs) {
+            Some(ug) => Graph::build(ug).modules.len(),
+            None => 0,
+        };
+        let transport = fs.transport.lock().unwrap();
+        let scheduler = fs.scheduler.lock().unwrap();
+        let lines = vec![
+            format!("pid {}", std::process::id()),
+            format!("mountpoint {}", self.mountpoint),
+            format!("sample-rate {}", transport.sample_rate),
+            format!("bpm {}", transport.bpm),
+            format!(
+                "measure {}/{}",
+                transport.measure.beat, transport.measure.note
+            ),
+            format!("quantize {}", scheduler.quantize),
+            format!("transaction {}", scheduler.txn.is_some()),
+            format!("modules {}", modules),
+        ];
+        Ok(format!("{}\n", lines.join("\n")))
+    }
+
+    fn tree(&self) -> Result<String, String> {
+        let root = self.fs.lock().unwrap().root.clone();
+        let mut text = String::new();
+        tree(root, "", &mut text);
+        Ok(text)
+    }
+
+    /// Evaluates TapirLisp code in the running environment. `(bpm ...)` and `(measure ...)`
+    /// change the running transport.
+    fn eval_lisp(&self, code: &str) -> Result<Value, String> {
+        let transport = self.fs.lock().unwrap().transport.clone();
+        let mut env = self.env.lock().unwrap();
+        env.transport = transport.lock().unwrap().clone();
+
+        let forms = match tlisp::sexp::read(code.to_string()) {
+            Ok(forms) => forms,
+            Err(err) => return Err(format!("cannot read: {:?}", err)),
+        };
+        let value = match tlisp::eval_all(forms, &mut env) {
+            Ok(value) => value,
+            Err(err) => return Err(format!("cannot evaluate: {:?}", err)),
+        };
+
+        let mut transport = transport.lock().unwrap();
+        transport.bpm = env.transport.bpm;
+        transport.measure.beat = env.transport.measure.beat;
+        transport.measure.note = env.transport.measure.note;
+        Ok(value)
+    }
+
+    fn eval_unit(&self, code: &str) -> Result<Aug, String> {
+        match self.eval_lisp(code)? {
+            Value::Unit(ug) => Ok(ug),
+            _ => Err(format!("{} is not a unit", code)),
+        }
+    }
+
+    /// Prints the unit at `path`, or the file if it's not a unit.
+    fn inspect(&self, path: &str) -> Result<String, String> {
+        let node = self
+            .fs
+            .lock()
+            .unwrap()
+            .lookup_path(path)
+            .ok_or(format!("{} is not found", path))?;
+        let kind = node.lock().unwrap().attr.kind;
+        let node = match kind {
+            FileType::Symlink => KotoNode::resolve_symlink(node),
+            _ => Some(node),
+        };
+        let node = node.ok_or(format!("{} is a broken link", path))?;
+
+        let ug = match &node.lock().unwrap().ug {
+            Ugen::Mapped(aug) => Some(aug.clone()),
+            _ => None,
+        };
+        match ug {
+            Some(ug) => Ok(save::to_form(ug, &self.env.lock().unwrap())),
+            None => self.get(path),
+        }
+    }
+
+    /// Prints `$vars` bound to units.
+    fn vars(&self) -> Result<String, String> {
+        let env = self.env.lock().unwrap();
+        let mut names: Vec<&String> = env.binds.keys().collect();
+        names.sort();
+        let mut text = String::new();
+        for name in names {
+            if let Value::Unit(ug) = &*env.binds[name] {
+                text.push_str(&format!("{} = {}", name, save::to_form(ug.clone(), &env)));
+            }
+        }
+        Ok(text)
+    }
+
+    /// Evaluates a line of the REPL. Lines starting with `:` are commands of Koto:
+    ///
+    /// - `:root`, `:get PATH`: prints the root unit, or the unit at PATH
+    /// - `:root FORM`: connects parameters of FORM to the root unit, which has the same type
+    /// - `:set PATH FORM`: connects FORM to the parameter at PATH like `/src0.seq/osc`
+    /// - `:vars`: prints `$vars` defined by `def`
+    pub fn eval(&self, code: &str) -> Result<String, String> {
+        let code = code.trim();
+        if !code.starts_with(':') {
+            return match self.eval_lisp(code)? {
+                Value::Unit(ug) => Ok(save::to_form(ug, &self.env.lock().unwrap())),
+                _ => Ok("".to_string()),
+            };
+        }
+
+        let mut words = code[1..].splitn(2, char::is_whitespace);
+        let command = words.next().unwrap_or("");
+        let arg = words.next().unwrap_or("").trim();
+        match (command, arg) {
+            ("root", "") => self.inspect("/"),
+            ("root", form) => {
+                let ug = self.eval_unit(form)?;
+                if self.fs.lock().unwrap().set_root(ug) {
+                    Ok("".to_string())
+                } else {
+                    Err("the root unit is not the same type as the running one".to_string())
+                }
+            }
+            ("get", path) if path.len() > 0 => self.inspect(path),
+            ("set", arg) if arg.len() > 0 => {
+                let mut words = arg.splitn(2, char::is_whitespace);
+                let path = words.next().unwrap_or("");
+                let form = words.next().unwrap_or("").trim();
+                let ug = self.eval_unit(form)?;
+                if self.fs.lock().unwrap().connect(path, ug) {
+                    Ok("".to_string())
+                } else {
+                    Err(format!("cannot set {}", path))
+                }
+            }
+            ("vars", "") => self.vars(),
+            _ => Err(format!("invalid command: {}", code)),
+        }
+    }
+
+    fn serve(&self, stream: UnixStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut stream = stream;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let mut words = line.trim().splitn(3, ' ');
+            let command = words.next().unwrap_or("");
+            let args: Vec<&str> = words.collect();
+            let result = match (command, &args[..]) {
+                ("save", []) => self.save(),
+                ("load", []) => {
+                    let config = read_lines(&mut reader)?;
+                    self.load(config)
+                }
+                ("get", [path]) => self.get(path),
+                ("set", [path, value]) => self.set(path, value),
+                ("status", []) => self.status(),
+                ("tree", []) => self.tree(),
+                ("eval", []) => {
+                    let code = read_lines(&mut reader)?;
+                    self.eval(&code)
+                }
+                _ => Err(format!("invalid request: {}", line.trim())),
+            };
+
+            match result {
+                Ok(text) => {
+                    stream.write_all(b"ok\n")?;
+                    write_lines(&mut stream, &text)?;
+                }
+                Err(message) => {
+                    let message = message.replace('\n', " ");
+                    stream.write_all(format!("error {}\n", message).as_bytes())?;
+                    write_lines(&mut stream, "")?;
+                }
+            }
+        }
+    }
+}
+
+/// Starts the control socket at `path` in another thread.
+pub fn start(control: Control, path: &Path) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        if !dir.exists() || dir == private_dir() {
+            make_private_dir(dir)?;
+        }
+    }
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(std::io::ErrorKind::AddrInUse.into());
+        }
+        // left by Koto which didn't exit normally
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+    let control = Arc::new(control);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let control = control.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = control.serve(stream) {
+                    println!("control connection is closed");
+                    println!("{:?}", err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// A connection to the control socket of the running Koto.
+pub struct Client {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl Client {
+    /// Connects to the socket at `path`, which must be made by the same user.
+    pub fn connect(path: &Path) -> std::io::Result<Client> {
+        if std::fs::metadata(path)?.uid() != get_current_uid() {
+            return Err(permission_denied("the socket is owned by another user"));
+        }
+        let stream = UnixStream::connect(path)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Client {
+            stream: stream,
+            reader: reader,
+        })
+    }
+
+    /// Sends a request and returns the result, or the error message from Koto.
+    pub fn request(
+        &mut self,
+        line: &str,
+        body: Option<&str>,
+    ) -> std::io::Result<Result<String, String>> {
+        self.stream.write_all(format!("{}\n", line).as_bytes())?;
+        if let Some(body) = body {
+            write_lines(&mut self.stream, body)?;
+        }
+        let mut status = String::new();
+        self.reader.read_line(&mut status)?;
+        let text = read_lines(&mut self.reader)?;
+        match status.trim() {
+            "ok" => Ok(Ok(text)),
+            status => Ok(Err(status.trim_start_matches("error ").to_string())),
+        }
+    }
+}
+
+/// Sends a request to the running Koto, and prints the result.
+pub fn request(path: &Path, line: &str, body: Option<&str>) {
+    match Client::connect(path).and_then(|mut client| client.request(line, body)) {
+        Ok(Ok(text)) => print!("{}", text),
+        Ok(Err(message)) => println!("{}", message),
+        Err(err) => {
+            println!("cannot connect to Koto at {:?}", path);
+            println!("{:?}", err);
+        }
+    }
+}
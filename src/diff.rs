@@ -0,0 +1,249 @@
+//! Differences between two unit graphs, in paths of the mount.
+
+use std::collections::HashSet;
+
+use crate::graph::{Graph, Input};
+
+/// Returns a short description of a parameter, like its value or its module type.
+fn describe(graph: &Graph, input: &Input) -> String {
+    match input {
+        Input::Number(n) => n.to_string(),
+        Input::Table(vec) => format!("table[{}]", vec.len()),
+        Input::Pattern(items) => items.join(" "),
+        Input::Module(id) => graph.modules[*id].typename.clone(),
+    }
+}
+
+/// Returns the file name of a parameter, like `freq.val`, `osc.saw` or `src0.sampler`.
+fn filename(graph: &Graph, name: &str, input: &Input) -> String {
+    let ext = match input {
+        Input::Number(_) => "val",
+        Input::Table(_) => "tab",
+        Input::Pattern(_) => "pat",
+        Input::Module(id) => &graph.modules[*id].typename,
+    };
+    format!("{}.{}", name, ext)
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.len() == 0 {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+struct Differ<'a> {
+    old: &'a Graph,
+    new: &'a Graph,
+    visited: HashSet<(usize, usize)>,
+    changes: Vec<String>,
+}
+
+impl<'a> Differ<'a> {
+    fn module(&mut self, old_id: usize, new_id: usize, dir: &str) {
+        // shared modules are compared once
+        if !self.visited.insert((old_id, new_id)) {
+            return;
+        }
+        let old = &self.old.modules[old_id];
+        let new = &self.new.modules[new_id];
+
+        for (name, old_input) in old.params.iter() {
+            match new.params.iter().find(|(n, _)| n == name) {
+                Some((_, new_input)) => self.param(name, old_input, new_input, dir),
+                None => self.changes.push(format!(
+                    "{}: removed",
+                    join(dir, &filename(self.old, name, old_input))
+                )),
+            }
+        }
+        for (name, new_input) in new.params.iter() {
+            if !old.params.iter().any(|(n, _)| n == name) {
+                self.changes.push(format!(
+                    "{}: added",
+                    join(dir, &filename(self.new, name, new_input))
+                ));
+            }
+        }
+    }
+
+    fn param(&mut self, name: &str, old: &Input, new: &Input, dir: &str) {
+        let path = join(dir, &filename(self.new, name, new));
+        match (old, new) {
+            (Input::Number(a), Input::Number(b)) if a == b => (),
+            (Input::Table(a), Input::Table(b)) if a == b => (),
+            (Input::Pattern(a), Input::Pattern(b)) if a == b => (),
+            (Input::Table(a), Input::Table(b)) if a.len() == b.len() => {
+                self.changes.push(format!("{}: changed", path))
+            }
+            (Input::Module(a), Input::Module(b))
+                if self.old.modules[*a].typename == self.new.modules[*b].typename =>
+            {
+                self.module(*a, *b, &path)
+            }
+            (Input::Number(_), Input::Number(_))
+            | (Input::Table(_), Input::Table(_))
+            | (Input::Pattern(_), Input::Pattern(_)) => self.changes.push(format!(
+                "{}: {} -> {}",
+                path,
+                describe(self.old, old),
+                describe(self.new, new)
+            )),
+            _ => self.changes.push(format!(
+                "{}: {} -> {}",
+                join(dir, name),
+                filename(self.old, name, old),
+                filename(self.new, name, new)
+            )),
+        }
+    }
+}
+
+/// Returns changes from `old` to `new`, one for each line.
+pub fn diff(old: &Graph, new: &Graph) -> Vec<String> {
+    let mut differ = Differ {
+        old: old,
+        new: new,
+        visited: HashSet::new(),
+        changes: Vec::new(),
+    };
+    if old.modules[0].typename != new.modules[0].typename {
+        differ.changes.push(format!(
+            "/: {} -> {}",
+            old.modules[0].typename, new.modules[0].typename
+        ));
+    } else {
+        differ.module(0, 0, "");
+    }
+    differ.changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::Module;
+
+    fn module(typename: &str, params: Vec<(&str, Input)>) -> Module {
+        Module {
+            typename: typename.to_string(),
+            path: "".to_string(),
+            params: params
+                .into_iter()
+                .map(|(name, input)| (name.to_string(), input))
+                .collect(),
+        }
+    }
+
+    /// `(out 0.25 (seq (pat ...) (saw 0 440) ...))`
+    fn graph(vol: f64, freq: f64, pattern: &[&str], osc: &str) -> Graph {
+        let items = pattern.iter().map(|s| s.to_string()).collect();
+        Graph {
+            modules: vec![
+                module(
+                    "out",
+                    vec![("vol", Input::Number(vol)), ("src0", Input::Module(1))],
+                ),
+                module(
+                    "seq",
+                    vec![
+                        ("pattern", Input::Pattern(items)),
+                        ("osc", Input::Module(2)),
+                    ],
+                ),
+                module(
+                    osc,
+                    vec![
+                        ("init_ph", Input::Number(0.0)),
+                        ("freq", Input::Number(freq)),
+                    ],
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_no_changes() {
+        let g = graph(0.25, 440.0, &["(a4 2)"], "saw");
+        assert_eq!(diff(&g, &g.clone()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_values() {
+        let old = graph(0.25, 440.0, &["(a4 2)"], "saw");
+        let new = graph(0.5, 220.0, &["(a4 2)", "(c4 1)"], "saw");
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                "vol.val: 0.25 -> 0.5",
+                "src0.seq/pattern.pat: (a4 2) -> (a4 2) (c4 1)",
+                "src0.seq/osc.saw/freq.val: 440 -> 220",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_module_types() {
+        let old = graph(0.25, 440.0, &["(a4 2)"], "saw");
+        let new = graph(0.25, 440.0, &["(a4 2)"], "tri");
+        assert_eq!(diff(&old, &new), vec!["src0.seq/osc: osc.saw -> osc.tri"]);
+
+        let mut new = old.clone();
+        new.modules[0].typename = "pan".to_string();
+        assert_eq!(diff(&old, &new), vec!["/: out -> pan"]);
+
+        // samplers are dumped as wavetables, but graphs have their directory types
+        let old = graph(0.25, 440.0, &["(a4 2)"], "wavetable");
+        let new = graph(0.25, 440.0, &["(a4 2)"], "sampler");
+        assert_eq!(
+            diff(&old, &new),
+            vec!["src0.seq/osc: osc.wavetable -> osc.sampler"]
+        );
+    }
+
+    #[test]
+    fn test_added_and_removed() {
+        let old = graph(0.25, 440.0, &["(a4 2)"], "saw");
+        let mut new = old.clone();
+        new.modules[0]
+            .params
+            .push(("src1".to_string(), Input::Module(2)));
+        new.modules[2].params.remove(0);
+        assert_eq!(
+            diff(&old, &new),
+            vec!["src0.seq/osc.saw/init_ph.val: removed", "src1.saw: added"]
+        );
+    }
+
+    #[test]
+    fn test_tables() {
+        let mut old = graph(0.25, 440.0, &["(a4 2)"], "wavetable");
+        old.modules[2].params[0] = ("table".to_string(), Input::Table(vec![0.0, 1.0]));
+        let mut new = old.clone();
+        new.modules[2].params[0] = ("table".to_string(), Input::Table(vec![0.0, 0.5]));
+        assert_eq!(
+            diff(&old, &new),
+            vec!["src0.seq/osc.wavetable/table.tab: changed"]
+        );
+
+        new.modules[2].params[0] = ("table".to_string(), Input::Table(vec![0.0]));
+        assert_eq!(
+            diff(&old, &new),
+            vec!["src0.seq/osc.wavetable/table.tab: table[2] -> table[1]"]
+        );
+    }
+
+    #[test]
+    fn test_shared_modules_are_compared_once() {
+        let mut old = graph(0.25, 440.0, &["(a4 2)"], "saw");
+        old.modules[0]
+            .params
+            .push(("src1".to_string(), Input::Module(1)));
+        let mut new = old.clone();
+        new.modules[2].params[1] = ("freq".to_string(), Input::Number(220.0));
+        assert_eq!(
+            diff(&old, &new),
+            vec!["src0.seq/osc.saw/freq.val: 440 -> 220"]
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use tapirus::musical_time::time::Transport;
+
+#[derive(Debug)]
+pub enum ExprError {
+    UnexpectedChar(char),
+    UnexpectedToken(String),
+    UnexpectedEnd,
+    UnknownUnit(String),
+    InvalidNote(String),
+    AmbiguousFraction(String),
+    DivisionByZero,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Note(f64),
+    Word(String),
+    Op(char),
+    Open,
+    Close,
+}
+
+const NOTE_NAMES: [(char, i32); 7] = [
+    ('c', 0),
+    ('d', 2),
+    ('e', 4),
+    ('f', 5),
+    ('g', 7),
+    ('a', 9),
+    ('b', 11),
+];
+
+pub fn midi_to_freq(midi: f64) -> f64 {
+    440.0 * 2.0f64.powf((midi - 69.0) / 12.0)
+}
+
+/// Converts note names like `a4`, `c#3`, `bb2` or `a+4` into a MIDI note number.
+pub fn note_to_midi(name: &str) -> Option<i32> {
+    let chars: Vec<char> = name.chars().collect();
+    if chars.len() < 2 {
+        return None;
+    }
+
+    let letter = chars[0].to_ascii_lowercase();
+    let (_, degree) = NOTE_NAMES.iter().find(|(c, _)| *c == letter)?;
+    let (accidental, rest) = match chars[1] {
+        '#' | '+' => (1, &chars[2..]),
+        'b' | '-' => (-1, &chars[2..]),
+        _ => (0, &chars[1..]),
+    };
+    if rest.len() == 0 || !rest.iter().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let octave: i32 = rest.iter().collect::<String>().parse().ok()?;
+
+    Some((octave + 1) * 12 + degree + accidental)
+}
+
+const SHARP_NAMES: [&str; 12] = [
+    "c", "c+", "d", "d+", "e", "f", "f+", "g", "g+", "a", "a+", "b",
+];
+
+/// The MIDI note number of `c0`, the lowest note which has a name in patterns.
+pub const LOWEST_NOTE: i32 = 12;
+
+/// Converts a MIDI note number into a note name of patterns like `a4` or `c+3`.
+///
+/// Notes below `c0` have no names, since `c-1` reads as c flat 1.
+pub fn midi_to_note(midi: i32) -> Option<String> {
+    if midi < LOWEST_NOTE {
+        return None;
+    }
+    Some(format!(
+        "{}{}",
+        SHARP_NAMES[midi.rem_euclid(12) as usize],
+        midi.div_euclid(12) - 1
+    ))
+}
+
+/// Raises notes below `c0` by octaves, so they have names.
+pub fn lift_octaves(midi: i32) -> i32 {
+    if midi < LOWEST_NOTE {
+        LOWEST_NOTE + midi.rem_euclid(12)
+    } else {
+        midi
+    }
+}
+
+fn is_note_start(chars: &[char], pos: usize) -> bool {
+    if !NOTE_NAMES
+        .iter()
+        .any(|(c, _)| *c == chars[pos].to_ascii_lowercase())
+    {
+        return false;
+    }
+    let mut i = pos + 1;
+    if i < chars.len() && "#+b-".contains(chars[i]) {
+        i += 1;
+    }
+    i < chars.len() && chars[i].is_ascii_digit()
+}
+
+fn read_digits(chars: &[char], pos: &mut usize) -> String {
+    let mut s = String::new();
+    while *pos < chars.len() && (chars[*pos].is_ascii_digit() || chars[*pos] == '.') {
+        s.push(chars[*pos]);
+        *pos += 1;
+    }
+    s
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, ExprError> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let num = read_digits(&chars, &mut pos);
+            let mut value: f64 = num
+                .parse()
+                .map_err(|_| ExprError::UnexpectedToken(num.clone()))?;
+
+            // `1/8b` is a fraction with a unit, not a division by `8b`,
+            // and `1/8` needs a unit or spaces like `1 / 8` to tell which it is
+            if pos + 1 < chars.len() && chars[pos] == '/' && chars[pos + 1].is_ascii_digit() {
+                let mut p = pos + 1;
+                let den = read_digits(&chars, &mut p);
+                if p >= chars.len() || !chars[p].is_alphabetic() {
+                    return Err(ExprError::AmbiguousFraction(format!("{}/{}", num, den)));
+                }
+                let den: f64 = den
+                    .parse()
+                    .map_err(|_| ExprError::UnexpectedToken(den.clone()))?;
+                if den == 0.0 {
+                    return Err(ExprError::DivisionByZero);
+                }
+                value /= den;
+                pos = p;
+            }
+            tokens.push(Token::Number(value));
+        } else if c.is_alphabetic() {
+            if is_note_start(&chars, pos) {
+                let mut name = String::new();
+                name.push(chars[pos]);
+                pos += 1;
+                if "#+b-".contains(chars[pos]) {
+                    name.push(chars[pos]);
+                    pos += 1;
+                }
+                while pos < chars.len() && chars[pos].is_ascii_digit() {
+                    name.push(chars[pos]);
+                    pos += 1;
+                }
+                match note_to_midi(&name) {
+                    Some(midi) => tokens.push(Token::Note(midi_to_freq(midi as f64))),
+                    None => return Err(ExprError::InvalidNote(name)),
+                }
+            } else if c == 'm' && pos + 1 < chars.len() && chars[pos + 1].is_ascii_digit() {
+                pos += 1;
+                let num = read_digits(&chars, &mut pos);
+                let midi: f64 = num
+                    .parse()
+                    .map_err(|_| ExprError::InvalidNote(format!("m{}", num)))?;
+                tokens.push(Token::Note(midi_to_freq(midi)));
+            } else {
+                let mut word = String::new();
+                while pos < chars.len() && chars[pos].is_alphabetic() {
+                    word.push(chars[pos]);
+                    pos += 1;
+                }
+                tokens.push(Token::Word(word));
+            }
+        } else if "+-*/".contains(c) {
+            tokens.push(Token::Op(c));
+            pos += 1;
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            pos += 1;
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            pos += 1;
+        } else {
+            return Err(ExprError::UnexpectedChar(c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    transport: &'a Transport,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expr(&mut self) -> Result<f64, ExprError> {
+        let mut value = self.term()?;
+        while let Some(Token::Op(op)) = self.peek().cloned() {
+            if op != '+' && op != '-' {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<f64, ExprError> {
+        let mut value = self.factor()?;
+        while let Some(Token::Op(op)) = self.peek().cloned() {
+            if op != '*' && op != '/' {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.factor()?;
+            if op == '*' {
+                value *= rhs;
+            } else if rhs == 0.0 {
+                return Err(ExprError::DivisionByZero);
+            } else {
+                value /= rhs;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Units are applied after the sign so that `-6dB` means "minus six decibels".
+    fn factor(&mut self) -> Result<f64, ExprError> {
+        let value = self.signed()?;
+        if let Some(Token::Word(unit)) = self.peek().cloned() {
+            self.pos += 1;
+            self.unit(value, &unit)
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn signed(&mut self) -> Result<f64, ExprError> {
+        match self.peek() {
+            Some(Token::Op('-')) => {
+                self.pos += 1;
+                Ok(-self.signed()?)
+            }
+            Some(Token::Op('+')) => {
+                self.pos += 1;
+                self.signed()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<f64, ExprError> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Note(f)) => Ok(f),
+            Some(Token::Open) => {
+                let value = self.expr()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(value),
+                    Some(token) => Err(ExprError::UnexpectedToken(format!("{:?}", token))),
+                    None => Err(ExprError::UnexpectedEnd),
+                }
+            }
+            Some(token) => Err(ExprError::UnexpectedToken(format!("{:?}", token))),
+            None => Err(ExprError::UnexpectedEnd),
+        }
+    }
+
+    fn unit(&self, value: f64, unit: &str) -> Result<f64, ExprError> {
+        let beat = 60.0 / self.transport.bpm as f64;
+        match &unit.to_lowercase()[..] {
+            "hz" => Ok(value),
+            "db" => Ok(10.0f64.powf(value / 20.0)),
+            "ms" => Ok(value / 1000.0),
+            "s" | "sec" => Ok(value),
+            "b" | "beat" | "beats" => Ok(value * beat),
+            "bar" | "bars" => Ok(value * beat * self.transport.measure.beat as f64),
+            // note values like `1/8n` are fractions of a whole note
+            "n" => Ok(value * beat * self.transport.measure.note as f64),
+            _ => Err(ExprError::UnknownUnit(unit.to_string())),
+        }
+    }
+}
+
+/// Evaluates a `.val` file expression into a plain number.
+///
+/// Numbers can be written as note names (`a4`, `c#3`), MIDI note numbers (`m69`),
+/// decibels (`-6dB`), durations (`250ms`, `1/8b`, `2bar`) and arithmetic of them.
+pub fn eval_expr(s: &str, transport: &Transport) -> Result<f64, ExprError> {
+    let mut parser = Parser {
+        tokens: tokenize(s)?,
+        pos: 0,
+        transport: transport,
+    };
+    let value = parser.expr()?;
+    match parser.next() {
+        None => Ok(value),
+        Some(token) => Err(ExprError::UnexpectedToken(format!("{:?}", token))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str) -> f64 {
+        eval_expr(s, &Transport::new(44100)).unwrap()
+    }
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_note_to_midi() {
+        assert_eq!(note_to_midi("a4"), Some(69));
+        assert_eq!(note_to_midi("c4"), Some(60));
+        assert_eq!(note_to_midi("c#3"), Some(49));
+        assert_eq!(note_to_midi("c+3"), Some(49));
+        assert_eq!(note_to_midi("bb2"), Some(46));
+        assert_eq!(note_to_midi("a"), None);
+        assert_eq!(note_to_midi("h4"), None);
+        assert_eq!(note_to_midi("a4x"), None);
+    }
+
+    #[test]
+    fn test_midi_to_note() {
+        assert_eq!(midi_to_note(69), Some("a4".to_string()));
+        assert_eq!(midi_to_note(61), Some("c+4".to_string()));
+        for k in 0..=127 {
+            match midi_to_note(k) {
+                Some(name) => assert_eq!(note_to_midi(&name), Some(k)),
+                None => assert!(k < LOWEST_NOTE),
+            }
+        }
+        assert_eq!(midi_to_note(lift_octaves(11)), Some("b0".to_string()));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_near(eval("1 + 2 * 3"), 7.0);
+        assert_near(eval("(1 + 2) * 3"), 9.0);
+        assert_near(eval("-2 - -3"), 1.0);
+        assert_near(eval("440 / 2"), 220.0);
+    }
+
+    #[test]
+    fn test_notes() {
+        assert_near(eval("a4"), 440.0);
+        assert_near(eval("m69"), 440.0);
+        assert_near(eval("a4 * 2"), eval("a5"));
+    }
+
+    #[test]
+    fn test_units() {
+        let transport = Transport::new(44100);
+        let beat = 60.0 / transport.bpm as f64;
+        assert_near(eval("-6dB"), 10.0f64.powf(-6.0 / 20.0));
+        assert_near(eval("250ms"), 0.25);
+        assert_near(eval("2s"), 2.0);
+        assert_near(eval("1/8b"), beat / 8.0);
+        assert_near(eval("2bar"), 2.0 * beat * transport.measure.beat as f64);
+        assert_near(eval("1/4n"), beat * transport.measure.note as f64 / 4.0);
+    }
+
+    #[test]
+    fn test_errors() {
+        let transport = Transport::new(44100);
+        match eval_expr("1 / 0", &transport) {
+            Err(ExprError::DivisionByZero) => (),
+            result => panic!("{:?}", result),
+        }
+        match eval_expr("1/0b", &transport) {
+            Err(ExprError::DivisionByZero) => (),
+            result => panic!("{:?}", result),
+        }
+        match eval_expr("1/8", &transport) {
+            Err(ExprError::AmbiguousFraction(fraction)) => assert_eq!(fraction, "1/8"),
+            result => panic!("{:?}", result),
+        }
+        match eval_expr("3 parsecs", &transport) {
+            Err(ExprError::UnknownUnit(unit)) => assert_eq!(unit, "parsecs"),
+            result => panic!("{:?}", result),
+        }
+        match eval_expr("(1 + 2", &transport) {
+            Err(ExprError::UnexpectedEnd) => (),
+            result => panic!("{:?}", result),
+        }
+        match eval_expr("1 $ 2", &transport) {
+            Err(ExprError::UnexpectedChar('$')) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! A unit which Koto puts between a module and its parent for `mute`, `solo` and `bypass`.
+//!
+//! The module stays connected and keeps running while it's silenced, and the gate dumps
+//! as the module itself, so saves and the node tree don't see the gate.
+
+use tapirus::musical_time::time::Transport;
+use tapirus::ugens::core::{
+    Aug, Dump, Operate, OperateError, Osc, Proc, Signal, UGen, UgNode, Walk, UG,
+};
+
+/// The parameter name which returns the gated module, to find gates in the graph.
+const GATED: &str = "gated";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flags {
+    pub mute: bool,
+    pub solo: bool,
+    pub bypass: bool,
+}
+
+pub struct Gate {
+    ug: Aug,
+    flags: Flags,
+    /// Muted because another source of the same mixer is soloed.
+    soloed_out: bool,
+}
+
+impl Gate {
+    pub fn new(ug: Aug, flags: Flags, soloed_out: bool) -> Aug {
+        let ug = gate_of(&ug).unwrap_or(ug);
+        let is_osc = if let UG::Osc(_) = &ug.0.lock().unwrap().ug {
+            true
+        } else {
+            false
+        };
+        let gate = Gate {
+            ug: ug,
+            flags: flags,
+            soloed_out: soloed_out,
+        };
+        // gated oscillators still take frequencies from sequencers
+        if is_osc {
+            Aug::new(UGen::new(UG::Osc(Box::new(gate))))
+        } else {
+            Aug::new(UGen::new(UG::Proc(Box::new(gate))))
+        }
+    }
+}
+
+/// Returns the module in the gate `ug`, or `None` if `ug` is not a gate.
+pub fn gate_of(ug: &Aug) -> Option<Aug> {
+    ug.get(GATED).ok()
+}
+
+/// Sets the frequency of the oscillator `ug`. Nothing happens if it's not an oscillator.
+pub fn set_freq(ug: &Aug, freq: Aug) {
+    if let UG::Osc(ref mut osc) = &mut ug.0.lock().unwrap().ug {
+        osc.set_freq(freq);
+    }
+}
+
+/// Returns the flags of the gate `ug`.
+pub fn flags_of(ug: &Aug) -> Option<Flags> {
+    gate_of(ug)?;
+    let flag = |name: &str| ug.get_str(name).map(|v| v == "1").unwrap_or(false);
+    Some(Flags {
+        mute: flag("mute"),
+        solo: flag("solo"),
+        bypass: flag("bypass"),
+    })
+}
+
+impl Walk for Gate {
+    fn walk(&self, f: &mut dyn FnMut(&Aug) -> bool) {
+        if f(&self.ug) {
+            self.ug.walk(f);
+        }
+    }
+}
+
+impl Dump for Gate {
+    fn dump(&self, shared: &Vec<Aug>) -> UgNode {
+        self.ug.dump(shared)
+    }
+}
+
+impl Operate for Gate {
+    fn get(&self, pname: &str) -> Result<Aug, OperateError> {
+        match pname {
+            GATED => Ok(self.ug.clone()),
+            _ => self.ug.get(pname),
+        }
+    }
+
+    fn get_str(&self, pname: &str) -> Result<String, OperateError> {
+        let flag = |b: bool| if b { "1" } else { "0" }.to_string();
+        match pname {
+            "mute" => Ok(flag(self.flags.mute)),
+            "solo" => Ok(flag(self.flags.solo)),
+            "bypass" => Ok(flag(self.flags.bypass)),
+            _ => self.ug.get_str(pname),
+        }
+    }
+
+    fn set(&mut self, pname: &str, ug: Aug) -> Result<Aug, OperateError> {
+        self.ug.set(pname, ug)
+    }
+
+    fn set_str(&mut self, pname: &str, data: String) -> Result<Aug, OperateError> {
+        self.ug.set_str(pname, data)
+    }
+
+    fn clear(&mut self, pname: &str) {
+        self.ug.clear(pname)
+    }
+}
+
+impl Proc for Gate {
+    fn proc(&mut self, transport: &Transport) -> Signal {
+        // the module runs even if it's silenced, so sequencers don't lose their positions
+        let sig = self.ug.proc(transport);
+        if self.flags.mute || self.soloed_out {
+            (0.0, 0.0)
+        } else if self.flags.bypass {
+            match self.ug.get("src") {
+                Ok(src) => src.proc(transport),
+                Err(_) => sig,
+            }
+        } else {
+            sig
+        }
+    }
+}
+
+impl Osc for Gate {
+    fn set_freq(&mut self, freq: Aug) {
+        set_freq(&self.ug, freq);
+    }
+}
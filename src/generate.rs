@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use tapirus::musical_time::time::Transport;
+
+use crate::expr::{eval_expr, ExprError};
+
+pub const GENERATOR_NAMES: [&str; 3] = ["euclid", "randpat", "arp"];
+
+/// Counts like `steps` larger than this are refused, not to fill the memory.
+const MAX_COUNT: usize = 4096;
+
+#[derive(Debug)]
+pub enum GenerateError {
+    UnknownGenerator(String),
+    InvalidParameter(String, ExprError),
+    UnknownMode(String),
+    TooLarge(String, f64),
+    EmptyNotes,
+}
+
+/// Returns parameters of the generator `typename` and their default values.
+pub fn defaults(typename: &str) -> Vec<(&'static str, &'static str)> {
+    match typename {
+        "euclid" => vec![
+            ("steps", "16"),
+            ("pulses", "5"),
+            ("rotation", "0"),
+            ("note", "a4"),
+            ("len", "1"),
+        ],
+        "randpat" => vec![
+            ("steps", "16"),
+            ("prob", "0.5"),
+            ("seed", "1"),
+            ("notes", "a4"),
+            ("len", "1"),
+        ],
+        "arp" => vec![
+            ("chord", "c4 e4 g4"),
+            ("mode", "up"),
+            ("steps", "8"),
+            ("seed", "1"),
+            ("len", "2"),
+        ],
+        _ => vec![],
+    }
+}
+
+struct Params<'a> {
+    params: &'a HashMap<String, String>,
+    defaults: Vec<(&'static str, &'static str)>,
+}
+
+impl<'a> Params<'a> {
+    fn text(&self, name: &str) -> String {
+        match self.params.get(name) {
+            Some(text) => text.trim().to_string(),
+            None => match self.defaults.iter().find(|(n, _)| *n == name) {
+                Some((_, text)) => text.to_string(),
+                None => "".to_string(),
+            },
+        }
+    }
+
+    fn number(&self, name: &str, transport: &Transport) -> Result<f64, GenerateError> {
+        eval_expr(&self.text(name), transport)
+            .map_err(|err| GenerateError::InvalidParameter(name.to_string(), err))
+    }
+
+    fn count(&self, name: &str, transport: &Transport) -> Result<usize, GenerateError> {
+        let n = self.number(name, transport)?.max(0.0);
+        if n > MAX_COUNT as f64 {
+            return Err(GenerateError::TooLarge(name.to_string(), n));
+        }
+        Ok(n as usize)
+    }
+}
+
+/// xorshift64, not to change generated patterns by versions of any crates.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Distributes `pulses` onsets in `steps` as evenly as possible, rotated by `rotation` steps.
+pub fn euclid(steps: usize, pulses: usize, rotation: isize) -> Vec<bool> {
+    let pulses = pulses.min(steps);
+    let mut onsets = Vec::with_capacity(steps);
+    for i in 0..steps {
+        onsets.push((i * pulses) % steps < pulses);
+    }
+    if steps > 0 {
+        let r = rotation.rem_euclid(steps as isize) as usize;
+        onsets.rotate_right(r);
+    }
+    onsets
+}
+
+/// Generates pattern items by the generator `typename` with `params`.
+pub fn generate(
+    typename: &str,
+    params: &HashMap<String, String>,
+    transport: &Transport,
+) -> Result<Vec<String>, GenerateError> {
+    let params = Params {
+        params: params,
+        defaults: defaults(typename),
+    };
+    let len = params.text("len");
+    let mut items = Vec::new();
+
+    match typename {
+        "euclid" => {
+            let steps = params.count("steps", transport)?;
+            let pulses = params.count("pulses", transport)?;
+            let rotation = params.number("rotation", transport)? as isize;
+            let note = params.text("note");
+            for onset in euclid(steps, pulses, rotation) {
+                let note = if onset { &note[..] } else { "r" };
+                items.push(format!("({} {})", note, len));
+            }
+        }
+        "randpat" => {
+            let steps = params.count("steps", transport)?;
+            let prob = params.number("prob", transport)?;
+            let seed = params.number("seed", transport)? as u64;
+            let notes = params.text("notes");
+            let notes: Vec<&str> = notes.split_whitespace().collect();
+            if notes.len() == 0 {
+                return Err(GenerateError::EmptyNotes);
+            }
+            let mut random = Random::new(seed);
+            for _ in 0..steps {
+                let on = random.next() < prob;
+                let idx = (random.next() * notes.len() as f64) as usize;
+                let note = if on {
+                    notes[idx.min(notes.len() - 1)]
+                } else {
+                    "r"
+                };
+                items.push(format!("({} {})", note, len));
+            }
+        }
+        "arp" => {
+            let steps = params.count("steps", transport)?;
+            let seed = params.number("seed", transport)? as u64;
+            let chord = params.text("chord");
+            let chord: Vec<&str> = chord.split_whitespace().collect();
+            if chord.len() == 0 {
+                return Err(GenerateError::EmptyNotes);
+            }
+            let order: Vec<usize> = match &params.text("mode")[..] {
+                "up" => (0..chord.len()).collect(),
+                "down" => (0..chord.len()).rev().collect(),
+                "updown" => {
+                    // the top and the bottom notes are not repeated
+                    let mut order: Vec<usize> = (0..chord.len()).collect();
+                    order.extend((1..chord.len().max(2) - 1).rev());
+                    order
+                }
+                "random" => {
+                    let mut random = Random::new(seed);
+                    (0..steps)
+                        .map(|_| (random.next() * chord.len() as f64) as usize)
+                        .collect()
+                }
+                mode => return Err(GenerateError::UnknownMode(mode.to_string())),
+            };
+            for i in 0..steps {
+                let note = chord[order[i % order.len()].min(chord.len() - 1)];
+                items.push(format!("({} {})", note, len));
+            }
+        }
+        _ => return Err(GenerateError::UnknownGenerator(typename.to_string())),
+    }
+
+    items.push("loop".to_string());
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(typename: &str, params: &[(&str, &str)]) -> Result<Vec<String>, GenerateError> {
+        let params: HashMap<String, String> = params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        generate(typename, &params, &Transport::new(44100))
+    }
+
+    fn onsets(items: &[String]) -> String {
+        items
+            .iter()
+            .filter(|item| *item != "loop")
+            .map(|item| if item.starts_with("(r ") { '.' } else { 'x' })
+            .collect()
+    }
+
+    #[test]
+    fn test_euclid() {
+        let pattern: String = euclid(8, 3, 0)
+            .iter()
+            .map(|on| if *on { 'x' } else { '.' })
+            .collect();
+        assert_eq!(pattern, "x..x..x.");
+        assert_eq!(euclid(8, 3, 1)[1], true);
+        assert_eq!(euclid(4, 9, 0), vec![true; 4]);
+        assert_eq!(euclid(0, 3, 2), vec![]);
+    }
+
+    #[test]
+    fn test_generate_euclid() {
+        let items = run("euclid", &[("steps", "8"), ("pulses", "3"), ("note", "c4")]).unwrap();
+        assert_eq!(onsets(&items), "x..x..x.");
+        assert_eq!(items[0], "(c4 1)");
+        assert_eq!(items.last().unwrap(), "loop");
+    }
+
+    #[test]
+    fn test_randpat_is_reproducible() {
+        let params = [("steps", "16"), ("seed", "42"), ("notes", "a4 c5")];
+        let items = run("randpat", &params).unwrap();
+        assert_eq!(items.len(), 17);
+        assert_eq!(items, run("randpat", &params).unwrap());
+        assert_eq!(
+            onsets(&run("randpat", &[("prob", "0")]).unwrap()),
+            ".".repeat(16)
+        );
+        assert_eq!(
+            onsets(&run("randpat", &[("prob", "1")]).unwrap()),
+            "x".repeat(16)
+        );
+    }
+
+    #[test]
+    fn test_arp() {
+        let arp = |mode| {
+            run(
+                "arp",
+                &[("chord", "c4 e4 g4"), ("mode", mode), ("steps", "6")],
+            )
+        };
+        let notes = |items: Vec<String>| -> Vec<String> {
+            items
+                .iter()
+                .filter(|item| *item != "loop")
+                .map(|item| item[1..3].to_string())
+                .collect()
+        };
+        assert_eq!(
+            notes(arp("up").unwrap()),
+            vec!["c4", "e4", "g4", "c4", "e4", "g4"]
+        );
+        assert_eq!(
+            notes(arp("down").unwrap()),
+            vec!["g4", "e4", "c4", "g4", "e4", "c4"]
+        );
+        assert_eq!(
+            notes(arp("updown").unwrap()),
+            vec!["c4", "e4", "g4", "e4", "c4", "e4"]
+        );
+        match arp("sideways") {
+            Err(GenerateError::UnknownMode(mode)) => assert_eq!(mode, "sideways"),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        match run("arp", &[("chord", " ")]) {
+            Err(GenerateError::EmptyNotes) => (),
+            result => panic!("{:?}", result),
+        }
+        match run("euclid", &[("steps", "100000")]) {
+            Err(GenerateError::TooLarge(name, _)) => assert_eq!(name, "steps"),
+            result => panic!("{:?}", result),
+        }
+        match run("euclid", &[("pulses", "five")]) {
+            Err(GenerateError::InvalidParameter(name, _)) => assert_eq!(name, "pulses"),
+            result => panic!("{:?}", result),
+        }
+        match run("drunk", &[]) {
+            Err(GenerateError::UnknownGenerator(name)) => assert_eq!(name, "drunk"),
+            result => panic!("{:?}", result),
+        }
+    }
+}
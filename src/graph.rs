@@ -0,0 +1,302 @@
+use tapirus::ugens::core::{Aug, Dump, UgNode, Value};
+use tapirus::ugens::util::collect_shared_ugs;
+
+use crate::kotonode::KotoNode;
+
+/// A value connected to a parameter.
+#[derive(Debug, Clone)]
+pub enum Input {
+    Number(f64),
+    Table(Vec<f64>),
+    Pattern(Vec<String>),
+    Module(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    /// The type name of the module directory, like `sampler` of a dumped `wavetable`.
+    pub typename: String,
+    /// The path of the module directory in the mount, like `/src0.seq`. `/` for the root.
+    pub path: String,
+    pub params: Vec<(String, Input)>,
+}
+
+/// A snapshot of the unit graph. Shared units are one module with several parents.
+///
+/// It's walked the same way as `KotoFS::build_node()`, so paths are the same as the mount.
+#[derive(Debug, Clone)]
+pub struct Graph {
+    /// `modules[0]` is the root.
+    pub modules: Vec<Module>,
+}
+
+/// Splits a dumped unit into its module type name and parameters, or returns its value.
+fn split_node(node: UgNode) -> Result<(String, Vec<(String, Value)>), Value> {
+    match node {
+        UgNode::Val(v) => Err(v),
+        UgNode::Ug(typename, slots) => Ok((
+            KotoNode::module_typename(&typename, &slots),
+            slots.into_iter().map(|s| (s.name, s.value)).collect(),
+        )),
+        UgNode::UgRest(typename, slots, basename, values) => {
+            let typename = KotoNode::module_typename(&typename, &slots);
+            let mut params: Vec<(String, Value)> =
+                slots.into_iter().map(|s| (s.name, s.value)).collect();
+            for (i, v) in values.into_iter().enumerate() {
+                params.push((format!("{}{}", basename, i), *v));
+            }
+            Ok((typename, params))
+        }
+    }
+}
+
+struct Builder {
+    modules: Vec<Module>,
+    shared: Vec<Aug>,
+    shared_ids: Vec<Option<usize>>,
+}
+
+impl Builder {
+    fn add_module(&mut self, typename: String, path: String, slots: Vec<(String, Value)>) -> usize {
+        let id = self.modules.len();
+        self.modules.push(Module {
+            typename: typename,
+            path: path.clone(),
+            params: Vec::new(),
+        });
+
+        let dir = if id == 0 { "".to_string() } else { path };
+        for (name, value) in slots {
+            let input = self.add_value(value, &dir, &name);
+            self.modules[id].params.push((name, input));
+        }
+        id
+    }
+
+    fn add_ug(&mut self, ug: &Aug, dir: &str, name: &str) -> Input {
+        let shared = self.shared.clone();
+        match split_node(ug.dump(&shared)) {
+            Ok((typename, params)) => {
+                let path = format!("{}/{}.{}", dir, name, typename);
+                Input::Module(self.add_module(typename, path, params))
+            }
+            Err(v) => self.add_value(v, dir, name),
+        }
+    }
+
+    fn add_value(&mut self, value: Value, dir: &str, name: &str) -> Input {
+        match value {
+            Value::Number(n) => Input::Number(n),
+            Value::Table(vec) => Input::Table(vec),
+            Value::Pattern(vec) => Input::Pattern(vec),
+            Value::Ug(aug) => self.add_ug(&aug, dir, name),
+            Value::Shared(_, aug) => {
+                let idx = match self.shared.iter().position(|s| *s == aug) {
+                    Some(idx) => idx,
+                    None => return self.add_ug(&aug, dir, name),
+                };
+                match self.shared_ids[idx] {
+                    Some(id) => Input::Module(id),
+                    None => {
+                        let input = self.add_ug(&aug, dir, name);
+                        if let Input::Module(id) = input {
+                            self.shared_ids[idx] = Some(id);
+                        }
+                        input
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Graph {
+    pub fn build(ug: Aug) -> Graph {
+        let shared = collect_shared_ugs(ug.clone());
+        let mut builder = Builder {
+            modules: Vec::new(),
+            shared_ids: shared.iter().map(|_| None).collect(),
+            shared: shared,
+        };
+
+        match split_node(ug.dump(&builder.shared.clone())) {
+            Ok((typename, params)) => {
+                builder.add_module(typename, "/".to_string(), params);
+            }
+            Err(v) => {
+                builder.add_module("val".to_string(), "/".to_string(), vec![]);
+                let input = builder.add_value(v, "", "value");
+                builder.modules[0].params.push(("value".to_string(), input));
+            }
+        }
+
+        Graph {
+            modules: builder.modules,
+        }
+    }
+
+    /// Returns paths and patterns of all sequencers.
+    pub fn patterns(&self) -> Vec<(String, Vec<String>)> {
+        let mut patterns = Vec::new();
+        for module in self.modules.iter().filter(|m| &m.typename[..] == "seq") {
+            for (name, input) in module.params.iter() {
+                if let ("pattern", Input::Pattern(items)) = (&name[..], input) {
+                    patterns.push((module.path.clone(), items.clone()));
+                }
+            }
+        }
+        patterns
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Graph {
+    /// Returns the graph in Graphviz DOT. Values are in the labels of modules,
+    /// and edges go from inputs to their parents, labeled with parameter names.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph koto {\n    rankdir=RL;\n    node [shape=box];\n");
+
+        for (id, module) in self.modules.iter().enumerate() {
+            let mut label = vec![format!(
+                "{}\\n{}",
+                escape(&module.typename),
+                escape(&module.path)
+            )];
+            for (name, input) in module.params.iter() {
+                let value = match input {
+                    Input::Number(n) => format!("{}", n),
+                    Input::Table(vec) => format!("table[{}]", vec.len()),
+                    Input::Pattern(items) => format!("pattern[{}]", items.len()),
+                    Input::Module(_) => continue,
+                };
+                label.push(format!("{} = {}", escape(name), value));
+            }
+            dot.push_str(&format!("    n{} [label=\"{}\"];\n", id, label.join("\\l")));
+        }
+
+        for (id, module) in self.modules.iter().enumerate() {
+            for (name, input) in module.params.iter() {
+                if let Input::Module(child) = input {
+                    dot.push_str(&format!(
+                        "    n{} -> n{} [label=\"{}\"];\n",
+                        child,
+                        id,
+                        escape(name)
+                    ));
+                }
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tapirus::musical_time::time::Transport;
+    use tapirus::ugens::core::{Operate, OperateError, Proc, Signal, Slot, UGen, Walk, UG};
+
+    /// A unit which only dumps as the node made by its function.
+    struct Unit(fn() -> UgNode);
+
+    impl Walk for Unit {
+        fn walk(&self, _f: &mut dyn FnMut(&Aug) -> bool) {}
+    }
+
+    impl Dump for Unit {
+        fn dump(&self, _shared: &Vec<Aug>) -> UgNode {
+            (self.0)()
+        }
+    }
+
+    impl Operate for Unit {
+        fn get(&self, pname: &str) -> Result<Aug, OperateError> {
+            Err(OperateError::ParamNotFound(pname.to_string()))
+        }
+
+        fn get_str(&self, pname: &str) -> Result<String, OperateError> {
+            Err(OperateError::ParamNotFound(pname.to_string()))
+        }
+
+        fn set(&mut self, pname: &str, _ug: Aug) -> Result<Aug, OperateError> {
+            Err(OperateError::ParamNotFound(pname.to_string()))
+        }
+
+        fn set_str(&mut self, pname: &str, _data: String) -> Result<Aug, OperateError> {
+            Err(OperateError::ParamNotFound(pname.to_string()))
+        }
+
+        fn clear(&mut self, _pname: &str) {}
+    }
+
+    impl Proc for Unit {
+        fn proc(&mut self, _transport: &Transport) -> Signal {
+            (0.0, 0.0)
+        }
+    }
+
+    fn unit(dump: fn() -> UgNode) -> Aug {
+        Aug::new(UGen::new(UG::Proc(Box::new(Unit(dump)))))
+    }
+
+    fn slot(name: &str, ug: Aug) -> Slot {
+        Slot {
+            ug: ug.clone(),
+            name: name.to_string(),
+            value: Value::Ug(ug),
+        }
+    }
+
+    fn wavetable_of(ph: fn() -> UgNode) -> UgNode {
+        let table = Slot {
+            ug: Aug::val(0.0),
+            name: "table".to_string(),
+            value: Value::Table(vec![0.0, 1.0]),
+        };
+        UgNode::Ug("wavetable".to_string(), vec![table, slot("ph", unit(ph))])
+    }
+
+    fn sampler() -> UgNode {
+        wavetable_of(|| UgNode::Ug("oneshot".to_string(), vec![]))
+    }
+
+    fn wavetable() -> UgNode {
+        wavetable_of(|| UgNode::Ug("phase".to_string(), vec![]))
+    }
+
+    #[test]
+    fn test_sampler() {
+        let mut builder = Builder {
+            modules: Vec::new(),
+            shared: Vec::new(),
+            shared_ids: Vec::new(),
+        };
+        let out = UgNode::Ug(
+            "out".to_string(),
+            vec![slot("src0", unit(sampler)), slot("src1", unit(wavetable))],
+        );
+        let (typename, params) = split_node(out).ok().unwrap();
+        builder.add_module(typename, "/".to_string(), params);
+
+        let paths: Vec<(&str, &str)> = builder
+            .modules
+            .iter()
+            .map(|m| (&m.typename[..], &m.path[..]))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("out", "/"),
+                ("sampler", "/src0.sampler"),
+                ("oneshot", "/src0.sampler/ph.oneshot"),
+                ("wavetable", "/src1.wavetable"),
+                ("phase", "/src1.wavetable/ph.phase"),
+            ]
+        );
+    }
+}
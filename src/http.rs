@@ -0,0 +1,333 @@
+//! HTTP frontend, which shows the same nodes as the mount.
+//!
+//! - `GET /path`: contents of a file, or a JSON listing of a directory
+//! - `PUT /path`: writes a file, and creates it if it doesn't exist. touches a directory
+//! - `MKCOL /path`: makes a directory
+//! - `DELETE /path`: removes a file or a directory
+//!
+//! Requests from browsers are refused unless their `Origin` is allowed with `--http-origin`.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use fuse::FileType;
+use libc::EACCES;
+
+use crate::json::Json;
+use crate::kotofs::KotoFS;
+use crate::kotonode::KotoNode;
+
+/// Requests larger than this are refused.
+const MAX_BODY: usize = 64 * 1024 * 1024;
+
+struct Request {
+    method: String,
+    path: String,
+    origin: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: &'static str, content_type: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status: status,
+            content_type: content_type,
+            body: body,
+        }
+    }
+
+    fn status(status: &'static str) -> Response {
+        Response::new(status, "text/plain", format!("{}\n", status).into_bytes())
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut words = line.split_whitespace();
+    let method = words.next()?.to_string();
+    let target = words.next()?;
+    let path = percent_decode(target.split('?').next().unwrap_or("/"));
+
+    let mut length = 0;
+    let mut origin = None;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim();
+        if header.len() == 0 {
+            break;
+        }
+        if let Some(pos) = header.find(':') {
+            let name = &header[..pos];
+            if name.eq_ignore_ascii_case("content-length") {
+                length = header[pos + 1..].trim().parse().ok()?;
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(header[pos + 1..].trim().to_string());
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return None;
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        method: method,
+        path: path,
+        origin: origin,
+        body: body,
+    })
+}
+
+fn listing(node: Arc<Mutex<KotoNode>>) -> Json {
+    let mut entries = Vec::new();
+    for (name, child) in KotoNode::get_children(node) {
+        let child = child.lock().unwrap();
+        let mut entry = vec![("name".to_string(), Json::String(name))];
+        let kind = match child.attr.kind {
+            FileType::Directory => "directory",
+            FileType::Symlink => "symlink",
+            _ => "file",
+        };
+        entry.push(("type".to_string(), Json::String(kind.to_string())));
+        entry.push(("size".to_string(), Json::Number(child.attr.size as f64)));
+        if let Some(link) = &child.link {
+            let link = link.to_string_lossy().to_string();
+            entry.push(("link".to_string(), Json::String(link)));
+        }
+        entries.push(Json::Object(entry));
+    }
+    Json::Array(entries)
+}
+
+/// Splits a path into the parent directory and the name.
+fn split_path(path: &str) -> Option<(&str, String)> {
+    let path = path.trim_end_matches('/');
+    let pos = path.rfind('/')?;
+    let name = &path[pos + 1..];
+    if name.len() == 0 {
+        None
+    } else {
+        Some((&path[..pos], name.to_string()))
+    }
+}
+
+fn get(fs: &mut KotoFS, path: &str) -> Response {
+    let node = match fs.lookup_path(path) {
+        Some(node) => node,
+        None => return Response::status("404 Not Found"),
+    };
+    let kind = node.lock().unwrap().attr.kind;
+    match kind {
+        FileType::Directory => {
+            let body = format!("{}\n", listing(node)).into_bytes();
+            Response::new("200 OK", "application/json", body)
+        }
+        FileType::Symlink => match KotoNode::resolve_symlink(node) {
+            Some(target) => {
+                fs.refresh(target.clone());
+                let data = target.lock().unwrap().data.clone();
+                Response::new("200 OK", "application/octet-stream", data)
+            }
+            None => Response::status("404 Not Found"),
+        },
+        _ => {
+            fs.refresh(node.clone());
+            let data = node.lock().unwrap().data.clone();
+            Response::new("200 OK", "application/octet-stream", data)
+        }
+    }
+}
+
+fn put(fs: &mut KotoFS, path: &str, body: Vec<u8>) -> Response {
+    let (status, node) = match fs.lookup_path(path) {
+        Some(node) => ("204 No Content", node),
+        None => {
+            let created = split_path(path).and_then(|(dir, name)| {
+                let parent = fs.lookup_path(dir)?;
+                let ino = parent.lock().unwrap().attr.ino;
+                fs.make_file(ino, name)
+            });
+            match created {
+                Some(node) => ("201 Created", node),
+                None => return Response::status("404 Not Found"),
+            }
+        }
+    };
+
+    let kind = node.lock().unwrap().attr.kind;
+    match kind {
+        FileType::RegularFile => (),
+        FileType::Directory => {
+            // like `touch dir/`, to build modules
+            fs.apply(node);
+            return Response::status(status);
+        }
+        _ => return Response::status("405 Method Not Allowed"),
+    }
+    match fs.replace(node, body) {
+        Ok(()) => Response::status(status),
+        Err(_) => Response::status("403 Forbidden"),
+    }
+}
+
+fn mkcol(fs: &mut KotoFS, path: &str) -> Response {
+    if fs.lookup_path(path).is_some() {
+        return Response::status("405 Method Not Allowed");
+    }
+    let created = split_path(path).and_then(|(dir, name)| {
+        let parent = fs.lookup_path(dir)?;
+        let ino = parent.lock().unwrap().attr.ino;
+        fs.make_dir(ino, name)
+    });
+    match created {
+        Some(_) => Response::status("201 Created"),
+        None => Response::status("409 Conflict"),
+    }
+}
+
+fn delete(fs: &mut KotoFS, path: &str) -> Response {
+    let (dir, name) = match split_path(path) {
+        Some(split) => split,
+        None => return Response::status("403 Forbidden"),
+    };
+    let (parent, node) = match (fs.lookup_path(dir), fs.lookup_path(path)) {
+        (Some(parent), Some(node)) => (parent, node),
+        _ => return Response::status("404 Not Found"),
+    };
+    let ino = parent.lock().unwrap().attr.ino;
+    let kind = node.lock().unwrap().attr.kind;
+    let result = match kind {
+        FileType::Directory => fs.remove_dir(ino, name),
+        _ => fs.remove_file(ino, name),
+    };
+    match result {
+        Ok(()) => Response::status("204 No Content"),
+        Err(err) if err == EACCES => Response::status("403 Forbidden"),
+        Err(_) => Response::status("404 Not Found"),
+    }
+}
+
+/// Returns CORS headers for a request from `origin`, if it's allowed.
+///
+/// `Content-Type` is allowed because `PUT` of JSON from browsers is preflighted.
+fn cors_headers(origin: Option<String>, allowed: bool) -> String {
+    match origin {
+        Some(origin) if allowed => format!(
+            "Access-Control-Allow-Origin: {}\r\n\
+             Access-Control-Allow-Methods: GET, PUT, MKCOL, DELETE, OPTIONS\r\n\
+             Access-Control-Allow-Headers: Content-Type\r\n\
+             Vary: Origin\r\n",
+            origin
+        ),
+        _ => String::new(),
+    }
+}
+
+fn handle(stream: TcpStream, fs: Arc<Mutex<KotoFS>>, origins: Arc<Vec<String>>) {
+    let request = read_request(&stream);
+    // requests without `Origin` are not from browsers, like curl
+    let origin = request.as_ref().and_then(|req| req.origin.clone());
+    let allowed = match &origin {
+        Some(origin) => origins.iter().any(|o| o == origin),
+        None => true,
+    };
+
+    let response = match request {
+        Some(_) if !allowed => Response::status("403 Forbidden"),
+        Some(req) => {
+            let mut fs = fs.lock().unwrap();
+            match &req.method[..] {
+                "GET" => get(&mut fs, &req.path),
+                "PUT" => put(&mut fs, &req.path, req.body),
+                "MKCOL" => mkcol(&mut fs, &req.path),
+                "DELETE" => delete(&mut fs, &req.path),
+                // CORS preflight from browsers
+                "OPTIONS" => Response::status("204 No Content"),
+                _ => Response::status("405 Method Not Allowed"),
+            }
+        }
+        None => Response::status("400 Bad Request"),
+    };
+
+    let cors = cors_headers(origin, allowed);
+    let mut stream = stream;
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        cors
+    );
+    let _ = stream.write_all(header.as_bytes());
+    let _ = stream.write_all(&response.body);
+}
+
+/// Starts the HTTP server at `addr` like `127.0.0.1:8080` in another thread.
+/// Browsers can access it only from `origins` like `http://localhost:3000`.
+pub fn start(fs: Arc<Mutex<KotoFS>>, addr: &str, origins: Vec<String>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let origins = Arc::new(origins);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let fs = fs.clone();
+                    let origins = origins.clone();
+                    std::thread::spawn(move || handle(stream, fs, origins));
+                }
+                Err(err) => {
+                    println!("cannot accept a HTTP connection");
+                    println!("{:?}", err);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cors_headers() {
+        let origin = Some("http://localhost:3000".to_string());
+        let headers = cors_headers(origin.clone(), true);
+        assert!(headers.contains("Access-Control-Allow-Origin: http://localhost:3000\r\n"));
+        assert!(headers.contains("Access-Control-Allow-Headers: Content-Type\r\n"));
+
+        assert_eq!(cors_headers(origin, false), "");
+        assert_eq!(cors_headers(None, true), "");
+    }
+}
@@ -0,0 +1,373 @@
+//! A small JSON reader and writer for patch files.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum JsonError {
+    UnexpectedEnd,
+    UnexpectedChar(char, usize),
+    InvalidNumber(String),
+    InvalidEscape(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Members keep their order.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(vec) => Some(vec),
+            _ => None,
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(vec) if vec.len() == 0 => write!(f, "[]"),
+            Json::Array(vec) if vec.iter().all(|v| !v.is_container()) => {
+                // numbers and strings like tables and patterns are in one line
+                write!(f, "[")?;
+                for (i, v) in vec.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    v.write(f, indent)?;
+                }
+                write!(f, "]")
+            }
+            Json::Array(vec) => {
+                writeln!(f, "[")?;
+                for (i, v) in vec.iter().enumerate() {
+                    write!(f, "{}", pad)?;
+                    v.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < vec.len() { "," } else { "" })?;
+                }
+                write!(f, "{}]", "  ".repeat(indent))
+            }
+            Json::Object(members) if members.len() == 0 => write!(f, "{{}}"),
+            Json::Object(members) => {
+                writeln!(f, "{{")?;
+                for (i, (k, v)) in members.iter().enumerate() {
+                    write!(f, "{}", pad)?;
+                    write_string(f, k)?;
+                    write!(f, ": ")?;
+                    v.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < members.len() { "," } else { "" })?;
+                }
+                write!(f, "{}}}", "  ".repeat(indent))
+            }
+        }
+    }
+
+    fn is_container(&self) -> bool {
+        match self {
+            Json::Array(_) | Json::Object(_) => true,
+            _ => false,
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Writes indented JSON.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn skip_ws(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Result<char, JsonError> {
+        self.skip_ws();
+        self.chars
+            .get(self.pos)
+            .cloned()
+            .ok_or(JsonError::UnexpectedEnd)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), JsonError> {
+        match self.peek()? {
+            ch if ch == c => {
+                self.pos += 1;
+                Ok(())
+            }
+            ch => Err(JsonError::UnexpectedChar(ch, self.pos)),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        for c in word.chars() {
+            match self.chars.get(self.pos) {
+                Some(ch) if *ch == c => self.pos += 1,
+                Some(ch) => return Err(JsonError::UnexpectedChar(*ch, self.pos)),
+                None => return Err(JsonError::UnexpectedEnd),
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        match self.peek()? {
+            '{' => self.object(),
+            '[' => self.array(),
+            '"' => Ok(Json::String(self.string()?)),
+            't' => self.keyword("true", Json::Bool(true)),
+            'f' => self.keyword("false", Json::Bool(false)),
+            'n' => self.keyword("null", Json::Null),
+            '-' | '0'..='9' => self.number(),
+            c => Err(JsonError::UnexpectedChar(c, self.pos)),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        while let Some(c) = self.chars.get(self.pos) {
+            match c {
+                '-' | '+' | '.' | 'e' | 'E' | '0'..='9' => self.pos += 1,
+                _ => break,
+            }
+        }
+        let s: String = self.chars[start..self.pos].iter().collect();
+        match s.parse() {
+            Ok(n) => Ok(Json::Number(n)),
+            Err(_) => Err(JsonError::InvalidNumber(s)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            let c = *self.chars.get(self.pos).ok_or(JsonError::UnexpectedEnd)?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let e = *self.chars.get(self.pos).ok_or(JsonError::UnexpectedEnd)?;
+                    self.pos += 1;
+                    match e {
+                        '"' | '\\' | '/' => s.push(e),
+                        'n' => s.push('\n'),
+                        't' => s.push('\t'),
+                        'r' => s.push('\r'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'u' => {
+                            let hex: String = self.chars.iter().skip(self.pos).take(4).collect();
+                            // `from_str_radix()` also takes signs like `+fff`
+                            if hex.len() < 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                                return Err(JsonError::InvalidEscape(self.pos));
+                            }
+                            let code = u32::from_str_radix(&hex, 16)
+                                .map_err(|_| JsonError::InvalidEscape(self.pos))?;
+                            // surrogate pairs are not supported
+                            s.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                            self.pos += 4;
+                        }
+                        _ => return Err(JsonError::InvalidEscape(self.pos - 1)),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        self.expect('[')?;
+        let mut vec = Vec::new();
+        if self.peek()? == ']' {
+            self.pos += 1;
+            return Ok(Json::Array(vec));
+        }
+        loop {
+            vec.push(self.value()?);
+            match self.peek()? {
+                ',' => self.pos += 1,
+                ']' => {
+                    self.pos += 1;
+                    return Ok(Json::Array(vec));
+                }
+                c => return Err(JsonError::UnexpectedChar(c, self.pos)),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        self.expect('{')?;
+        let mut members = Vec::new();
+        if self.peek()? == '}' {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.peek()?;
+            let key = self.string()?;
+            self.expect(':')?;
+            members.push((key, self.value()?));
+            match self.peek()? {
+                ',' => self.pos += 1,
+                '}' => {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                c => return Err(JsonError::UnexpectedChar(c, self.pos)),
+            }
+        }
+    }
+}
+
+pub fn parse(src: &str) -> Result<Json, JsonError> {
+    let mut parser = Parser {
+        chars: src.chars().collect(),
+        pos: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_ws();
+    match parser.chars.get(parser.pos) {
+        Some(c) => Err(JsonError::UnexpectedChar(*c, parser.pos)),
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let json = parse(r#" {"a": [1, -2.5e1, true, null], "b": {"c": "d"}} "#).unwrap();
+        assert_eq!(
+            json.get("a"),
+            Some(&Json::Array(vec![
+                Json::Number(1.0),
+                Json::Number(-25.0),
+                Json::Bool(true),
+                Json::Null,
+            ]))
+        );
+        assert_eq!(
+            json.get("b")
+                .and_then(|b| b.get("c"))
+                .and_then(|c| c.as_str()),
+            Some("d")
+        );
+        assert_eq!(parse("[]").unwrap(), Json::Array(vec![]));
+    }
+
+    #[test]
+    fn test_escapes() {
+        let json = parse(r#""q\"b\\s\/n\nt\tu\u00e9\u0001""#).unwrap();
+        assert_eq!(json, Json::String("q\"b\\s/n\nt\tu\u{e9}\u{1}".to_string()));
+
+        let s = "quote \" backslash \\ newline \n tab \t control \u{1f}";
+        let written = format!("{}", Json::String(s.to_string()));
+        assert_eq!(
+            written,
+            r#""quote \" backslash \\ newline \n tab \t control \u001f""#
+        );
+        assert_eq!(parse(&written).unwrap(), Json::String(s.to_string()));
+    }
+
+    #[test]
+    fn test_invalid_escapes() {
+        for src in &[r#""\x""#, r#""\u12""#, r#""\u+fff""#, r#""\u00g0""#] {
+            match parse(src) {
+                Err(JsonError::InvalidEscape(_)) => (),
+                result => panic!("{}: {:?}", src, result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        match parse(r#"{"a": 1"#) {
+            Err(JsonError::UnexpectedEnd) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse("[1 2]") {
+            Err(JsonError::UnexpectedChar('2', 3)) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse("1 2") {
+            Err(JsonError::UnexpectedChar('2', 2)) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse("1.2.3") {
+            Err(JsonError::InvalidNumber(s)) => assert_eq!(s, "1.2.3"),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = Json::Object(vec![
+            ("koto".to_string(), Json::Number(1.0)),
+            (
+                "pattern".to_string(),
+                Json::Array(vec![Json::String("(a4 2)".to_string())]),
+            ),
+            ("empty".to_string(), Json::Object(vec![])),
+        ]);
+        assert_eq!(parse(&format!("{}", json)).unwrap(), json);
+    }
+}
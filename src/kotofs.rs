@@ -3,32 +3,66 @@ use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
-use libc::{EACCES, ENOENT};
+use libc::{c_int, EACCES, EFBIG, ENOENT};
 use time::Timespec;
 
 use fuse::{
     FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyWrite, Request,
+    ReplyEntry, ReplyOpen, ReplyWrite, Request,
 };
 
 use tapirus::musical_time::time::Transport;
 use tapirus::ugens::core::{Aug, Dump, Operate, UgNode, Value};
 
-use crate::kotonode::{create_file, KotoNode, Ugen};
+use crate::diff;
+use crate::gate;
+use crate::generate;
+use crate::graph::Graph;
+use crate::kotonode::{create_file, table_to_f32, KotoNode, Ugen};
+use crate::midi;
+use crate::patch;
+use crate::ramp;
+use crate::schedule::{self, Quantize, Scheduler};
+use crate::tracker;
+use crate::transform;
+use crate::tuning;
 
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
 
+/// `FOPEN_DIRECT_IO` of FUSE, which bypasses the page cache.
+const FOPEN_DIRECT_IO: u32 = 1;
+
+/// Files larger than this are refused, by FUSE and by 9P.
+pub const MAX_FILE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How many symbolic links are followed at most, like `SYMLOOP_MAX`.
+const MAX_LINK_HOPS: usize = 8;
+
+/// Read-only files in `.koto` generated from the running unit graph.
+const GENERATED_FILES: [&str; 4] = ["export.mid", "graph.dot", "patch.json", "diff"];
+
+/// An opened file. Its written data is applied to the unit graph when it's closed.
+pub struct Handle {
+    pub ino: u64,
+    pub dirty: bool,
+}
+
 pub struct KotoFS {
     pub root: Arc<Mutex<KotoNode>>,
     pub inodes: HashMap<u64, Arc<Mutex<KotoNode>>>,
     pub augs: HashMap<Aug, Arc<Mutex<KotoNode>>>,
     pub transport: Arc<Mutex<Transport>>,
     pub lock: Arc<Mutex<bool>>,
+    pub scheduler: Arc<Mutex<Scheduler>>,
+    pub handles: HashMap<u64, Handle>,
+    /// A snapshot at the last save, to show differences in `.koto/diff`.
+    pub saved: Arc<Mutex<Graph>>,
     pub inode_count: u64,
+    pub fh_count: u64,
 }
 
 impl KotoFS {
-    fn inode(&mut self) -> u64 {
+    pub fn inode(&mut self) -> u64 {
         let ino = self.inode_count;
         self.inode_count += 1;
         ino
@@ -144,6 +178,7 @@ impl KotoFS {
         shared: &Vec<Aug>,
         shared_used: &mut Vec<bool>,
     ) -> Arc<Mutex<KotoNode>> {
+        let is_root = parent.is_none();
         let ug_node = ug.dump(shared);
         match ug_node {
             UgNode::Val(v) => {
@@ -155,11 +190,12 @@ impl KotoFS {
                 node
             }
             UgNode::Ug(name, slots) => {
+                let typename = KotoNode::module_typename(&name, &slots);
                 let node = Arc::new(Mutex::new(KotoNode {
                     ug: Ugen::Mapped(ug.clone()),
                     parent: parent,
                     children: [].to_vec(),
-                    name: name,
+                    name: typename.clone(),
                     data: [].to_vec(),
                     link: None,
                     attr: create_file(self.inode(), 0, FileType::Directory),
@@ -179,10 +215,22 @@ impl KotoFS {
                     let newname =
                         format!("{}.{}", s.name.clone(), child.lock().unwrap().name.clone());
                     node.lock().unwrap().children.push((newname, child.clone()));
+                    if let Value::Table(vec) = &s.value {
+                        let ug = Ugen::Mapped(s.ug.clone());
+                        self.build_table_view(node.clone(), &s.name, ug, vec);
+                    }
+                    if let Value::Pattern(vec) = &s.value {
+                        let ug = Ugen::Mapped(s.ug.clone());
+                        self.build_pattern_view(node.clone(), &s.name, ug, vec);
+                    }
+                }
+                if !is_root {
+                    self.build_flag_nodes(node.clone(), &typename);
                 }
                 node
             }
             UgNode::UgRest(name, slots, basename, values) => {
+                let typename = name.clone();
                 let node = Arc::new(Mutex::new(KotoNode {
                     ug: Ugen::Mapped(ug.clone()),
                     parent: parent,
@@ -207,6 +255,14 @@ impl KotoFS {
                         .unwrap()
                         .children
                         .push((nodename, child.clone()));
+                    if let Value::Table(vec) = &s.value {
+                        let ug = Ugen::Mapped(s.ug.clone());
+                        self.build_table_view(node.clone(), &s.name, ug, vec);
+                    }
+                    if let Value::Pattern(vec) = &s.value {
+                        let ug = Ugen::Mapped(s.ug.clone());
+                        self.build_pattern_view(node.clone(), &s.name, ug, vec);
+                    }
                 }
                 for (i, v) in values.iter().enumerate() {
                     let child = self.build_node_from_value(
@@ -228,11 +284,148 @@ impl KotoFS {
                         .children
                         .push((nodename, child.clone()));
                 }
+                if !is_root {
+                    self.build_flag_nodes(node.clone(), &typename);
+                }
                 node
             }
         }
     }
 
+    /// Creates `xxx.f32` file, a view of the table `xxx` as raw little-endian f32,
+    /// and `xxx.d` directory to edit the table, if they don't exist.
+    fn build_table_view(
+        &mut self,
+        parent: Arc<Mutex<KotoNode>>,
+        paramname: &str,
+        ug: Ugen,
+        table: &Vec<f64>,
+    ) {
+        let has = |typename: &str| {
+            KotoNode::get_child(parent.clone(), &format!("{}.{}", paramname, typename)).is_some()
+        };
+        if has("f32") && has("d") {
+            return;
+        }
+        if !has("f32") {
+            self.build_f32_view(parent.clone(), paramname, ug, table);
+        }
+        if !has("d") {
+            self.build_table_dir(parent, paramname, table);
+        }
+    }
+
+    fn build_f32_view(
+        &mut self,
+        parent: Arc<Mutex<KotoNode>>,
+        paramname: &str,
+        ug: Ugen,
+        table: &Vec<f64>,
+    ) {
+        let data = table_to_f32(table);
+        let node = Arc::new(Mutex::new(KotoNode {
+            ug: ug,
+            parent: Some(parent.clone()),
+            children: [].to_vec(),
+            name: "f32".to_string(),
+            data: data.clone(),
+            link: None,
+            attr: create_file(self.inode(), data.len() as u64, FileType::RegularFile),
+        }));
+        self.inodes
+            .insert(node.lock().unwrap().attr.ino, node.clone());
+        parent
+            .lock()
+            .unwrap()
+            .children
+            .push((format!("{}.f32", paramname), node.clone()));
+    }
+
+    fn build_table_dir(&mut self, parent: Arc<Mutex<KotoNode>>, paramname: &str, table: &Vec<f64>) {
+        let dir = self.build_control_node(
+            parent.clone(),
+            &format!("{}.d", paramname),
+            "",
+            FileType::Directory,
+        );
+        let length = format!("{}\n", table.len());
+        self.build_control_node(dir.clone(), "length", &length, FileType::RegularFile);
+        self.build_control_node(dir.clone(), "resample", "linear\n", FileType::RegularFile);
+        self.build_control_node(dir.clone(), "harmonics", "", FileType::RegularFile);
+        self.build_control_node(dir.clone(), "normalize", "", FileType::RegularFile);
+    }
+
+    /// Creates `xxx.trk` file, a tracker-style view of the pattern `xxx`, if it doesn't exist.
+    fn build_pattern_view(
+        &mut self,
+        parent: Arc<Mutex<KotoNode>>,
+        paramname: &str,
+        ug: Ugen,
+        pattern: &Vec<String>,
+    ) {
+        let name = format!("{}.trk", paramname);
+        if KotoNode::get_child(parent.clone(), &name).is_some() {
+            return;
+        }
+        let data = tracker::to_trk(pattern, &[]).into_bytes();
+        let node = Arc::new(Mutex::new(KotoNode {
+            ug: ug,
+            parent: Some(parent.clone()),
+            children: [].to_vec(),
+            name: "trk".to_string(),
+            data: data.clone(),
+            link: None,
+            attr: create_file(self.inode(), data.len() as u64, FileType::RegularFile),
+        }));
+        self.inodes
+            .insert(node.lock().unwrap().attr.ino, node.clone());
+        parent.lock().unwrap().children.push((name, node.clone()));
+    }
+
+    /// Creates views of the parameter file `node` which is written, like `xxx.f32` and
+    /// `xxx.d` of `xxx.tab`, or `xxx.trk` of `xxx.pat`. Their contents are updated when the parameter is set.
+    fn build_views(&mut self, node: Arc<Mutex<KotoNode>>) {
+        let (paramname, typename) = match KotoNode::get_nodename(node.clone()) {
+            Some(nodename) => nodename,
+            None => return,
+        };
+        let parent = match &node.lock().unwrap().parent {
+            Some(parent) => parent.clone(),
+            None => return,
+        };
+        if KotoNode::is_control(parent.clone()) || KotoNode::is_control(node.clone()) {
+            return;
+        }
+        let parent_ino = parent.lock().unwrap().attr.ino;
+        let source = |typename: &str| {
+            let name = format!("{}.{}", paramname, typename);
+            match KotoNode::get_child(parent.clone(), &name) {
+                Some(_) => None,
+                None => Some(name),
+            }
+        };
+
+        match &typename[..] {
+            "tab" | "f32" | "wav" => {
+                if typename == "f32" {
+                    if let Some(name) = source("tab") {
+                        self.make_file(parent_ino, name);
+                    }
+                }
+                self.build_table_view(parent, &paramname, Ugen::NotMapped, &Vec::new());
+            }
+            "pat" | "trk" | "mid" => {
+                if typename == "trk" {
+                    if let Some(name) = source("pat") {
+                        self.make_file(parent_ino, name);
+                    }
+                }
+                self.build_pattern_view(parent, &paramname, Ugen::NotMapped, &Vec::new());
+            }
+            _ => (),
+        }
+    }
+
     fn modify_symlink(&self, pathmap: &Vec<(Arc<Mutex<KotoNode>>, String)>) {
         for (node, path) in pathmap.iter() {
             let mut is_symlink = false;
@@ -278,222 +471,622 @@ impl KotoFS {
             })),
             transport: transport,
             lock: lock,
+            scheduler: Arc::new(Mutex::new(Scheduler::new())),
+            handles: HashMap::new(),
+            saved: Arc::new(Mutex::new(Graph::build(ug.clone()))),
             inode_count: 151,
+            fh_count: 1,
         };
 
+        fs.build_tree(ug);
+        fs.build_control_tree();
+        fs
+    }
+
+    fn build_tree(&mut self, ug: Aug) {
         let shared_ug = tapirus::ugens::util::collect_shared_ugs(ug.clone());
         let mut shared_used: Vec<bool> = shared_ug.iter().map(|_| false).collect();
 
-        let root = fs.build_node(ug, None, &shared_ug, &mut shared_used);
+        let root = self.build_node(ug, None, &shared_ug, &mut shared_used);
 
         let mut pathmap = Vec::new();
         KotoNode::build_pathmap(root.clone(), "".to_string(), &mut pathmap);
-        fs.modify_symlink(&pathmap);
-        fs.augs.clear();
+        self.modify_symlink(&pathmap);
+        self.augs.clear();
 
-        fs.root = root.clone();
-        fs.root.lock().unwrap().attr.ino = 1;
-        fs.inodes.insert(1, fs.root.clone());
-        fs
+        self.root = root.clone();
+        self.root.lock().unwrap().attr.ino = 1;
+        self.inodes.insert(1, self.root.clone());
     }
 
-    pub fn mount(self, mountpoint: OsString) {
-        fuse::mount(self, &mountpoint, &[]).expect(&format!("fail mount() with {:?}", mountpoint));
-    }
-}
+    /// Throws away the node tree and builds it again from the running unit graph.
+    ///
+    /// Nodes at the same paths keep their inode numbers, so opened files and working
+    /// directories in the mount point are still valid.
+    pub fn rebuild(&mut self) {
+        let ug = match &self.root.lock().unwrap().ug {
+            Ugen::Mapped(aug) => aug.clone(),
+            _ => return,
+        };
+        let control = KotoNode::get_child(self.root.clone(), ".koto");
 
-impl Filesystem for KotoFS {
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        if let Some(node) = self.inodes.get(&ino) {
-            reply.attr(&TTL, &node.lock().unwrap().attr);
-            return;
-        } else {
-            reply.error(ENOENT);
-            return;
+        let mut old_pathmap = Vec::new();
+        KotoNode::build_pathmap(self.root.clone(), "".to_string(), &mut old_pathmap);
+        let old_inodes: HashMap<String, (u64, FileType)> = old_pathmap
+            .iter()
+            .map(|(node, path)| {
+                let attr = node.lock().unwrap().attr;
+                (path.clone(), (attr.ino, attr.kind))
+            })
+            .collect();
+
+        self.inodes.clear();
+        self.build_tree(ug);
+
+        if let Some(control) = control {
+            control.lock().unwrap().parent = Some(self.root.clone());
+            self.root
+                .lock()
+                .unwrap()
+                .children
+                .push((".koto".to_string(), control.clone()));
         }
-    }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        if offset > 0 {
-            reply.ok();
-            return;
+        // new nodes have new inode numbers, which are never used by old nodes
+        let mut pathmap = Vec::new();
+        KotoNode::build_pathmap(self.root.clone(), "".to_string(), &mut pathmap);
+        self.inodes.clear();
+        self.inodes.insert(1, self.root.clone());
+        for (node, path) in pathmap.iter() {
+            let ino = {
+                let mut node = node.lock().unwrap();
+                if let Some((ino, kind)) = old_inodes.get(path) {
+                    if *kind == node.attr.kind {
+                        node.attr.ino = *ino;
+                    }
+                }
+                node.attr.ino
+            };
+            self.inodes.insert(ino, node.clone());
         }
+    }
 
-        if let Some(dirnode) = self.inodes.get(&ino) {
-            reply.add(ino, 0, FileType::Directory, ".");
+    /// Connects parameters of `ug` to the running root unit, and builds the node tree again.
+    /// Variable parameters like `src0`, `src1`... of `out` or `+` are replaced too.
+    /// Returns false if `ug` is not the same type as the root unit.
+    pub fn set_root(&mut self, ug: Aug) -> bool {
+        let mut root = match &self.root.lock().unwrap().ug {
+            Ugen::Mapped(aug) => aug.clone(),
+            _ => return false,
+        };
+        let (slots, rest, old_count) = match (root.dump(&vec![]), ug.dump(&vec![])) {
+            (UgNode::Ug(old, _), UgNode::Ug(new, slots)) if old == new => (slots, None, 0),
+            (
+                UgNode::UgRest(old, _, _, old_values),
+                UgNode::UgRest(new, slots, basename, values),
+            ) if old == new => (slots, Some((basename, values)), old_values.len()),
+            _ => return false,
+        };
 
-            let mut parent_ino = 1;
-            if let Some(ref parent) = &dirnode.lock().unwrap().parent {
-                parent_ino = parent.lock().unwrap().attr.ino as i64
+        if let Ok(_) = self.lock.lock() {
+            for s in slots.iter() {
+                let _ = root.set(&s.name, s.ug.clone());
             }
-            reply.add(
-                dirnode.lock().unwrap().attr.ino,
-                parent_ino,
-                FileType::Directory,
-                "..",
-            );
-            let mut reply_add_offset = 2;
-
-            for (name, node) in dirnode.lock().unwrap().children.iter() {
-                let attr = node.lock().unwrap().attr;
-                reply.add(attr.ino, reply_add_offset, attr.kind, name);
-                reply_add_offset += 1;
+            if let Some((basename, values)) = rest {
+                for (i, value) in values.iter().enumerate() {
+                    let name = format!("{}{}", basename, i);
+                    let value = match &**value {
+                        Value::Number(n) => Aug::val(*n),
+                        Value::Ug(aug) | Value::Shared(_, aug) => aug.clone(),
+                        _ => match ug.get(&name) {
+                            Ok(aug) => aug,
+                            Err(_) => continue,
+                        },
+                    };
+                    if i < old_count {
+                        let _ = root.set(&name, value);
+                    } else {
+                        // adds a new one at the end
+                        let _ = root.set(&basename, value);
+                    }
+                }
+                for i in (values.len()..old_count).rev() {
+                    root.clear(&format!("{}{}", basename, i));
+                }
             }
         }
-        reply.ok();
+        self.rebuild();
+        true
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if let Some(parent_node) = self.inodes.get(&parent) {
-            let children = &mut parent_node.lock().unwrap().children;
-            let name = name.to_str().unwrap().to_string();
+    /// Connects `ug` to the parameter at `path` like `/src0.seq/osc`, and builds the node
+    /// tree again. The last name may have its type like `osc.saw`.
+    pub fn connect(&mut self, path: &str, ug: Aug) -> bool {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = match path.rfind('/') {
+            Some(pos) => (&path[..pos], &path[pos + 1..]),
+            None => ("", path),
+        };
+        let paramname = match KotoNode::parse_nodename(name.to_string()) {
+            Some((paramname, _)) => paramname,
+            None => name.to_string(),
+        };
+        let parent = match self.lookup_path(dir) {
+            Some(parent) => parent,
+            None => return false,
+        };
+        let mut aug = match &parent.lock().unwrap().ug {
+            Ugen::Mapped(aug) => aug.clone(),
+            _ => return false,
+        };
 
-            if let Some((_, node)) = children.iter().find(|(nodename, _)| nodename == &name) {
-                let attr = node.lock().unwrap().attr;
-                reply.entry(&TTL, &attr, 0);
-                return;
-            }
+        let result = match self.lock.lock() {
+            Ok(_) => aug.set(&paramname, ug),
+            Err(_) => return false,
+        };
+        if result.is_err() {
+            return false;
         }
+        self.rebuild();
+        true
+    }
 
-        reply.error(ENOENT);
+    fn open_handle(&mut self, ino: u64) -> u64 {
+        let fh = self.fh_count;
+        self.fh_count += 1;
+        self.handles.insert(
+            fh,
+            Handle {
+                ino: ino,
+                dirty: false,
+            },
+        );
+        fh
     }
 
-    fn create(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _flag: u32,
-        reply: ReplyCreate,
-    ) {
-        let ino = self.inode();
-        let mut created: Option<Arc<Mutex<KotoNode>>> = None;
-        let name = name.to_str().unwrap().to_string();
+    /// Applies data written through the handle `fh` if it's not applied yet.
+    fn flush_handle(&mut self, fh: u64) {
+        let ino = match self.handles.get_mut(&fh) {
+            Some(handle) if handle.dirty => {
+                handle.dirty = false;
+                handle.ino
+            }
+            _ => return,
+        };
+        if let Some(node) = self.inodes.get(&ino).cloned() {
+            self.apply(node);
+        }
+    }
 
-        if let Some(parent_node) = self.inodes.get(&parent) {
-            let node = KotoNode::create_node(ino, name.clone(), [].to_vec(), FileType::RegularFile);
-            let node = Arc::new(Mutex::new(node));
-            node.lock().unwrap().parent = Some(parent_node.clone());
-            parent_node
-                .lock()
-                .unwrap()
-                .children
-                .push((name.clone(), node.clone()));
-            created = Some(node.clone());
+    pub fn apply(&mut self, node: Arc<Mutex<KotoNode>>) {
+        if KotoNode::is_control(node.clone()) {
+            self.write_control(node);
+        } else {
+            self.sync(node, "".to_string());
         }
+    }
 
-        if let Some(node) = created.clone() {
-            self.inodes
-                .insert(node.clone().lock().unwrap().attr.ino, node.clone());
-            reply.created(&TTL, &node.lock().unwrap().attr, 0, 0, 0);
+    /// Applies changes of `node` to the unit graph, at the next quantization boundary if needed.
+    fn sync(&mut self, node: Arc<Mutex<KotoNode>>, oldname: String) {
+        if KotoFS::is_tuning_file(node.clone()) {
+            self.retune();
+            return;
         }
+        self.build_views(node.clone());
+        let transport = Scheduler::transport(self.scheduler.clone(), self.transport.clone());
+        let scheduler = self.scheduler.clone();
+        Scheduler::run(
+            self.scheduler.clone(),
+            self.lock.clone(),
+            Box::new(move || {
+                KotoNode::sync_ug(node.clone(), oldname, transport);
+                ramp::schedule_end(node, scheduler);
+            }),
+        );
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<Timespec>,
-        _mtime: Option<Timespec>,
-        _fd: Option<u64>,
-        _crtime: Option<Timespec>,
-        _chgtime: Option<Timespec>,
-        _bkuptime: Option<Timespec>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        match self.inodes.get(&ino) {
-            Some(node) => {
-                if let Ok(_) = self.lock.lock() {
-                    KotoNode::sync_ug(node.clone(), "".to_string(), self.transport.clone());
+    /// Disconnects the parameter `paramname` of `parent`, at the next quantization boundary if needed.
+    fn clear(&self, parent: Arc<Mutex<KotoNode>>, paramname: String) {
+        Scheduler::run(
+            self.scheduler.clone(),
+            self.lock.clone(),
+            Box::new(move || {
+                if let Ugen::Mapped(aug) = &parent.lock().unwrap().ug {
+                    schedule::clear(aug, &paramname);
                 }
-                reply.attr(&TTL, &node.lock().unwrap().attr);
-            }
-            None => reply.error(EACCES),
-        }
+            }),
+        );
     }
 
-    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
+    fn build_control_node(
+        &mut self,
+        parent: Arc<Mutex<KotoNode>>,
+        name: &str,
+        data: &str,
+        ftype: FileType,
+    ) -> Arc<Mutex<KotoNode>> {
         let ino = self.inode();
-        if let Some(parent_node) = self.inodes.get(&parent) {
-            let name = name.to_str().unwrap().to_string();
-            let mut node =
-                KotoNode::create_node(ino, name.clone(), [].to_vec(), FileType::Directory);
-            node.parent = Some(parent_node.clone());
+        let mut node =
+            KotoNode::create_node(ino, name.to_string(), data.to_string().into_bytes(), ftype);
+        node.ug = Ugen::Control(name.to_string());
+        node.parent = Some(parent.clone());
 
-            let node = Arc::new(Mutex::new(node));
-            parent_node
-                .lock()
-                .unwrap()
-                .children
-                .push((name, node.clone()));
-            self.inodes
-                .insert(node.lock().unwrap().attr.ino, node.clone());
-            reply.entry(&TTL, &node.lock().unwrap().attr, 0);
-            return;
+        let node = Arc::new(Mutex::new(node));
+        parent
+            .lock()
+            .unwrap()
+            .children
+            .push((name.to_string(), node.clone()));
+        self.inodes.insert(ino, node.clone());
+        node
+    }
+
+    /// Creates `.koto` directory which holds settings of Koto itself.
+    fn build_control_tree(&mut self) {
+        let root = self.root.clone();
+        let koto = self.build_control_node(root, ".koto", "", FileType::Directory);
+        let quantize = format!("{}\n", self.scheduler.lock().unwrap().quantize);
+        self.build_control_node(koto.clone(), "quantize", &quantize, FileType::RegularFile);
+
+        let txn = self.build_control_node(koto.clone(), "txn", "", FileType::Directory);
+        for name in ["begin", "commit", "abort"].iter() {
+            self.build_control_node(txn.clone(), name, "", FileType::RegularFile);
+        }
+
+        self.build_control_node(koto.clone(), "tuning", "", FileType::Directory);
+
+        for name in GENERATED_FILES.iter() {
+            let node = self.build_control_node(koto.clone(), name, "", FileType::RegularFile);
+            node.lock().unwrap().attr.perm = 0o444;
         }
-        reply.error(ENOENT);
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name = name.to_str().unwrap().to_string();
-        let mut inode = None;
+    pub fn is_generated(node: Arc<Mutex<KotoNode>>) -> bool {
+        match &node.lock().unwrap().ug {
+            Ugen::Control(name) => GENERATED_FILES.contains(&&name[..]),
+            _ => false,
+        }
+    }
 
-        if let Some(parent_node) = self.inodes.get(&parent) {
-            let pos = parent_node
-                .lock()
-                .unwrap()
-                .children
-                .iter()
-                .position(|(nodename, _)| nodename == &name);
+    /// Generates contents of the read-only file `node` in `.koto`. It's called when the file
+    /// is opened.
+    ///
+    /// The unit graph is not locked while generating, so the sound doesn't stop. Contents
+    /// are generated from a snapshot of the graph.
+    pub fn refresh(&mut self, node: Arc<Mutex<KotoNode>>) {
+        if !KotoFS::is_generated(node.clone()) {
+            return;
+        }
+        let name = match &node.lock().unwrap().ug {
+            Ugen::Control(name) => name.clone(),
+            _ => return,
+        };
+        let ug = match &self.root.lock().unwrap().ug {
+            Ugen::Mapped(aug) => aug.clone(),
+            _ => return,
+        };
 
-            if let Some(idx) = pos {
-                let (_, node) = &mut parent_node.lock().unwrap().children.remove(idx);
-                inode = Some(node.lock().unwrap().attr.ino);
+        let transport = self.transport.lock().unwrap().clone();
+        let data = match &name[..] {
+            "export.mid" => midi::export(&Graph::build(ug).patterns(), &transport),
+            "graph.dot" => Graph::build(ug).to_dot().into_bytes(),
+            "patch.json" => format!("{}\n", patch::to_json(ug, &transport)).into_bytes(),
+            "diff" => {
+                let graph = Graph::build(ug);
+                let changes = diff::diff(&self.saved.lock().unwrap(), &graph);
+                let mut text = changes.join("\n");
+                if changes.len() > 0 {
+                    text.push_str("\n");
+                }
+                text.into_bytes()
             }
+            _ => return,
+        };
+        let mut node = node.lock().unwrap();
+        node.attr.size = data.len() as u64;
+        node.data = data;
+    }
 
-            if let Ugen::Mapped(ref mut aug) = &mut parent_node.lock().unwrap().ug {
-                if let Some((paramname, _)) = KotoNode::parse_nodename(name) {
-                    aug.clear(&paramname);
+    fn is_tuning_file(node: Arc<Mutex<KotoNode>>) -> bool {
+        let parent = match &node.lock().unwrap().parent {
+            Some(parent) => parent.clone(),
+            None => return false,
+        };
+        let parent = parent.lock().unwrap();
+        match (&parent.ug, parent.attr.kind) {
+            (Ugen::Control(name), FileType::Directory) => &name[..] == "tuning",
+            _ => false,
+        }
+    }
+
+    /// Applies tunings in `.koto/tuning` to all sequencers.
+    fn retune(&self) {
+        let seqs: Vec<Arc<Mutex<KotoNode>>> = self
+            .inodes
+            .values()
+            .filter(|node| KotoNode::get_typename((*node).clone()) == Some("seq".to_string()))
+            .cloned()
+            .collect();
+        Scheduler::run(
+            self.scheduler.clone(),
+            self.lock.clone(),
+            Box::new(move || {
+                for seq in seqs {
+                    KotoNode::apply_tuning(seq);
                 }
+            }),
+        );
+    }
+
+    /// Creates `mute`, `solo` and `bypass` flag files in the module directory `node`,
+    /// and pattern transformation files if it's a sequencer. Flags are read from the gate
+    /// of the module, if it's already flagged.
+    fn build_flag_nodes(&mut self, node: Arc<Mutex<KotoNode>>, typename: &str) {
+        let flags = match &node.lock().unwrap().ug {
+            Ugen::Mapped(aug) => gate::flags_of(aug),
+            _ => None,
+        };
+        let flags = flags
+            .map(|f| [f.mute, f.solo, f.bypass])
+            .unwrap_or([false; 3]);
+        for (name, on) in ["mute", "solo", "bypass"].iter().zip(flags.iter()) {
+            let data = if *on { "1\n" } else { "0\n" };
+            self.build_control_node(node.clone(), name, data, FileType::RegularFile);
+        }
+        if typename == "seq" {
+            let aug = match &node.lock().unwrap().ug {
+                Ugen::Mapped(aug) => Some(aug.clone()),
+                _ => None,
+            };
+            for (name, default) in [
+                ("transpose", "0"),
+                ("reverse", "0"),
+                ("rotate", "0"),
+                ("scale", "off"),
+            ]
+            .iter()
+            {
+                let setting = aug
+                    .as_ref()
+                    .and_then(|aug| transform::setting_of(aug, name));
+                let data = format!("{}\n", setting.unwrap_or(default.to_string()));
+                self.build_control_node(node.clone(), name, &data, FileType::RegularFile);
             }
+            let osc = aug.and_then(|aug| aug.get("osc").ok());
+            let tuning = osc
+                .and_then(|osc| tuning::name_of(&osc))
+                .unwrap_or("off".to_string());
+            let data = format!("{}\n", tuning);
+            self.build_control_node(node.clone(), "tuning", &data, FileType::RegularFile);
         }
+    }
 
-        if let Some(ino) = inode {
-            self.inodes.remove(&ino);
-        }
+    /// Creates parameter files of the pattern generator `node` with default values.
+    fn build_generator_nodes(&mut self, node: Arc<Mutex<KotoNode>>, typename: &str) {
+        for (name, value) in generate::defaults(typename).iter() {
+            let ino = self.inode();
+            let data = format!("{}\n", value).into_bytes();
+            let mut child =
+                KotoNode::create_node(ino, "val".to_string(), data, FileType::RegularFile);
+            child.parent = Some(node.clone());
 
-        reply.ok();
+            let child = Arc::new(Mutex::new(child));
+            node.lock()
+                .unwrap()
+                .children
+                .push((format!("{}.val", name), child.clone()));
+            self.inodes.insert(ino, child);
+        }
     }
 
-    fn rename(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        newparent: u64,
-        newname: &OsStr,
-        reply: ReplyEmpty,
-    ) {
-        let reply_ok = true;
-        let old_name = name.to_str().unwrap().to_string();
-        let new_name = newname.to_str().unwrap().to_string();
+    fn write_control(&mut self, node: Arc<Mutex<KotoNode>>) {
+        let (name, data) = {
+            let node = node.lock().unwrap();
+            let name = match &node.ug {
+                Ugen::Control(name) => name.clone(),
+                _ => return,
+            };
+            (name, String::from_utf8_lossy(&node.data).to_string())
+        };
 
-        if parent == newparent {
-            if let Some(parent_node) = self.inodes.get(&parent) {
-                let children = &mut parent_node.lock().unwrap().children;
+        match &name[..] {
+            "quantize" => match Quantize::parse(&data) {
+                Some(quantize) => self.scheduler.lock().unwrap().quantize = quantize,
+                None => println!("invalid quantize setting: {:?}", data),
+            },
+            "begin" => {
+                if !self.scheduler.lock().unwrap().begin() {
+                    println!("transaction is already begun");
+                }
+            }
+            "commit" => {
+                if !Scheduler::commit(self.scheduler.clone(), self.lock.clone()) {
+                    println!("no transaction to commit");
+                }
+            }
+            "abort" => {
+                if self.scheduler.lock().unwrap().abort() {
+                    self.rebuild();
+                } else {
+                    println!("no transaction to abort");
+                }
+            }
+            "mute" | "solo" | "bypass" => {
+                let module = node.lock().unwrap().parent.clone();
+                if let Some(module) = module {
+                    Scheduler::run(
+                        self.scheduler.clone(),
+                        self.lock.clone(),
+                        Box::new(move || KotoNode::sync_flags(module)),
+                    );
+                }
+            }
+            "tuning" => {
+                if node.lock().unwrap().attr.kind == FileType::Directory {
+                    // `touch .koto/tuning/`
+                    self.retune();
+                    return;
+                }
+                let module = node.lock().unwrap().parent.clone();
+                if let Some(module) = module {
+                    Scheduler::run(
+                        self.scheduler.clone(),
+                        self.lock.clone(),
+                        Box::new(move || KotoNode::apply_tuning(module)),
+                    );
+                }
+            }
+            "transpose" | "reverse" | "rotate" | "scale" => {
+                let module = node.lock().unwrap().parent.clone();
+                if let Some(module) = module {
+                    let transport =
+                        Scheduler::transport(self.scheduler.clone(), self.transport.clone());
+                    Scheduler::run(
+                        self.scheduler.clone(),
+                        self.lock.clone(),
+                        Box::new(move || KotoNode::sync_transform(module, transport)),
+                    );
+                }
+            }
+            "length" | "resample" | "harmonics" | "normalize" => {
+                let transport =
+                    Scheduler::transport(self.scheduler.clone(), self.transport.clone());
+                Scheduler::run(
+                    self.scheduler.clone(),
+                    self.lock.clone(),
+                    Box::new(move || KotoNode::sync_table_control(node, transport)),
+                );
+            }
+            _ => (),
+        }
+    }
+    /// Creates an empty file `name` in the directory `parent`.
+    pub fn make_file(&mut self, parent: u64, name: String) -> Option<Arc<Mutex<KotoNode>>> {
+        let ino = self.inode();
+        let parent_node = self.inodes.get(&parent)?.clone();
+        let node = KotoNode::create_node(ino, name.clone(), [].to_vec(), FileType::RegularFile);
+        let node = Arc::new(Mutex::new(node));
+        node.lock().unwrap().parent = Some(parent_node.clone());
+        parent_node
+            .lock()
+            .unwrap()
+            .children
+            .push((name.clone(), node.clone()));
+        self.inodes.insert(ino, node.clone());
+        Some(node)
+    }
+
+    /// Creates a directory `name` in the directory `parent`. Modules are built when it's touched.
+    pub fn make_dir(&mut self, parent: u64, name: String) -> Option<Arc<Mutex<KotoNode>>> {
+        let ino = self.inode();
+        let parent_node = self.inodes.get(&parent)?.clone();
+        let mut node = KotoNode::create_node(ino, name.clone(), [].to_vec(), FileType::Directory);
+        node.parent = Some(parent_node.clone());
+
+        let node = Arc::new(Mutex::new(node));
+        parent_node
+            .lock()
+            .unwrap()
+            .children
+            .push((name, node.clone()));
+        self.inodes
+            .insert(node.lock().unwrap().attr.ino, node.clone());
+
+        if let Some((_, typename)) = KotoNode::get_nodename(node.clone()) {
+            if KotoNode::is_generator(&typename) {
+                self.build_generator_nodes(node.clone(), &typename);
+            } else if KotoNode::is_module_type(&typename) {
+                self.build_flag_nodes(node.clone(), &typename);
+            }
+        }
+        Some(node)
+    }
+
+    pub fn remove_dir(&mut self, parent: u64, name: String) -> Result<(), c_int> {
+        let mut inode = None;
+
+        if let Some(parent_node) = self.inodes.get(&parent) {
+            if let Some(node) = KotoNode::get_child(parent_node.clone(), &name) {
+                if KotoNode::is_control(node) {
+                    return Err(EACCES);
+                }
+            }
+
+            let pos = parent_node
+                .lock()
+                .unwrap()
+                .children
+                .iter()
+                .position(|(nodename, _)| nodename == &name);
+
+            if let Some(idx) = pos {
+                let (_, node) = &mut parent_node.lock().unwrap().children.remove(idx);
+                inode = Some(node.lock().unwrap().attr.ino);
+            }
+
+            if let Some((paramname, _)) = KotoNode::parse_nodename(name) {
+                self.clear(parent_node.clone(), paramname);
+            }
+        }
+
+        if let Some(ino) = inode {
+            self.inodes.remove(&ino);
+        }
+        Ok(())
+    }
+
+    pub fn remove_file(&mut self, parent: u64, name: String) -> Result<(), c_int> {
+        let mut inode = None;
+
+        if let Some(parent_node) = self.inodes.get(&parent) {
+            if let Some(node) = KotoNode::get_child(parent_node.clone(), &name) {
+                if KotoNode::is_control(node) {
+                    return Err(EACCES);
+                }
+            }
+            // removing views like `xxx.f32` keeps the parameter
+            let siblings: Vec<String> = KotoNode::get_children(parent_node.clone())
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect();
+            let is_view = KotoNode::is_view(&name, &siblings);
+
+            let pos = parent_node
+                .lock()
+                .unwrap()
+                .children
+                .iter()
+                .position(|(nodename, _)| nodename == &name);
+            if let Some(pos) = pos {
+                let (_, node) = parent_node.lock().unwrap().children.remove(pos);
+                inode = Some(node.lock().unwrap().attr.ino);
+            }
+
+            if KotoNode::is_control(parent_node.clone()) {
+                if name.ends_with(".scl") || name.ends_with(".kbm") {
+                    self.retune();
+                }
+            } else if !is_view {
+                if let Some((paramname, _)) = KotoNode::parse_nodename(name) {
+                    self.clear(parent_node.clone(), paramname);
+                }
+            }
+        }
+
+        match inode {
+            Some(inode) => {
+                self.inodes.remove(&inode);
+                Ok(())
+            }
+            None => Err(ENOENT),
+        }
+    }
+
+    /// Moves the node `old_name` in `parent` to `new_name` in `newparent`, and applies it.
+    pub fn rename_node(&mut self, parent: u64, old_name: String, newparent: u64, new_name: String) {
+        if parent == newparent {
+            if let Some(parent_node) = self.inodes.get(&parent) {
+                let children = &mut parent_node.lock().unwrap().children;
                 if let Some(n) = children
                     .iter()
                     .position(|(nodename, _)| nodename == &old_name)
@@ -516,12 +1109,8 @@ impl Filesystem for KotoFS {
                 }
                 if let Some(pos) = pos {
                     parent_node.lock().unwrap().children.remove(pos);
-                    if let Ok(_) = self.lock.lock() {
-                        if let Some((paramname, _)) = KotoNode::parse_nodename(new_name.clone()) {
-                            if let Ugen::Mapped(ref mut aug) = &mut parent_node.lock().unwrap().ug {
-                                aug.clear(&paramname);
-                            }
-                        }
+                    if let Some((paramname, _)) = KotoNode::parse_nodename(new_name.clone()) {
+                        self.clear(parent_node.clone(), paramname);
                     }
                 }
             }
@@ -534,12 +1123,8 @@ impl Filesystem for KotoFS {
                         .unwrap()
                         .children
                         .push((new_name.clone(), node.clone()));
-                    if let Ok(_) = self.lock.lock() {
-                        if let Some((paramname, _)) = KotoNode::get_nodename(new_parent.clone()) {
-                            if let Ugen::Mapped(ref mut aug) = &mut new_parent.lock().unwrap().ug {
-                                aug.clear(&paramname);
-                            }
-                        }
+                    if let Some((paramname, _)) = KotoNode::get_nodename(new_parent.clone()) {
+                        self.clear(new_parent.clone(), paramname);
                     }
                 }
             }
@@ -561,42 +1146,439 @@ impl Filesystem for KotoFS {
             };
 
         if let Some(node) = node {
-            if let Ok(_) = self.lock.lock() {
-                KotoNode::sync_ug(node.clone(), old_name.clone(), self.transport.clone());
+            self.sync(node.clone(), old_name.clone());
+        }
+    }
+
+    /// Replaces the whole contents of a file and applies it.
+    pub fn replace(&mut self, node: Arc<Mutex<KotoNode>>, data: Vec<u8>) -> Result<(), c_int> {
+        if KotoFS::is_generated(node.clone()) {
+            return Err(EACCES);
+        }
+        {
+            let mut node = node.lock().unwrap();
+            node.attr.size = data.len() as u64;
+            node.data = data;
+        }
+        self.apply(node);
+        Ok(())
+    }
+
+    /// Returns the node at `path` like `/src0.seq/pattern.pat`.
+    /// Symbolic links in the middle of `path` are followed, but the last one is not.
+    pub fn lookup_path(&mut self, path: &str) -> Option<Arc<Mutex<KotoNode>>> {
+        let mut node = self.root.clone();
+        for name in path.split('/').filter(|name| name.len() > 0) {
+            // links can point links, but not forever
+            let mut hops = 0;
+            while node.lock().unwrap().attr.kind == FileType::Symlink {
+                hops += 1;
+                if hops > MAX_LINK_HOPS {
+                    return None;
+                }
+                node = KotoNode::resolve_symlink(node)?;
             }
+            node = KotoNode::get_child(node, name)?;
         }
+        Some(node)
+    }
+}
+
+/// Shares the filesystem between the mount and other frontends, like HTTP.
+pub struct Mount(pub Arc<Mutex<KotoFS>>);
+
+impl Mount {
+    pub fn mount(self, mountpoint: OsString) {
+        fuse::mount(self, &mountpoint, &[]).expect(&format!("fail mount() with {:?}", mountpoint));
+    }
+}
+
+impl Filesystem for Mount {
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.0.lock().unwrap().getattr(req, ino, reply)
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.0.lock().unwrap().readdir(req, ino, fh, offset, reply)
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.0.lock().unwrap().lookup(req, parent, name, reply)
+    }
 
-        if reply_ok {
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flag: u32,
+        reply: ReplyCreate,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .create(req, parent, name, mode, flag, reply)
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.0.lock().unwrap().open(req, ino, flags, reply)
+    }
+
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.0
+            .lock()
+            .unwrap()
+            .flush(req, ino, fh, lock_owner, reply)
+    }
+
+    fn release(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .release(req, ino, fh, flags, lock_owner, flush, reply)
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        fd: Option<u64>,
+        crtime: Option<Timespec>,
+        chgtime: Option<Timespec>,
+        bkuptime: Option<Timespec>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.0.lock().unwrap().setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, fd, crtime, chgtime, bkuptime, flags,
+            reply,
+        )
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        self.0.lock().unwrap().mkdir(req, parent, name, mode, reply)
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.0.lock().unwrap().rmdir(req, parent, name, reply)
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .rename(req, parent, name, newparent, newname, reply)
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .write(req, ino, fh, offset, data, flags, reply)
+    }
+
+    fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        self.0
+            .lock()
+            .unwrap()
+            .read(req, ino, fh, offset, size, reply)
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.0.lock().unwrap().unlink(req, parent, name, reply)
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        self.0.lock().unwrap().readlink(req, ino, reply)
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .symlink(req, parent, name, link, reply)
+    }
+}
+
+impl Filesystem for KotoFS {
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        if let Some(node) = self.inodes.get(&ino).cloned() {
+            reply.attr(&TTL, &node.lock().unwrap().attr);
+            return;
+        } else {
+            reply.error(ENOENT);
+            return;
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if offset > 0 {
             reply.ok();
+            return;
+        }
+
+        if let Some(dirnode) = self.inodes.get(&ino) {
+            reply.add(ino, 0, FileType::Directory, ".");
+
+            let mut parent_ino = 1;
+            if let Some(ref parent) = &dirnode.lock().unwrap().parent {
+                parent_ino = parent.lock().unwrap().attr.ino as i64
+            }
+            reply.add(
+                dirnode.lock().unwrap().attr.ino,
+                parent_ino,
+                FileType::Directory,
+                "..",
+            );
+            let mut reply_add_offset = 2;
+
+            for (name, node) in dirnode.lock().unwrap().children.iter() {
+                let attr = node.lock().unwrap().attr;
+                reply.add(attr.ino, reply_add_offset, attr.kind, name);
+                reply_add_offset += 1;
+            }
+        }
+        reply.ok();
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if let Some(parent_node) = self.inodes.get(&parent).cloned() {
+            let name = name.to_str().unwrap().to_string();
+
+            if let Some(node) = KotoNode::get_child(parent_node, &name) {
+                let attr = node.lock().unwrap().attr;
+                reply.entry(&TTL, &attr, 0);
+                return;
+            }
+        }
+
+        reply.error(ENOENT);
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _flag: u32,
+        reply: ReplyCreate,
+    ) {
+        let name = name.to_str().unwrap().to_string();
+        if let Some(node) = self.make_file(parent, name) {
+            let ino = node.lock().unwrap().attr.ino;
+            let fh = self.open_handle(ino);
+            reply.created(&TTL, &node.lock().unwrap().attr, 0, fh, 0);
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        if let Some(node) = self.inodes.get(&ino).cloned() {
+            // generated files are read directly, because their sizes change on open
+            let flags = if KotoFS::is_generated(node.clone()) {
+                FOPEN_DIRECT_IO
+            } else {
+                0
+            };
+            self.refresh(node);
+            let fh = self.open_handle(ino);
+            reply.opened(fh, flags);
         } else {
             reply.error(ENOENT);
         }
     }
 
+    fn flush(&mut self, _req: &Request, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        self.flush_handle(fh);
+        reply.ok();
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.flush_handle(fh);
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<Timespec>,
+        _mtime: Option<Timespec>,
+        _fd: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        match self.inodes.get(&ino) {
+            Some(node) => {
+                let node = node.clone();
+                if let Some(size) = size {
+                    if size > MAX_FILE_SIZE as u64 {
+                        reply.error(EFBIG);
+                        return;
+                    }
+                    let mut node = node.lock().unwrap();
+                    node.data.resize(size as usize, 0);
+                    node.attr.size = size;
+                }
+
+                let mut is_opened = false;
+                for handle in self.handles.values_mut().filter(|h| h.ino == ino) {
+                    is_opened = true;
+                    if size.is_some() {
+                        handle.dirty = true;
+                    }
+                }
+
+                if is_opened && size.is_some() {
+                    // truncated by `open(O_TRUNC)`; applied when it's closed
+                } else if !KotoNode::is_control(node.clone()) {
+                    self.sync(node.clone(), "".to_string());
+                } else if size.is_none() {
+                    // `touch .koto/txn/commit` and so on
+                    self.write_control(node.clone());
+                }
+                reply.attr(&TTL, &node.lock().unwrap().attr);
+            }
+            None => reply.error(EACCES),
+        }
+    }
+
+    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        let name = name.to_str().unwrap().to_string();
+        match self.make_dir(parent, name) {
+            Some(node) => reply.entry(&TTL, &node.lock().unwrap().attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_str().unwrap().to_string();
+        match self.remove_dir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        let name = name.to_str().unwrap().to_string();
+        let newname = newname.to_str().unwrap().to_string();
+        self.rename_node(parent, name, newparent, newname);
+        reply.ok();
+    }
+
     fn write(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _flags: u32,
         reply: ReplyWrite,
     ) {
-        let length: usize = data.len();
-        if let Some(n) = self.inodes.get_mut(&ino) {
-            if offset == 0 {
-                n.lock().unwrap().attr.size = data.len() as u64;
-                n.lock().unwrap().data = data.to_vec();
-            } else {
-                n.lock().unwrap().attr.size += data.len() as u64;
-                n.lock().unwrap().data.append(&mut data.to_vec());
+        if let Some(n) = self.inodes.get(&ino) {
+            if KotoFS::is_generated(n.clone()) {
+                reply.error(EACCES);
+                return;
             }
         }
 
+        let length: usize = data.len();
+        let end = match (offset as usize).checked_add(data.len()) {
+            Some(end) if offset >= 0 && end <= MAX_FILE_SIZE => end,
+            _ => {
+                reply.error(EFBIG);
+                return;
+            }
+        };
         if let Some(n) = self.inodes.get(&ino) {
-            if let Ok(_) = self.lock.lock() {
-                KotoNode::sync_file(n.clone(), "".to_string());
+            let mut n = n.lock().unwrap();
+            let offset = offset as usize;
+            if n.data.len() < end {
+                n.data.resize(end, 0);
+            }
+            n.data[offset..end].copy_from_slice(data);
+            n.attr.size = n.data.len() as u64;
+        }
+
+        match self.handles.get_mut(&fh) {
+            Some(handle) => handle.dirty = true,
+            None => {
+                if let Some(n) = self.inodes.get(&ino).cloned() {
+                    self.apply(n);
+                }
             }
         }
         reply.written(length as u32);
@@ -613,7 +1595,8 @@ impl Filesystem for KotoFS {
     ) {
         match self.inodes.get(&ino) {
             Some(n) => {
-                let data_rest = &n.lock().unwrap().data[offset as usize..];
+                let data = &n.lock().unwrap().data;
+                let data_rest = &data[(offset as usize).min(data.len())..];
                 if data_rest.len() >= size as usize {
                     reply.data(&data_rest[..size as usize]);
                 } else {
@@ -625,38 +1608,11 @@ impl Filesystem for KotoFS {
     }
 
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let mut inode = None;
-
-        if let Some(parent_node) = self.inodes.get(&parent) {
-            let name = name.to_str().unwrap().to_string();
-
-            let pos = parent_node
-                .lock()
-                .unwrap()
-                .children
-                .iter()
-                .position(|(nodename, _)| nodename == &name);
-            if let Some(pos) = pos {
-                let (_, node) = parent_node.lock().unwrap().children.remove(pos);
-                inode = Some(node.lock().unwrap().attr.ino);
-            }
-
-            if let Ok(_) = self.lock.lock() {
-                if let Ugen::Mapped(ref mut aug) = &mut parent_node.lock().unwrap().ug {
-                    if let Some((paramname, _)) = KotoNode::parse_nodename(name) {
-                        aug.clear(&paramname);
-                    }
-                }
-            }
-        }
-
-        if let Some(inode) = inode {
-            self.inodes.remove(&inode);
-            reply.ok();
-            return;
+        let name = name.to_str().unwrap().to_string();
+        match self.remove_file(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
         }
-
-        reply.error(ENOENT);
     }
 
     fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
@@ -702,9 +1658,7 @@ impl Filesystem for KotoFS {
                 .push((name.clone(), node.clone()));
             self.inodes
                 .insert(node.lock().unwrap().attr.ino, node.clone());
-            if let Ok(_) = self.lock.lock() {
-                KotoNode::sync_ug(node.clone(), "".to_string(), self.transport.clone());
-            }
+            self.sync(node.clone(), "".to_string());
             reply.entry(&TTL, &node.lock().unwrap().attr, 0);
             return;
         }
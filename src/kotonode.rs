@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
@@ -9,12 +9,55 @@ use tapirus::musical_time::time::Transport;
 use tapirus::tapirlisp::eval::{eval, TYPE_NAMES};
 use tapirus::tapirlisp::sexp::read;
 use tapirus::tapirlisp::types::{Env, Value};
-use tapirus::ugens::core::{Aug, Dump, Operate, UgNode};
+use tapirus::ugens::core::{Aug, Dump, Operate, Slot, UgNode};
+
+use crate::expr::eval_expr;
+use crate::gate::{self, Flags, Gate};
+use crate::generate::{self, GENERATOR_NAMES};
+use crate::midi;
+use crate::ramp::{current_value, parse_ramp, value_of, Ramp};
+use crate::schedule;
+use crate::table::{self, Interp};
+use crate::tracker;
+use crate::transform::{self, Transform, Transformed, SETTING_NAMES};
+use crate::tuning::{self, Tuned, Tuning};
+use crate::wav;
+
+pub fn table_to_text(table: &[f64]) -> String {
+    let mut tab = String::new();
+    for val in table {
+        tab.push_str(&format!("{}", val));
+        tab.push_str(" ");
+    }
+    tab.push_str("\n");
+    tab
+}
+
+/// Encodes a table into raw little-endian f32 bytes of `.f32` files.
+pub fn table_to_f32(table: &[f64]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(table.len() * 4);
+    for val in table {
+        data.extend_from_slice(&(*val as f32).to_le_bytes());
+    }
+    data
+}
+
+pub fn f32_to_table(data: &[u8]) -> Vec<f64> {
+    data.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+        .collect()
+}
+
+pub const KOTO_TYPE_NAMES: [&str; 1] = ["sampler"];
+
+/// Types of view files and their sources, like `pattern.trk` of `pattern.pat`.
+const VIEW_TYPES: [(&str, &str); 2] = [("trk", "pat"), ("f32", "tab")];
 
 #[derive(Clone)]
 pub enum Ugen {
     NotMapped,
     Mapped(Aug),
+    Control(String),
 }
 
 #[derive(Clone)]
@@ -83,6 +126,15 @@ impl KotoNode {
         children
     }
 
+    pub fn get_child(node: Arc<Mutex<KotoNode>>, name: &str) -> Option<Arc<Mutex<KotoNode>>> {
+        node.lock()
+            .unwrap()
+            .children
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, child)| child.clone())
+    }
+
     fn resolve_symlink_1(
         path: &[&str],
         node: Arc<Mutex<KotoNode>>,
@@ -150,19 +202,40 @@ impl KotoNode {
         }
     }
 
-    pub fn build_ug_from_node(
-        node: Arc<Mutex<KotoNode>>,
-        transport: Arc<Mutex<Transport>>,
-    ) -> Option<Aug> {
-        let name = node.lock().unwrap().name.clone();
-        if let Ugen::Mapped(aug) = &node.lock().unwrap().ug {
-            return Some(aug.clone());
+    /// Module types which Koto builds from TapirLisp units by itself.
+    pub fn is_module_type(typename: &str) -> bool {
+        let set: HashSet<&str> = TYPE_NAMES.iter().cloned().collect();
+        set.contains(typename)
+            || KOTO_TYPE_NAMES.contains(&typename)
+            || KotoNode::is_generator(typename)
+    }
+
+    pub fn is_generator(typename: &str) -> bool {
+        GENERATOR_NAMES.contains(&typename)
+    }
+
+    /// Returns the module type of a unit dumped as `typename` with `slots`.
+    ///
+    /// A `sampler` is saved as a `wavetable` which has a `oneshot` phase, so it's found by
+    /// the phase when the unit graph is loaded again.
+    pub fn module_typename(typename: &str, slots: &[Slot]) -> String {
+        let oneshot = slots
+            .iter()
+            .find(|s| s.name == "ph")
+            .map(|s| match s.ug.dump(&vec![]) {
+                UgNode::Ug(name, _) => name == "oneshot",
+                _ => false,
+            });
+        if typename == "wavetable" && oneshot == Some(true) {
+            "sampler".to_string()
+        } else {
+            typename.to_string()
         }
+    }
 
-        println!("building {}.", name.clone());
-        let (_, name) = KotoNode::parse_nodename(name.clone()).unwrap();
-        let mut env = Env::init(Transport::new(transport.lock().unwrap().sample_rate));
-        let form_str = match &name[..] {
+    /// Returns the TapirLisp form which a module of `typename` is built from.
+    pub fn default_form(typename: &str) -> &'static str {
+        match typename {
             "pan" => "(pan 0 0)",
             "clip" => "(clip 0 0 0)",
             "offset" => "(offset 0 0)",
@@ -181,7 +254,10 @@ impl KotoNode {
             "table" => "(table 0 0)",
             "phase" => "(phase 0)",
             "wavetable" => "(wavetable (table -1 -1 -1 1 1 1) 0)",
+            "sampler" => "(wavetable (table 0 0) (oneshot (phase (saw 0 0)) (adsr 0 0 1 0)))",
             "pat" => "(pat)",
+            // filled by `KotoNode::sync_generator()`
+            "euclid" | "randpat" | "arp" => "(pat)",
             "trig" => "(trig 0 0)",
             "adsr" => "(adsr 0 0 0 0)",
             "seq" => "(seq 0 0 0 0)",
@@ -189,7 +265,22 @@ impl KotoNode {
             "delay" => "(delay 0 0 0 0)",
             "out" => "(out 0 0)",
             _ => "0",
-        };
+        }
+    }
+
+    pub fn build_ug_from_node(
+        node: Arc<Mutex<KotoNode>>,
+        transport: Arc<Mutex<Transport>>,
+    ) -> Option<Aug> {
+        let name = node.lock().unwrap().name.clone();
+        if let Ugen::Mapped(aug) = &node.lock().unwrap().ug {
+            return Some(aug.clone());
+        }
+
+        println!("building {}.", name.clone());
+        let (_, name) = KotoNode::parse_nodename(name.clone()).unwrap();
+        let mut env = Env::init(Transport::new(transport.lock().unwrap().sample_rate));
+        let form_str = KotoNode::default_form(&name);
 
         match read(form_str.to_string()) {
             Ok(form) => match eval(&form[0], &mut env) {
@@ -277,7 +368,54 @@ impl KotoNode {
         }
     }
 
-    pub fn sync_file(node: Arc<Mutex<KotoNode>>, oldname: String) {
+    pub fn sync_file(
+        node: Arc<Mutex<KotoNode>>,
+        oldname: String,
+        transport: Arc<Mutex<Transport>>,
+    ) {
+        let parent = node.lock().unwrap().parent.clone();
+        if let Some(parent) = parent {
+            if let Some(typename) = KotoNode::get_typename(parent.clone()) {
+                if KotoNode::is_generator(&typename) {
+                    KotoNode::sync_generator(parent, transport.clone());
+                    return;
+                }
+            }
+        }
+
+        if let Some((paramname, typename)) = KotoNode::get_nodename(node.clone()) {
+            if &typename[..] == "wav" {
+                KotoNode::sync_wav(node.clone(), paramname, transport.clone());
+                return;
+            }
+            if &typename[..] == "f32" {
+                let table = f32_to_table(&node.lock().unwrap().data);
+                KotoNode::set_table(node.clone(), paramname, table);
+                return;
+            }
+            if &typename[..] == "mid" {
+                KotoNode::sync_midi(node.clone(), paramname, transport.clone());
+                return;
+            }
+            if &typename[..] == "pat" {
+                let pat = String::from_utf8_lossy(&node.lock().unwrap().data).to_string();
+                let items = tracker::split_pattern(&pat);
+                KotoNode::set_pattern(node.clone(), paramname, items);
+                return;
+            }
+            if &typename[..] == "trk" {
+                let trk = String::from_utf8_lossy(&node.lock().unwrap().data).to_string();
+                match tracker::from_trk(&trk) {
+                    Ok(items) => KotoNode::set_pattern(node.clone(), paramname, items),
+                    Err(err) => {
+                        println!("cannot read '{}' as a tracker pattern", paramname);
+                        println!("{:?}", err);
+                    }
+                }
+                return;
+            }
+        }
+
         let data = node.lock().unwrap().data.clone();
         let data: String = if let Ok(data) = String::from_utf8(data.clone()) {
             data.clone()
@@ -286,33 +424,591 @@ impl KotoNode {
             return;
         };
 
-        if let Some((paramname, _)) = KotoNode::get_nodename(node.clone()) {
-            let mut node_ug = None;
-            if let Some(parent) = &node.lock().unwrap().parent {
-                if let Ugen::Mapped(ref mut aug) = &mut parent.lock().unwrap().ug {
-                    let mut data = data.clone();
-                    data.retain(|c| c != '\n');
-                    if let Err(err) = aug.set_str(&paramname, data.clone()) {
-                        println!("Error while setting '{}'", data.clone());
-                        println!("{:?}", err);
+        if let Some((paramname, typename)) = KotoNode::get_nodename(node.clone()) {
+            let aug = match &node.lock().unwrap().parent {
+                Some(parent) => match &parent.lock().unwrap().ug {
+                    Ugen::Mapped(aug) => aug.clone(),
+                    _ => return,
+                },
+                None => return,
+            };
+            let table: Vec<f64> = data
+                .split_whitespace()
+                .filter_map(|v| v.parse().ok())
+                .collect();
+            let mut data = data.clone();
+            data.retain(|c| c != '\n');
+
+            let new_ug = if &typename[..] == "tab" {
+                table::table_ug(table.clone())
+            } else if &typename[..] == "val" {
+                let transport = transport.lock().unwrap();
+                if let Some((target, duration)) = parse_ramp(&data) {
+                    match (
+                        eval_expr(&target, &transport),
+                        eval_expr(&duration, &transport),
+                    ) {
+                        (Ok(to), Ok(duration)) => {
+                            // the last value of this file may not be swapped in yet
+                            let from = match &node.lock().unwrap().ug {
+                                Ugen::Mapped(ug) => value_of(ug),
+                                _ => None,
+                            };
+                            let from = from.or(current_value(&aug, &paramname)).unwrap_or(to);
+                            let length = (duration.max(0.0) * transport.sample_rate as f64) as u64;
+                            Ramp::new(from, to, transport.tick, length)
+                        }
+                        (Err(err), _) | (_, Err(err)) => {
+                            println!("cannot evaluate '{}' as a glide", data.clone());
+                            println!("{:?}", err);
+                            return;
+                        }
                     }
-                    if let Ok(ug) = aug.get(&paramname) {
-                        node_ug = Some(ug.clone());
+                } else {
+                    match eval_expr(&data, &transport) {
+                        Ok(n) => Aug::val(n),
+                        Err(err) => {
+                            println!("cannot evaluate '{}' as an expression", data.clone());
+                            println!("{:?}", err);
+                            return;
+                        }
                     }
                 }
+            } else {
+                match data.trim().parse() {
+                    Ok(n) => Aug::val(n),
+                    Err(err) => {
+                        println!("invalid data: {:?}", data.clone());
+                        println!("{:?}", err);
+                        return;
+                    }
+                }
+            };
+
+            if let Err(err) = schedule::set(&aug, &paramname, new_ug.clone()) {
+                println!("Error while setting '{}'", data.clone());
+                println!("{:?}", err);
+                return;
             }
-            if let Some(ug) = node_ug {
-                node.lock().unwrap().ug = Ugen::Mapped(ug.clone());
+            node.lock().unwrap().ug = Ugen::Mapped(new_ug);
+            if &typename[..] == "tab" {
+                KotoNode::sync_table_views(node.clone(), &paramname, &table);
             }
         } else {
             if let Some((paramname, _)) = KotoNode::parse_nodename(oldname.clone()) {
                 if let Some(parent) = &node.lock().unwrap().parent {
-                    if let Ugen::Mapped(ref mut aug) = &mut parent.lock().unwrap().ug {
-                        aug.clear(&paramname);
+                    if let Ugen::Mapped(aug) = &parent.lock().unwrap().ug {
+                        schedule::clear(aug, &paramname);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decodes WAV file bytes and sets them as the table `paramname`.
+    ///
+    /// In a `sampler`, the phase oscillator is also set to play the table at normal speed.
+    pub fn sync_wav(
+        node: Arc<Mutex<KotoNode>>,
+        paramname: String,
+        transport: Arc<Mutex<Transport>>,
+    ) {
+        let sample_rate = transport.lock().unwrap().sample_rate;
+        let table = match wav::decode(&node.lock().unwrap().data, sample_rate) {
+            Ok(table) => table,
+            Err(err) => {
+                println!("cannot read '{}' as a WAV file", paramname);
+                println!("{:?}", err);
+                return;
+            }
+        };
+
+        KotoNode::set_table(node.clone(), paramname.clone(), table.clone());
+
+        let parent = match &node.lock().unwrap().parent {
+            Some(parent) => parent.clone(),
+            None => return,
+        };
+        if KotoNode::get_typename(parent.clone()) != Some("sampler".to_string()) || table.len() == 0
+        {
+            return;
+        }
+
+        let aug = match &parent.lock().unwrap().ug {
+            Ugen::Mapped(aug) => aug.clone(),
+            _ => return,
+        };
+
+        // see `bin/calc-phasef` for this expression
+        let freq = 2.0 * sample_rate as f64 / table.len() as f64;
+        let saw = aug
+            .get("ph")
+            .and_then(|oneshot| oneshot.get("osc"))
+            .and_then(|phase| phase.get("osc"));
+        match saw {
+            Ok(saw) => {
+                let _ = schedule::set(&saw, "freq", Aug::val(freq));
+            }
+            Err(err) => {
+                println!("sampler has no phase oscillator");
+                println!("{:?}", err);
+            }
+        }
+    }
+
+    /// Converts Standard MIDI File bytes into a pattern and sets it as `paramname`.
+    pub fn sync_midi(
+        node: Arc<Mutex<KotoNode>>,
+        paramname: String,
+        transport: Arc<Mutex<Transport>>,
+    ) {
+        let items = match midi::parse(&node.lock().unwrap().data).and_then(|smf| {
+            let notes = smf.track(None)?;
+            Ok(midi::to_pattern(
+                notes,
+                smf.division,
+                &transport.lock().unwrap(),
+            ))
+        }) {
+            Ok(items) => items,
+            Err(err) => {
+                println!("cannot read '{}' as a MIDI file", paramname);
+                println!("{:?}", err);
+                return;
+            }
+        };
+        KotoNode::set_pattern(node, paramname, items);
+    }
+
+    /// Sets `table` as the table parameter `paramname` of the parent of `node`.
+    pub fn set_table(node: Arc<Mutex<KotoNode>>, paramname: String, table: Vec<f64>) {
+        let mut node_ug = None;
+        if let Some(parent) = &node.lock().unwrap().parent {
+            if let Ugen::Mapped(aug) = &parent.lock().unwrap().ug {
+                let ug = table::table_ug(table.clone());
+                match schedule::set(aug, &paramname, ug.clone()) {
+                    Ok(_) => node_ug = Some(ug),
+                    Err(err) => {
+                        println!("Error while setting table '{}'", paramname);
+                        println!("{:?}", err);
+                    }
+                }
+            }
+        }
+        if let Some(ug) = node_ug {
+            node.lock().unwrap().ug = Ugen::Mapped(ug.clone());
+            KotoNode::sync_table_views(node.clone(), &paramname, &table);
+        }
+    }
+
+    /// Updates `xxx.tab` and `xxx.f32` views of the table `xxx` except `node` itself.
+    pub fn sync_table_views(node: Arc<Mutex<KotoNode>>, paramname: &str, table: &[f64]) {
+        let parent = match &node.lock().unwrap().parent {
+            Some(parent) => parent.clone(),
+            None => return,
+        };
+
+        for (name, view) in KotoNode::get_children(parent).iter() {
+            if Arc::ptr_eq(view, &node) {
+                continue;
+            }
+            let data = match KotoNode::parse_nodename(name.clone()) {
+                Some((ref p, ref t)) if p == paramname && t == "tab" => {
+                    table_to_text(table).into_bytes()
+                }
+                Some((ref p, ref t)) if p == paramname && t == "f32" => table_to_f32(table),
+                Some((ref p, ref t)) if p == paramname && t == "d" => {
+                    if let Some(length) = KotoNode::get_child(view.clone(), "length") {
+                        let data = format!("{}\n", table.len()).into_bytes();
+                        let mut length = length.lock().unwrap();
+                        length.attr.size = data.len() as u64;
+                        length.data = data;
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            let mut view = view.lock().unwrap();
+            view.attr.size = data.len() as u64;
+            view.data = data;
+        }
+    }
+
+    /// Reads contents of `reverse`, `rotate`, `transpose` and `scale` files of `node`.
+    fn get_transform_settings(node: Arc<Mutex<KotoNode>>) -> Vec<(String, String)> {
+        SETTING_NAMES
+            .iter()
+            .zip(["0", "0", "0", "off"].iter())
+            .map(|(name, default)| {
+                let data = match KotoNode::get_child(node.clone(), name) {
+                    Some(child) => KotoNode::read_text(child).trim().to_string(),
+                    None => default.to_string(),
+                };
+                (name.to_string(), data)
+            })
+            .collect()
+    }
+
+    /// Reads `transpose`, `reverse`, `rotate` and `scale` files of the sequencer `node`.
+    pub fn get_transform(node: Arc<Mutex<KotoNode>>) -> Option<Transform> {
+        if KotoNode::get_typename(node.clone()) != Some("seq".to_string()) {
+            return None;
+        }
+
+        let settings = KotoNode::get_transform_settings(node);
+        match Transform::parse(
+            &settings[0].1,
+            &settings[1].1,
+            &settings[2].1,
+            &settings[3].1,
+        ) {
+            Ok(transform) => Some(transform),
+            Err(err) => {
+                println!("cannot transform the pattern");
+                println!("{:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Sets pattern `items` as the pattern parameter `paramname` of the parent of `node`.
+    ///
+    /// In a sequencer, the pattern is transformed by its control files but views show `items`.
+    pub fn set_pattern(node: Arc<Mutex<KotoNode>>, paramname: String, items: Vec<String>) {
+        let parent = node.lock().unwrap().parent.clone();
+        let transformed = match parent.clone().and_then(KotoNode::get_transform) {
+            Some(transform) if &paramname[..] == "pattern" => transform.apply(&items),
+            _ => items.clone(),
+        };
+
+        let pattern = match KotoNode::build_pattern(&transformed) {
+            Some(pattern) => pattern,
+            None => return,
+        };
+        let mut node_ug = None;
+        if let Some(parent) = &parent {
+            if let Ugen::Mapped(aug) = &parent.lock().unwrap().ug {
+                match schedule::set(aug, &paramname, pattern.clone()) {
+                    Ok(_) => node_ug = Some(pattern),
+                    Err(err) => {
+                        println!("Error while setting pattern '{}'", paramname);
+                        println!("{:?}", err);
+                    }
+                }
+            }
+        }
+        if let Some(ug) = node_ug {
+            node.lock().unwrap().ug = Ugen::Mapped(ug.clone());
+            KotoNode::sync_pattern_views(node.clone(), &paramname, &items);
+        }
+        // the sequencer is wrapped again to dump the new source pattern
+        if let Some(parent) = parent {
+            if &paramname[..] == "pattern" && KotoNode::get_transform(parent.clone()).is_some() {
+                KotoNode::apply_flags(parent);
+            }
+        }
+    }
+
+    /// Builds a pattern unit of `items`, to set it as a parameter.
+    fn build_pattern(items: &[String]) -> Option<Aug> {
+        let form_str = format!("(pat {})", tracker::join_pattern(items));
+        let mut env = Env::init(Transport::new(44100));
+        match read(form_str.clone()) {
+            Ok(form) => match eval(&form[0], &mut env) {
+                Ok(Value::Unit(aug)) => Some(aug),
+                Ok(_) => {
+                    println!("'{}' is not a pattern", form_str);
+                    None
+                }
+                Err(err) => {
+                    println!("cannot build the pattern '{}'", form_str);
+                    println!("{:?}", err);
+                    None
+                }
+            },
+            Err(err) => {
+                println!("cannot read the pattern '{}'", form_str);
+                println!("{:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Updates `xxx.pat` and `xxx.trk` views of the pattern `xxx` except `node` itself.
+    pub fn sync_pattern_views(node: Arc<Mutex<KotoNode>>, paramname: &str, items: &[String]) {
+        let parent = match &node.lock().unwrap().parent {
+            Some(parent) => parent.clone(),
+            None => return,
+        };
+
+        for (name, view) in KotoNode::get_children(parent).iter() {
+            if Arc::ptr_eq(view, &node) {
+                continue;
+            }
+            let mut view = view.lock().unwrap();
+            let data = match KotoNode::parse_nodename(name.clone()) {
+                Some((ref p, ref t)) if p == paramname && t == "pat" => {
+                    tracker::join_pattern(items)
+                }
+                Some((ref p, ref t)) if p == paramname && t == "trk" => {
+                    // comments of steps are kept
+                    let comments = tracker::comments(&String::from_utf8_lossy(&view.data));
+                    tracker::to_trk(items, &comments)
+                }
+                _ => continue,
+            };
+            view.attr.size = data.len() as u64;
+            view.data = data.into_bytes();
+        }
+    }
+
+    /// Sets the pattern of the sequencer `node` again with its current transformation.
+    pub fn sync_transform(node: Arc<Mutex<KotoNode>>, transport: Arc<Mutex<Transport>>) {
+        let mut generator = None;
+        for (name, child) in KotoNode::get_children(node.clone()).iter() {
+            let items = match KotoNode::parse_nodename(name.clone()) {
+                Some((ref p, ref t)) if p == "pattern" && t == "pat" => {
+                    tracker::split_pattern(&String::from_utf8_lossy(&child.lock().unwrap().data))
+                }
+                Some((ref p, ref t)) if p == "pattern" && t == "trk" => {
+                    let trk = String::from_utf8_lossy(&child.lock().unwrap().data).to_string();
+                    match tracker::from_trk(&trk) {
+                        Ok(items) => items,
+                        Err(_) => continue,
                     }
                 }
+                Some((ref p, ref t)) if p == "pattern" && KotoNode::is_generator(t) => {
+                    generator = Some(child.clone());
+                    continue;
+                }
+                _ => continue,
+            };
+            KotoNode::set_pattern(child.clone(), "pattern".to_string(), items);
+            return;
+        }
+
+        if let Some(generator) = generator {
+            KotoNode::sync_generator(generator, transport);
+        }
+    }
+
+    fn get_root(node: Arc<Mutex<KotoNode>>) -> Arc<Mutex<KotoNode>> {
+        let mut node = node;
+        loop {
+            let parent = node.lock().unwrap().parent.clone();
+            match parent {
+                Some(parent) => node = parent,
+                None => return node,
+            }
+        }
+    }
+
+    fn read_text(node: Arc<Mutex<KotoNode>>) -> String {
+        String::from_utf8_lossy(&node.lock().unwrap().data).to_string()
+    }
+
+    /// Reads the tuning of the sequencer `node` from `.koto/tuning`.
+    ///
+    /// The `tuning` file of the sequencer selects a scale by its name, and `off` is 12-TET.
+    pub fn get_tuning(node: Arc<Mutex<KotoNode>>) -> Option<(String, Tuning)> {
+        let name = match KotoNode::get_child(node.clone(), "tuning") {
+            Some(file) => KotoNode::read_text(file).trim().to_string(),
+            None => "off".to_string(),
+        };
+        if &name[..] == "off" || &name[..] == "12tet" || name.len() == 0 {
+            return None;
+        }
+
+        let root = KotoNode::get_root(node);
+        let dir = KotoNode::get_child(root, ".koto")
+            .and_then(|koto| KotoNode::get_child(koto, "tuning"))?;
+
+        let scl = match KotoNode::get_child(dir.clone(), &format!("{}.scl", name)) {
+            Some(scl) => KotoNode::read_text(scl),
+            None => {
+                println!("no such scale: {:?}", name);
+                return None;
+            }
+        };
+        let scale = match tuning::parse_scl(&scl) {
+            Ok(scale) => scale,
+            Err(err) => {
+                println!("cannot read '{}.scl' as a scale", name);
+                println!("{:?}", err);
+                return None;
+            }
+        };
+        let keymap = match KotoNode::get_child(dir.clone(), &format!("{}.kbm", name)) {
+            Some(kbm) => match tuning::parse_kbm(&KotoNode::read_text(kbm)) {
+                Ok(keymap) => Some(keymap),
+                Err(err) => {
+                    println!("cannot read '{}.kbm' as a keyboard mapping", name);
+                    println!("{:?}", err);
+                    None
+                }
+            },
+            None => None,
+        };
+        Some((name, Tuning::new(scale, keymap)))
+    }
+
+    /// Plays the oscillator of the sequencer `node` through its tuning, or directly if it
+    /// has no tuning.
+    pub fn apply_tuning(node: Arc<Mutex<KotoNode>>) {
+        if KotoNode::get_typename(node.clone()) != Some("seq".to_string()) {
+            return;
+        }
+        let tuning = KotoNode::get_tuning(node.clone());
+        let aug = match &node.lock().unwrap().ug {
+            Ugen::Mapped(aug) => aug.clone(),
+            _ => return,
+        };
+        // the oscillator of the directory may not be swapped in yet
+        let child = KotoNode::get_children(node.clone())
+            .into_iter()
+            .find(|(name, child)| {
+                KotoNode::parse_nodename(name.clone()).map(|(p, _)| p) == Some("osc".to_string())
+                    && !KotoNode::is_control(child.clone())
+            })
+            .and_then(|(_, child)| match &child.lock().unwrap().ug {
+                Ugen::Mapped(osc) => Some(osc.clone()),
+                _ => None,
+            });
+        let osc = match child.ok_or(()).or(aug.get("osc").map_err(|_| ())) {
+            Ok(osc) => tuning::tuned_of(&osc).unwrap_or(osc),
+            Err(_) => return,
+        };
+        let osc = match tuning {
+            Some((name, tuning)) => Tuned::new(osc, name, tuning),
+            None => osc,
+        };
+        if let Err(err) = schedule::set(&aug, "osc", osc) {
+            println!("Error while tuning the sequencer");
+            println!("{:?}", err);
+        }
+    }
+
+    /// Generates a pattern by the generator directory `node` and sets it to the sequencer.
+    pub fn sync_generator(node: Arc<Mutex<KotoNode>>, transport: Arc<Mutex<Transport>>) {
+        let (paramname, typename) = match KotoNode::get_nodename(node.clone()) {
+            Some(nodename) => nodename,
+            None => return,
+        };
+
+        let mut params = HashMap::new();
+        for (name, child) in KotoNode::get_children(node.clone()).iter() {
+            if let Some((name, _)) = KotoNode::parse_nodename(name.clone()) {
+                let data = String::from_utf8_lossy(&child.lock().unwrap().data).to_string();
+                params.insert(name, data);
             }
         }
+
+        let items = match generate::generate(&typename, &params, &transport.lock().unwrap()) {
+            Ok(items) => items,
+            Err(err) => {
+                println!("cannot generate a pattern by '{}'", typename);
+                println!("{:?}", err);
+                return;
+            }
+        };
+        KotoNode::set_pattern(node, paramname, items);
+    }
+
+    /// Edits the table `xxx` by the file `node` in its `xxx.d` directory.
+    pub fn sync_table_control(node: Arc<Mutex<KotoNode>>, transport: Arc<Mutex<Transport>>) {
+        let (name, data, dir) = {
+            let node = node.lock().unwrap();
+            let name = match &node.ug {
+                Ugen::Control(name) => name.clone(),
+                _ => return,
+            };
+            let data = String::from_utf8_lossy(&node.data).trim().to_string();
+            match &node.parent {
+                Some(dir) => (name, data, dir.clone()),
+                None => return,
+            }
+        };
+        let paramname = match KotoNode::get_nodename(dir.clone()) {
+            Some((paramname, _)) => paramname,
+            None => return,
+        };
+        let module = match &dir.lock().unwrap().parent {
+            Some(module) => module.clone(),
+            None => return,
+        };
+        let view = KotoNode::get_child(module.clone(), &format!("{}.tab", paramname)).or(
+            KotoNode::get_child(module.clone(), &format!("{}.f32", paramname)),
+        );
+        let view = match view {
+            Some(view) => view,
+            None => return,
+        };
+        // the last edit of the table may not be swapped in yet, but its view is written
+        let current: Vec<f64> = {
+            let view = view.lock().unwrap();
+            if view.name.ends_with(".f32") {
+                f32_to_table(&view.data)
+            } else {
+                String::from_utf8_lossy(&view.data)
+                    .split_whitespace()
+                    .filter_map(|v| v.parse().ok())
+                    .collect()
+            }
+        };
+
+        let new_table = match &name[..] {
+            "length" => table::parse_length(&data).map(|len| {
+                // the resampling mode only affects resizing, not playback
+                let interp = match KotoNode::get_child(dir.clone(), "resample") {
+                    Some(mode) => {
+                        let mode = String::from_utf8_lossy(&mode.lock().unwrap().data).to_string();
+                        Interp::parse(&mode).unwrap_or(Interp::Linear)
+                    }
+                    None => Interp::Linear,
+                };
+                table::resize(&current, len, interp)
+            }),
+            "harmonics" => {
+                let len = if current.len() < 2 {
+                    table::DEFAULT_LENGTH
+                } else {
+                    current.len()
+                };
+                table::harmonics(&data, len)
+            }
+            "normalize" => {
+                let peak = if data.len() == 0 {
+                    Ok(1.0)
+                } else {
+                    eval_expr(&data, &transport.lock().unwrap())
+                };
+                match peak {
+                    Ok(peak) => Ok(table::normalize(&current, peak)),
+                    Err(err) => {
+                        println!("cannot evaluate '{}' as a peak", data);
+                        println!("{:?}", err);
+                        return;
+                    }
+                }
+            }
+            "resample" => {
+                if Interp::parse(&data).is_none() {
+                    println!("invalid resampling mode: {:?}", data);
+                }
+                return;
+            }
+            _ => return,
+        };
+
+        let new_table = match new_table {
+            Ok(new_table) => new_table,
+            Err(err) => {
+                println!("cannot edit the table '{}'", paramname);
+                println!("{:?}", err);
+                return;
+            }
+        };
+        KotoNode::set_table(view, paramname, new_table);
     }
 
     pub fn sync_directory(
@@ -322,16 +1018,20 @@ impl KotoNode {
     ) {
         if let Some((paramname, typename)) = KotoNode::get_nodename(node.clone()) {
             // nodename satisfies xxx.yyy format
-            let set: HashSet<&str> = TYPE_NAMES.iter().cloned().collect();
-            if set.contains(&typename[..]) {
+            if KotoNode::is_module_type(&typename) {
                 // typename (yyy of xxx.yyy) is valid
                 if let Some(new_ug) = KotoNode::build_ug_from_node(node.clone(), transport.clone())
                 {
                     if let Some(parent) = &node.lock().unwrap().parent {
-                        if let Ugen::Mapped(ref mut parent_ug) = &mut parent.lock().unwrap().ug {
-                            let _ = parent_ug.set(&paramname, new_ug.clone());
+                        if let Ugen::Mapped(parent_ug) = &parent.lock().unwrap().ug {
+                            let _ = schedule::set(parent_ug, &paramname, new_ug.clone());
                         }
                     }
+                    if KotoNode::is_generator(&typename) {
+                        KotoNode::sync_generator(node.clone(), transport.clone());
+                    } else {
+                        KotoNode::apply_flags(node.clone());
+                    }
                 }
             } else if &typename[..] == "shared" {
                 println!("aaaaaaaaaaaaa");
@@ -341,18 +1041,16 @@ impl KotoNode {
                 }
                 if let Some(ug) = &aug {
                     if let Some(parent) = &node.lock().unwrap().parent {
-                        if let Ugen::Mapped(ref mut parent_ug) = &mut parent.lock().unwrap().ug {
-                            let _ = parent_ug.set(&paramname, ug.clone());
+                        if let Ugen::Mapped(parent_ug) = &parent.lock().unwrap().ug {
+                            let _ = schedule::set(parent_ug, &paramname, ug.clone());
                         }
                     }
                 }
             } else {
                 // paramname (xxx of xxx.yyy) is changed (or filename is not changed)
                 if let Some(parent) = &node.lock().unwrap().parent {
-                    if let Ugen::Mapped(ref mut parent_ug) = &mut parent.lock().unwrap().ug {
-                        {
-                            let _ = parent_ug.clear(&paramname);
-                        }
+                    if let Ugen::Mapped(parent_ug) = &parent.lock().unwrap().ug {
+                        schedule::clear(parent_ug, &paramname);
                     }
                 }
             }
@@ -360,8 +1058,8 @@ impl KotoNode {
             // nodename not satisfies xxx.yyy format
             if let Some((paramname, _)) = KotoNode::parse_nodename(oldname.clone()) {
                 if let Some(parent) = &node.lock().unwrap().parent {
-                    if let Ugen::Mapped(ref mut aug) = &mut parent.lock().unwrap().ug {
-                        aug.clear(&paramname);
+                    if let Ugen::Mapped(aug) = &parent.lock().unwrap().ug {
+                        schedule::clear(aug, &paramname);
                     }
                 }
             }
@@ -384,9 +1082,9 @@ impl KotoNode {
                     }
 
                     if let Some(parent) = parent {
-                        if let Ugen::Mapped(ref mut parent_aug) = &mut parent.lock().unwrap().ug {
+                        if let Ugen::Mapped(parent_aug) = &parent.lock().unwrap().ug {
                             // this Aug.set() causes deadlock but why...???
-                            let _ = parent_aug.set(&paramname, aug.clone());
+                            let _ = schedule::set(parent_aug, &paramname, aug.clone());
                             node.lock().unwrap().ug = Ugen::Mapped(aug.clone());
                         }
                     }
@@ -395,10 +1093,166 @@ impl KotoNode {
         }
     }
 
+    /// Returns true if the file `name` is a view of another file in `siblings`,
+    /// like `pattern.trk` next to `pattern.pat`.
+    pub fn is_view(name: &str, siblings: &[String]) -> bool {
+        match KotoNode::parse_nodename(name.to_string()) {
+            Some((paramname, typename)) => VIEW_TYPES
+                .iter()
+                .filter(|(view, _)| view == &typename)
+                .any(|(_, source)| siblings.contains(&format!("{}.{}", paramname, source))),
+            None => false,
+        }
+    }
+
+    pub fn is_control(node: Arc<Mutex<KotoNode>>) -> bool {
+        if let Ugen::Control(_) = &node.lock().unwrap().ug {
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the module type of a directory, like `seq` of `src0.seq`.
+    pub fn get_typename(node: Arc<Mutex<KotoNode>>) -> Option<String> {
+        if let Some((_, typename)) = KotoNode::get_nodename(node.clone()) {
+            return Some(typename);
+        }
+        let name = node.lock().unwrap().name.clone();
+        match KotoNode::parse_nodename(name.clone()) {
+            Some((_, typename)) => Some(typename),
+            None if name.len() > 0 => Some(name),
+            None => None,
+        }
+    }
+
+    pub fn get_flag(node: Arc<Mutex<KotoNode>>, flagname: &str) -> bool {
+        if let Some(flag) = KotoNode::get_child(node, flagname) {
+            if KotoNode::is_control(flag.clone()) {
+                let data = String::from_utf8_lossy(&flag.lock().unwrap().data).to_string();
+                return match data.trim() {
+                    "1" | "on" | "true" | "yes" => true,
+                    _ => false,
+                };
+            }
+        }
+        false
+    }
+
+    fn has_solo(node: Arc<Mutex<KotoNode>>) -> bool {
+        if KotoNode::get_flag(node.clone(), "solo") {
+            return true;
+        }
+        KotoNode::get_children(node)
+            .iter()
+            .filter(|(_, child)| !KotoNode::is_control(child.clone()))
+            .any(|(_, child)| KotoNode::has_solo(child.clone()))
+    }
+
+    /// Finds the nearest `out` or `+` which mixes `node`, and the source of it containing `node`.
+    fn find_mixer(
+        node: Arc<Mutex<KotoNode>>,
+    ) -> Option<(Arc<Mutex<KotoNode>>, Arc<Mutex<KotoNode>>)> {
+        let mut branch = node;
+        loop {
+            let parent = branch.lock().unwrap().parent.clone()?;
+            match KotoNode::get_typename(parent.clone()) {
+                Some(ref typename) if typename == "out" || typename == "+" => {
+                    return Some((parent, branch))
+                }
+                _ => branch = parent,
+            }
+        }
+    }
+
+    /// Connects `node` to its parent with respecting its mute, solo and bypass flags.
+    ///
+    /// A flagged module is connected through a `Gate`, so it stays in the unit graph, and a
+    /// transformed sequencer is connected through a `Transformed`.
+    pub fn apply_flags(node: Arc<Mutex<KotoNode>>) {
+        let paramname = match KotoNode::get_nodename(node.clone()) {
+            Some((paramname, _)) => paramname,
+            None => return,
+        };
+        let aug = match &node.lock().unwrap().ug {
+            Ugen::Mapped(aug) => aug.clone(),
+            _ => return,
+        };
+        let parent = match &node.lock().unwrap().parent {
+            Some(parent) => parent.clone(),
+            None => return,
+        };
+
+        let mut soloed_out = false;
+        if let Some(typename) = KotoNode::get_typename(parent.clone()) {
+            if (typename == "out" || typename == "+") && paramname.starts_with("src") {
+                let siblings = KotoNode::get_children(parent.clone());
+                let any_solo = siblings
+                    .iter()
+                    .filter(|(_, sibling)| !KotoNode::is_control(sibling.clone()))
+                    .any(|(_, sibling)| KotoNode::has_solo(sibling.clone()));
+                soloed_out = any_solo && !KotoNode::has_solo(node.clone());
+            }
+        }
+
+        let flags = Flags {
+            mute: KotoNode::get_flag(node.clone(), "mute"),
+            solo: KotoNode::get_flag(node.clone(), "solo"),
+            bypass: KotoNode::get_flag(node.clone(), "bypass"),
+        };
+        let aug = tuning::tuned_of(&aug).unwrap_or(aug);
+        let module = gate::gate_of(&aug).unwrap_or(aug);
+        let module = transform::transformed_of(&module).unwrap_or(module);
+        // sequencers play transformed patterns, but dump their source patterns
+        let module = match KotoNode::get_transform(node.clone()) {
+            Some(t) if !t.is_identity() => match KotoNode::get_child(node.clone(), "pattern.pat") {
+                Some(pat) => {
+                    let source = tracker::split_pattern(&KotoNode::read_text(pat));
+                    let settings = KotoNode::get_transform_settings(node.clone());
+                    Transformed::new(module, source, settings)
+                }
+                None => module,
+            },
+            _ => module,
+        };
+        if flags.bypass && module.get("src").is_err() {
+            println!("'{}' has no src to bypass", paramname);
+        }
+        let ug = if flags.mute || flags.solo || flags.bypass || soloed_out {
+            Gate::new(module, flags, soloed_out)
+        } else {
+            module
+        };
+
+        if let Ugen::Mapped(parent_aug) = &parent.lock().unwrap().ug {
+            let _ = schedule::set(parent_aug, &paramname, ug.clone());
+        }
+        node.lock().unwrap().ug = Ugen::Mapped(ug);
+        if &paramname[..] == "osc" {
+            KotoNode::apply_tuning(parent);
+        }
+    }
+
+    /// Reconnects `node` and, if it is mixed by `out` or `+`, its sibling sources.
+    pub fn sync_flags(node: Arc<Mutex<KotoNode>>) {
+        KotoNode::apply_flags(node.clone());
+        if let Some((mixer, _)) = KotoNode::find_mixer(node.clone()) {
+            for (name, source) in KotoNode::get_children(mixer.clone()).iter() {
+                if name.starts_with("src") && !KotoNode::is_control(source.clone()) {
+                    KotoNode::apply_flags(source.clone());
+                }
+            }
+        }
+    }
+
     pub fn sync_ug(node: Arc<Mutex<KotoNode>>, oldname: String, transport: Arc<Mutex<Transport>>) {
+        if KotoNode::is_control(node.clone()) {
+            return;
+        }
+
         let filetype = node.lock().unwrap().attr.kind;
         match filetype {
-            FileType::RegularFile => KotoNode::sync_file(node.clone(), oldname),
+            FileType::RegularFile => KotoNode::sync_file(node.clone(), oldname, transport.clone()),
             FileType::Directory => {
                 KotoNode::sync_directory(node.clone(), oldname, transport.clone())
             }
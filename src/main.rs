@@ -6,21 +6,46 @@ extern crate users;
 
 extern crate tapirus;
 
+mod ctl;
+mod diff;
+mod expr;
+mod gate;
+mod generate;
+mod graph;
+mod http;
+mod json;
 mod kotofs;
 mod kotonode;
+mod midi;
+mod ninep;
+mod osc;
+mod patch;
+mod ramp;
+mod repl;
+mod save;
+mod schedule;
+mod table;
+mod tracker;
+mod transform;
+mod tree;
+mod tuning;
+mod wav;
 
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use signal_hook::iterator::Signals;
 
 use tapirus::audiodevice::AudioDevice;
 use tapirus::musical_time::time::Transport;
 use tapirus::soundsystem::SoundSystem;
 use tapirus::tapirlisp as tlisp;
 use tapirus::tapirlisp::types::{Env, Value};
+use tapirus::ugens::core::Aug;
 
 fn main() {
     let matches = App::new("Koto - music performing filesystem")
@@ -31,20 +56,256 @@ fn main() {
                 .short("c")
                 .long("config")
                 .value_name("CONFIG.LISP")
-                .help("Sets initial synthesizer configuration"),
+                .help("Sets initial synthesizer configuration (TapirLisp or JSON)"),
+        )
+        .arg(
+            Arg::with_name("save")
+                .short("s")
+                .long("save")
+                .value_name("FORMAT")
+                .possible_values(&["lisp", "canonical"])
+                .help("Sets format of configurations saved by SIGUSR1 (default: lisp)"),
+        )
+        .arg(
+            Arg::with_name("http")
+                .long("http")
+                .value_name("ADDR:PORT")
+                .help("Serves the filesystem over HTTP, like '127.0.0.1:8080'"),
+        )
+        .arg(
+            Arg::with_name("http-origin")
+                .long("http-origin")
+                .value_name("ORIGIN")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Allows browsers at ORIGIN to access the HTTP server, like 'http://localhost:3000'"),
+        )
+        .arg(
+            Arg::with_name("osc-port")
+                .long("osc-port")
+                .value_name("PORT")
+                .help("Listens Open Sound Control messages at UDP PORT"),
+        )
+        .arg(
+            Arg::with_name("9p")
+                .long("9p")
+                .value_name("ADDR:PORT|SOCKET")
+                .help("Serves the filesystem over 9P2000 at a TCP address or a Unix domain socket"),
+        )
+        .arg(
+            Arg::with_name("ctl")
+                .long("ctl")
+                .value_name("SOCKET")
+                .help("Sets path of the control socket (default: koto.UID.sock in the temporary directory)"),
+        )
+        .arg(
+            Arg::with_name("repl")
+                .long("repl")
+                .help("Evaluates TapirLisp forms from stdin in the running environment"),
         )
         .arg(
             Arg::with_name("mountpoint")
                 .help("Specifies mount point")
                 .required(true),
         )
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("import-midi")
+                .about("Prints a Standard MIDI File as a pattern")
+                .arg(
+                    Arg::with_name("file")
+                        .help("Specifies MIDI file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("track")
+                        .short("t")
+                        .long("track")
+                        .value_name("N")
+                        .help("Sets track number counted from 1 (default: the first track with notes)"),
+                )
+                .arg(
+                    Arg::with_name("seq")
+                        .short("s")
+                        .long("seq")
+                        .value_name("OSC")
+                        .help("Prints a seq with an oscillator form like '(saw 0 0)'"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export-midi")
+                .about("Writes patterns of all seq in a configuration into a Standard MIDI File")
+                .arg(
+                    Arg::with_name("config")
+                        .help("Specifies configuration")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE.MID")
+                        .help("Sets output file name (default: koto.mid)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("graph")
+                .about("Prints the unit graph of a configuration in Graphviz DOT")
+                .arg(
+                    Arg::with_name("config")
+                        .help("Specifies configuration")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("json")
+                .about("Prints a configuration as a JSON patch")
+                .arg(
+                    Arg::with_name("config")
+                        .help("Specifies configuration")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export-tree")
+                .about("Writes a configuration as a directory tree like the mount point")
+                .arg(
+                    Arg::with_name("config")
+                        .help("Specifies configuration")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .help("Specifies output directory")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Removes files in the directory before writing, except hidden ones"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-tree")
+                .about("Builds a configuration from a directory tree like the mount point")
+                .arg(
+                    Arg::with_name("dir")
+                        .help("Specifies directory")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("root")
+                        .short("r")
+                        .long("root")
+                        .value_name("TYPE")
+                        .help("Sets module type of the directory itself (default: out)"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE.LISP")
+                        .help("Sets output file name (default: koto.lisp)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Prints changes between two configurations in paths of the mount point")
+                .arg(
+                    Arg::with_name("old")
+                        .help("Specifies old configuration")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .help("Specifies new configuration")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ctl")
+                .about("Controls the running Koto through its control socket")
+                .arg(
+                    Arg::with_name("socket")
+                        .short("S")
+                        .long("socket")
+                        .value_name("SOCKET")
+                        .help("Sets path of the control socket"),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .help("Specifies command")
+                        .possible_values(&["save", "load", "get", "set", "status", "tree"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .help("Specifies arguments: CONFIG for load, PATH for get, PATH VALUE for set")
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("Evaluates TapirLisp forms in the running Koto through its control socket")
+                .arg(
+                    Arg::with_name("socket")
+                        .short("S")
+                        .long("socket")
+                        .value_name("SOCKET")
+                        .help("Sets path of the control socket"),
+                ),
+        )
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("import-midi") {
+        import_midi(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("export-midi") {
+        export_midi(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("graph") {
+        print_graph(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("json") {
+        print_json(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("diff") {
+        print_diff(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("export-tree") {
+        export_tree(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("import-tree") {
+        import_tree(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("ctl") {
+        control(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("repl") {
+        attach_repl(matches);
+        return;
+    }
+
     let mut init_config: String;
     if let Some(config) = matches.value_of("config") {
         init_config = String::new();
         let mut f = File::open(config).unwrap();
         let _ = f.read_to_string(&mut init_config);
+        if config.ends_with(".json") {
+            init_config = match json_to_lisp(&init_config) {
+                Some(lisp) => lisp,
+                None => return,
+            };
+        }
     } else {
         init_config = "(out 0.25 0)".to_string();
     }
@@ -58,46 +319,389 @@ fn main() {
     let transport = Transport::new(sample_rate);
     let mut env = Env::init(transport);
 
-    let ug = match tlisp::eval_all(tlisp::sexp::read(init_config).unwrap(), &mut env) {
+    let ug = eval_config(init_config, &mut env);
+
+    let lock = Arc::new(Mutex::new(true));
+    let env_clone = Arc::new(Mutex::new(env.clone()));
+
+    let transport = Arc::new(Mutex::new(env.transport));
+    let fs = kotofs::KotoFS::init(transport.clone(), ug.clone(), lock.clone());
+    let saved = fs.saved.clone();
+    let scheduler = fs.scheduler.clone();
+    let fs = Arc::new(Mutex::new(fs));
+
+    // quantized edits are applied by the clock in the audio callback
+    let clock = schedule::Clock::new(ug.clone(), scheduler);
+    let ad = AudioDevice::open(sample_rate);
+    let mut lcd = SoundSystem::new(transport.clone(), clock, lock.clone());
+    std::thread::spawn(move || {
+        lcd.run(&ad);
+    });
+
+    if let Some(addr) = matches.value_of("http") {
+        let origins = match matches.values_of("http-origin") {
+            Some(origins) => origins.map(|o| o.to_string()).collect(),
+            None => Vec::new(),
+        };
+        if let Err(err) = http::start(fs.clone(), addr, origins) {
+            println!("cannot serve HTTP at {}", addr);
+            println!("{:?}", err);
+        }
+    }
+    if let Some(addr) = matches.value_of("9p") {
+        if let Err(err) = ninep::start(fs.clone(), addr) {
+            println!("cannot serve 9P at {}", addr);
+            println!("{:?}", err);
+        }
+    }
+    if let Some(port) = matches.value_of("osc-port") {
+        match port.parse::<u16>() {
+            Ok(port) => {
+                if let Err(err) = osc::start(fs.clone(), port) {
+                    println!("cannot listen OSC at {}", port);
+                    println!("{:?}", err);
+                }
+            }
+            Err(err) => {
+                println!("invalid OSC port");
+                println!("{:?}", err);
+            }
+        }
+    }
+
+    let canonical = matches.value_of("save") == Some("canonical");
+    let ctl_path = match matches.value_of("ctl") {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => ctl::default_path(),
+    };
+    let control = ctl::Control {
+        fs: fs.clone(),
+        env: env_clone.clone(),
+        mountpoint: mountpoint.clone(),
+        canonical: canonical,
+    };
+    if matches.is_present("repl") {
+        let control = control.clone();
+        std::thread::spawn(move || repl::run(|code| control.eval(code)));
+    }
+    let ctl_started = match ctl::start(control, &ctl_path) {
+        Ok(()) => true,
+        Err(err) => {
+            println!("cannot open the control socket at {:?}", ctl_path);
+            println!("{:?}", err);
+            false
+        }
+    };
+
+    let ug_clone = ug.clone();
+    let lock_clone = lock.clone();
+    // saving locks the unit graph, so it's done in a thread, not in the signal handler
+    let signals = match Signals::new(&[signal_hook::SIGUSR1]) {
+        Ok(signals) => Some(signals),
+        Err(err) => {
+            println!("cannot handle SIGUSR1");
+            println!("{:?}", err);
+            None
+        }
+    };
+    if let Some(signals) = signals.clone() {
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                let env = env_clone.lock().unwrap();
+                let result = save::save_file(
+                    ug_clone.clone(),
+                    &env,
+                    lock_clone.clone(),
+                    saved.clone(),
+                    canonical,
+                );
+                if let Err(err) = result {
+                    println!("cannot save the unit graph");
+                    println!("{:?}", err);
+                }
+            }
+        });
+    }
+
+    kotofs::Mount(fs).mount(OsString::from(mountpoint));
+
+    // somnia::run_test();
+
+    if let Some(signals) = signals {
+        signals.close();
+    }
+    if ctl_started {
+        let _ = std::fs::remove_file(&ctl_path);
+    }
+}
+
+fn import_midi(matches: &ArgMatches) {
+    let filename = matches.value_of("file").unwrap();
+    let mut data = Vec::new();
+    match File::open(filename) {
+        Ok(mut f) => {
+            let _ = f.read_to_end(&mut data);
+        }
+        Err(err) => {
+            println!("cannot open {}", filename);
+            println!("{:?}", err);
+            return;
+        }
+    }
+
+    let track = match matches.value_of("track").map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) => Some(n),
+        Some(Err(err)) => {
+            println!("invalid track number");
+            println!("{:?}", err);
+            return;
+        }
+        None => None,
+    };
+
+    let transport = Transport::new(44100);
+    let items = match midi::parse(&data).and_then(|smf| {
+        let notes = smf.track(track)?;
+        Ok(midi::to_pattern(notes, smf.division, &transport))
+    }) {
+        Ok(items) => items,
+        Err(err) => {
+            println!("cannot read {} as a MIDI file", filename);
+            println!("{:?}", err);
+            return;
+        }
+    };
+
+    let pat = format!("(pat {})", items.join(" "));
+    match matches.value_of("seq") {
+        Some(osc) => println!("(seq {} {} 0 (adsr 0 0.1 0 0))", pat, osc),
+        None => println!("{}", pat),
+    }
+}
+
+fn eval_config(config: String, env: &mut Env) -> Aug {
+    match tlisp::eval_all(tlisp::sexp::read(config).unwrap(), env) {
         Ok(Value::Unit(ug)) => ug,
         Ok(_v) => panic!("Oh, unit graph is not a unit!!"),
         Err(err) => panic!("Error!!! {:?}", err),
+    }
+}
+
+fn read_config(filename: &str) -> Option<String> {
+    let mut config = String::new();
+    match File::open(filename) {
+        Ok(mut f) => {
+            let _ = f.read_to_string(&mut config);
+            if filename.ends_with(".json") {
+                json_to_lisp(&config)
+            } else {
+                Some(config)
+            }
+        }
+        Err(err) => {
+            println!("cannot open {}", filename);
+            println!("{:?}", err);
+            None
+        }
+    }
+}
+
+fn export_midi(matches: &ArgMatches) {
+    let config = match read_config(matches.value_of("config").unwrap()) {
+        Some(config) => config,
+        None => return,
     };
+    let mut env = Env::init(Transport::new(44100));
+    let ug = eval_config(config, &mut env);
 
-    let lock = Arc::new(Mutex::new(true));
+    let graph = graph::Graph::build(ug);
+    let data = midi::export(&graph.patterns(), &env.transport);
+    let filename = matches.value_of("output").unwrap_or("koto.mid");
+    match File::create(filename) {
+        Ok(mut f) => {
+            let _ = f.write_all(&data);
+        }
+        Err(err) => {
+            println!("cannot create {}", filename);
+            println!("{:?}", err);
+        }
+    }
+}
 
-    let ug_clone = ug.clone();
-    let env_clone = env.clone();
-    let lock_clone = lock.clone();
-    let signal = unsafe {
-        signal_hook::register(signal_hook::SIGUSR1, move || {
-            let filename = format!(
-                "koto.{}.lisp",
-                time::strftime("%Y%m%dT%H%M%S", &time::now()).unwrap()
-            );
-            let mut f = File::create(filename).unwrap();
-            let mut config = None;
-            if let Ok(_) = lock_clone.lock() {
-                config = Some(tlisp::dump(ug_clone.clone(), &env_clone));
+fn print_graph(matches: &ArgMatches) {
+    let config = match read_config(matches.value_of("config").unwrap()) {
+        Some(config) => config,
+        None => return,
+    };
+    let mut env = Env::init(Transport::new(44100));
+    let ug = eval_config(config, &mut env);
+    print!("{}", graph::Graph::build(ug).to_dot());
+}
+
+fn json_to_lisp(config: &str) -> Option<String> {
+    match json::parse(config) {
+        Ok(patch) => match patch::to_lisp(&patch) {
+            Ok(lisp) => Some(lisp),
+            Err(err) => {
+                println!("invalid patch");
+                println!("{:?}", err);
+                None
             }
-            if let Some(config) = config {
-                let _ = f.write_all(config.as_bytes());
+        },
+        Err(err) => {
+            println!("cannot read JSON");
+            println!("{:?}", err);
+            None
+        }
+    }
+}
+
+fn print_json(matches: &ArgMatches) {
+    let config = match read_config(matches.value_of("config").unwrap()) {
+        Some(config) => config,
+        None => return,
+    };
+    let mut env = Env::init(Transport::new(44100));
+    let ug = eval_config(config, &mut env);
+    println!("{}", patch::to_json(ug, &env.transport));
+}
+
+fn export_tree(matches: &ArgMatches) {
+    let config = match read_config(matches.value_of("config").unwrap()) {
+        Some(config) => config,
+        None => return,
+    };
+    let mut env = Env::init(Transport::new(44100));
+    let ug = eval_config(config, &mut env);
+
+    let dir = Path::new(matches.value_of("dir").unwrap());
+    match tree::has_files(dir) {
+        Ok(true) if matches.is_present("force") => {
+            if let Err(err) = tree::clean(dir) {
+                println!("cannot clean {:?}", dir);
+                println!("{:?}", err);
+                return;
             }
-        })
+        }
+        Ok(true) => {
+            println!("{:?} is not empty. use --force to overwrite it", dir);
+            return;
+        }
+        Ok(false) => (),
+        Err(err) => {
+            println!("cannot read {:?}", dir);
+            println!("{:?}", err);
+            return;
+        }
     }
-    .unwrap();
 
     let transport = Arc::new(Mutex::new(env.transport));
-    let ad = AudioDevice::open(sample_rate);
-    let mut lcd = SoundSystem::new(transport.clone(), ug.clone(), lock.clone());
-    std::thread::spawn(move || {
-        lcd.run(&ad);
-    });
+    let fs = kotofs::KotoFS::init(transport, ug, Arc::new(Mutex::new(true)));
+    if let Err(err) = tree::export(fs.root.clone(), dir) {
+        println!("cannot write {:?}", dir);
+        println!("{:?}", err);
+        return;
+    }
 
-    let fs = kotofs::KotoFS::init(transport.clone(), ug.clone(), lock.clone());
-    fs.mount(OsString::from(mountpoint));
+    let typename = fs.root.lock().unwrap().name.clone();
+    if &typename[..] != "out" {
+        println!(
+            "the root module is '{}'. import it with '--root {}'",
+            typename, typename
+        );
+    }
+}
 
-    // somnia::run_test();
+fn import_tree(matches: &ArgMatches) {
+    let dir = Path::new(matches.value_of("dir").unwrap());
+    let typename = matches.value_of("root").unwrap_or("out");
+    let env = Env::init(Transport::new(44100));
+    let transport = Arc::new(Mutex::new(env.transport.clone()));
+
+    let ug = match tree::import(dir, typename, transport) {
+        Ok(Some(ug)) => ug,
+        Ok(None) => {
+            println!("cannot build '{}' from {:?}", typename, dir);
+            return;
+        }
+        Err(err) => {
+            println!("cannot read {:?}", dir);
+            println!("{:?}", err);
+            return;
+        }
+    };
 
-    signal_hook::unregister(signal);
+    let filename = matches.value_of("output").unwrap_or("koto.lisp");
+    match File::create(filename) {
+        Ok(mut f) => {
+            let _ = f.write_all(tlisp::dump(ug, &env).as_bytes());
+        }
+        Err(err) => {
+            println!("cannot create {}", filename);
+            println!("{:?}", err);
+        }
+    }
+}
+
+fn print_diff(matches: &ArgMatches) {
+    let mut graphs = Vec::new();
+    for name in ["old", "new"].iter() {
+        let config = match read_config(matches.value_of(name).unwrap()) {
+            Some(config) => config,
+            None => return,
+        };
+        let mut env = Env::init(Transport::new(44100));
+        graphs.push(graph::Graph::build(eval_config(config, &mut env)));
+    }
+    for change in diff::diff(&graphs[0], &graphs[1]) {
+        println!("{}", change);
+    }
+}
+
+fn control(matches: &ArgMatches) {
+    let path = match matches.value_of("socket") {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => ctl::default_path(),
+    };
+    let command = matches.value_of("command").unwrap();
+    let args: Vec<&str> = match matches.values_of("args") {
+        Some(args) => args.collect(),
+        None => Vec::new(),
+    };
+
+    let (line, body) = match (command, &args[..]) {
+        ("load", [filename]) => match read_config(filename) {
+            Some(config) => ("load".to_string(), Some(config)),
+            None => return,
+        },
+        ("get", [path]) => (format!("get {}", path), None),
+        ("set", [path, value @ ..]) if value.len() > 0 => {
+            (format!("set {} {}", path, value.join(" ")), None)
+        }
+        ("save", []) | ("status", []) | ("tree", []) => (command.to_string(), None),
+        _ => {
+            println!("invalid arguments for '{}'", command);
+            return;
+        }
+    };
+    ctl::request(&path, &line, body.as_ref().map(|b| &b[..]));
+}
+
+fn attach_repl(matches: &ArgMatches) {
+    let path = match matches.value_of("socket") {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => ctl::default_path(),
+    };
+    let mut client = match ctl::Client::connect(&path) {
+        Ok(client) => client,
+        Err(err) => {
+            println!("cannot connect to Koto at {:?}", path);
+            println!("{:?}", err);
+            return;
+        }
+    };
+    repl::run(|code| match client.request("eval", Some(code)) {
+        Ok(result) => result,
+        Err(err) => Err(format!("connection is lost: {:?}", err)),
+    });
 }
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+
+use tapirus::musical_time::time::Transport;
+
+use crate::expr::{lift_octaves, midi_to_note, note_to_midi};
+
+#[derive(Debug)]
+pub enum MidiError {
+    NotSmf,
+    Truncated,
+    UnsupportedDivision(u16),
+    UnexpectedStatus(u8),
+    NoSuchTrack(usize),
+    NoNotes,
+}
+
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub start: u64,
+    pub end: u64,
+    pub key: u8,
+    pub velocity: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Smf {
+    /// Ticks per quarter note.
+    pub division: u16,
+    pub tracks: Vec<Vec<Note>>,
+}
+
+/// Returns how many MIDI ticks one step of patterns is.
+///
+/// One step is a quarter of a beat, so it's a 16th note in 4/4.
+pub fn ticks_per_step(division: u16, transport: &Transport) -> f64 {
+    let beat = division as f64 * 4.0 / transport.measure.note as f64;
+    beat / 4.0
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, MidiError> {
+        let b = *self.data.get(self.pos).ok_or(MidiError::Truncated)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], MidiError> {
+        if self.pos + len > self.data.len() {
+            return Err(MidiError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, MidiError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Reads a variable-length quantity.
+    fn vlq(&mut self) -> Result<u64, MidiError> {
+        let mut n = 0u64;
+        for _ in 0..4 {
+            let b = self.byte()?;
+            n = (n << 7) | (b & 0x7f) as u64;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn parse_track(data: &[u8]) -> Result<Vec<Note>, MidiError> {
+    let mut reader = Reader { data: data, pos: 0 };
+    let mut notes = Vec::new();
+    let mut playing: HashMap<(u8, u8), (u64, u8)> = HashMap::new();
+    let mut tick = 0u64;
+    let mut running = None;
+
+    while reader.pos < data.len() {
+        tick += reader.vlq()?;
+        let mut status = reader.byte()?;
+        let mut first = None;
+        if status < 0x80 {
+            // running status
+            first = Some(status);
+            status = running.ok_or(MidiError::UnexpectedStatus(status))?;
+        }
+
+        match status {
+            0xff => {
+                let kind = reader.byte()?;
+                let len = reader.vlq()? as usize;
+                reader.bytes(len)?;
+                if kind == 0x2f {
+                    break;
+                }
+            }
+            0xf0 | 0xf7 => {
+                let len = reader.vlq()? as usize;
+                reader.bytes(len)?;
+            }
+            0x80..=0xef => {
+                running = Some(status);
+                let data1 = match first {
+                    Some(b) => b,
+                    None => reader.byte()?,
+                };
+                let kind = status & 0xf0;
+                if kind == 0xc0 || kind == 0xd0 {
+                    continue;
+                }
+                let data2 = reader.byte()?;
+                let channel = status & 0x0f;
+
+                if kind == 0x90 && data2 > 0 {
+                    playing.insert((channel, data1), (tick, data2));
+                } else if kind == 0x80 || kind == 0x90 {
+                    if let Some((start, velocity)) = playing.remove(&(channel, data1)) {
+                        notes.push(Note {
+                            start: start,
+                            end: tick,
+                            key: data1,
+                            velocity: velocity,
+                        });
+                    }
+                }
+            }
+            _ => return Err(MidiError::UnexpectedStatus(status)),
+        }
+    }
+
+    notes.sort_by_key(|n| (n.start, n.key));
+    Ok(notes)
+}
+
+/// Parses a Standard MIDI File into notes of each track.
+pub fn parse(data: &[u8]) -> Result<Smf, MidiError> {
+    let mut reader = Reader { data: data, pos: 0 };
+    if reader.bytes(4).map_err(|_| MidiError::NotSmf)? != b"MThd" {
+        return Err(MidiError::NotSmf);
+    }
+    let len = reader.u32()? as usize;
+    let header = reader.bytes(len)?;
+    if header.len() < 6 {
+        return Err(MidiError::Truncated);
+    }
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if division & 0x8000 != 0 {
+        // SMPTE time
+        return Err(MidiError::UnsupportedDivision(division));
+    }
+
+    let mut tracks = Vec::new();
+    while reader.pos + 8 <= data.len() {
+        let id = reader.bytes(4)?;
+        let len = reader.u32()? as usize;
+        let chunk = reader.bytes(len.min(data.len() - reader.pos))?;
+        if id == b"MTrk" {
+            tracks.push(parse_track(chunk)?);
+        }
+    }
+    Ok(Smf {
+        division: division,
+        tracks: tracks,
+    })
+}
+
+impl Smf {
+    /// Returns notes of the `track`th track (counted from 1), or the first track which has notes.
+    pub fn track(&self, track: Option<usize>) -> Result<&Vec<Note>, MidiError> {
+        match track {
+            Some(n) => match self.tracks.get(n.wrapping_sub(1)) {
+                Some(notes) => Ok(notes),
+                None => Err(MidiError::NoSuchTrack(n)),
+            },
+            None => self
+                .tracks
+                .iter()
+                .find(|notes| notes.len() > 0)
+                .ok_or(MidiError::NoNotes),
+        }
+    }
+}
+
+/// Converts notes into pattern items, quantized to steps and padded to the end of the bar.
+///
+/// Sequencers play one note at once, so the highest one is taken from notes of the same step.
+pub fn to_pattern(notes: &[Note], division: u16, transport: &Transport) -> Vec<String> {
+    let step = ticks_per_step(division, transport);
+    let quantize = |tick: u64| (tick as f64 / step).round() as u64;
+
+    let mut starts: Vec<(u64, u64, u8)> = Vec::new();
+    for note in notes {
+        let start = quantize(note.start);
+        let end = quantize(note.end).max(start + 1);
+        match starts.last_mut() {
+            Some(last) if last.0 == start => {
+                if note.key > last.2 {
+                    *last = (start, end, note.key);
+                }
+            }
+            _ => starts.push((start, end, note.key)),
+        }
+    }
+
+    let mut items = Vec::new();
+    let mut pos = 0;
+    for (i, (start, end, key)) in starts.iter().enumerate() {
+        if *start > pos {
+            items.push(format!("(r {})", start - pos));
+        }
+        let end = match starts.get(i + 1) {
+            Some((next, _, _)) => (*end).min(*next),
+            None => *end,
+        };
+        // notes below c0 are raised by octaves
+        let note = midi_to_note(lift_octaves(*key as i32)).unwrap_or("r".to_string());
+        items.push(format!("({} {})", note, end - start));
+        pos = end;
+    }
+
+    let bar = transport.measure.beat * 4;
+    if bar > 0 && pos % bar != 0 {
+        items.push(format!("(r {})", bar - pos % bar));
+    }
+    items.push("loop".to_string());
+    items
+}
+
+/// Ticks per quarter note of exported files.
+const EXPORT_DIVISION: u16 = 480;
+
+fn write_vlq(buf: &mut Vec<u8>, n: u64) {
+    let mut bytes = vec![(n & 0x7f) as u8];
+    let mut n = n >> 7;
+    while n > 0 {
+        bytes.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.reverse();
+    buf.extend(bytes);
+}
+
+fn write_meta(buf: &mut Vec<u8>, delta: u64, kind: u8, data: &[u8]) {
+    write_vlq(buf, delta);
+    buf.push(0xff);
+    buf.push(kind);
+    write_vlq(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn write_chunk(buf: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+    buf.extend_from_slice(id);
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Converts pattern items into notes. Velocities in [0.0, 1.0] are scaled into MIDI velocity.
+pub fn from_pattern(items: &[String], division: u16, transport: &Transport) -> Vec<Note> {
+    let step = ticks_per_step(division, transport);
+    let mut notes = Vec::new();
+    let mut pos = 0.0;
+    for item in items {
+        if !(item.starts_with('(') && item.ends_with(')')) {
+            continue;
+        }
+        let cols: Vec<&str> = item[1..item.len() - 1].split_whitespace().collect();
+        let len: f64 = match cols.get(1).and_then(|len| len.parse().ok()) {
+            Some(len) => len,
+            None => continue,
+        };
+        if let Some(key) = note_to_midi(cols[0]) {
+            let velocity = match cols.get(2).and_then(|v| v.parse::<f64>().ok()) {
+                Some(v) => (v * 127.0).round().max(1.0).min(127.0) as u8,
+                None => 100,
+            };
+            notes.push(Note {
+                start: (pos * step).round() as u64,
+                end: ((pos + len) * step).round() as u64,
+                key: key.max(0).min(127) as u8,
+                velocity: velocity,
+            });
+        }
+        pos += len;
+    }
+    notes
+}
+
+/// Writes patterns into a format 1 Standard MIDI File, one track for each pattern.
+///
+/// The first track has the tempo and the time signature of `transport`.
+pub fn export(patterns: &[(String, Vec<String>)], transport: &Transport) -> Vec<u8> {
+    let mut file = Vec::new();
+    let mut header = Vec::new();
+    header.extend_from_slice(&1u16.to_be_bytes());
+    header.extend_from_slice(&(patterns.len() as u16 + 1).to_be_bytes());
+    header.extend_from_slice(&EXPORT_DIVISION.to_be_bytes());
+    write_chunk(&mut file, b"MThd", &header);
+
+    let mut conductor = Vec::new();
+    write_meta(&mut conductor, 0, 0x03, b"koto");
+    let tempo = (60_000_000.0 / transport.bpm as f64).round() as u32;
+    write_meta(&mut conductor, 0, 0x51, &tempo.to_be_bytes()[1..]);
+    let note = transport.measure.note.max(1);
+    let denominator = 63 - (note as u64).leading_zeros() as u8;
+    let numerator = transport.measure.beat.min(255) as u8;
+    write_meta(&mut conductor, 0, 0x58, &[numerator, denominator, 24, 8]);
+    write_meta(&mut conductor, 0, 0x2f, &[]);
+    write_chunk(&mut file, b"MTrk", &conductor);
+
+    for (i, (name, items)) in patterns.iter().enumerate() {
+        let channel = (i % 16) as u8;
+        let mut events: Vec<(u64, u8, u8, u8)> = Vec::new();
+        for note in from_pattern(items, EXPORT_DIVISION, transport) {
+            events.push((note.start, 0x90 | channel, note.key, note.velocity));
+            events.push((note.end, 0x80 | channel, note.key, 0));
+        }
+        // note offs come before note ons at the same tick
+        events.sort_by_key(|(tick, status, _, _)| (*tick, *status & 0xf0 == 0x90));
+
+        let mut track = Vec::new();
+        write_meta(&mut track, 0, 0x03, name.as_bytes());
+        let mut tick = 0;
+        for (t, status, key, velocity) in events {
+            write_vlq(&mut track, t - tick);
+            track.extend_from_slice(&[status, key, velocity]);
+            tick = t;
+        }
+        write_meta(&mut track, 0, 0x2f, &[]);
+        write_chunk(&mut file, b"MTrk", &track);
+    }
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport() -> Transport {
+        let mut transport = Transport::new(44100);
+        transport.measure.beat = 4;
+        transport.measure.note = 4;
+        transport
+    }
+
+    fn smf(division: u16, track: &[u8]) -> Vec<u8> {
+        let mut file = Vec::new();
+        let mut header = vec![0, 0, 0, 1];
+        header.extend_from_slice(&division.to_be_bytes());
+        write_chunk(&mut file, b"MThd", &header);
+        write_chunk(&mut file, b"MTrk", track);
+        file
+    }
+
+    #[test]
+    fn test_vlq() {
+        for n in &[0, 0x7f, 0x80, 0x3fff, 0x4000, 0x0fff_ffff] {
+            let mut buf = Vec::new();
+            write_vlq(&mut buf, *n);
+            let mut reader = Reader { data: &buf, pos: 0 };
+            assert_eq!(reader.vlq().unwrap(), *n);
+            assert_eq!(reader.pos, buf.len());
+        }
+    }
+
+    #[test]
+    fn test_running_status() {
+        let track = [
+            0x00, 0x90, 60, 100, // note on
+            0x60, 64, 90, // note on with the running status
+            0x60, 60, 0, // note on with velocity 0 is note off
+            0x00, 0x80, 64, 0, // note off
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let smf = parse(&smf(96, &track)).unwrap();
+        assert_eq!(smf.division, 96);
+        let notes = smf.track(None).unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!((notes[0].start, notes[0].end, notes[0].key), (0, 192, 60));
+        assert_eq!(notes[0].velocity, 100);
+        assert_eq!((notes[1].start, notes[1].end, notes[1].key), (96, 192, 64));
+        assert_eq!(notes[1].velocity, 90);
+    }
+
+    #[test]
+    fn test_running_status_after_program_change() {
+        let track = [
+            0x00, 0xc0, 5, // program change has one data byte
+            0x00, 6, // running status of program change
+            0x00, 0x90, 69, 100, 0x18, 69, 0, 0x00, 0xff, 0x2f, 0x00,
+        ];
+        let notes = parse(&smf(96, &track)).unwrap().tracks[0].clone();
+        assert_eq!(notes.len(), 1);
+        assert_eq!((notes[0].start, notes[0].end, notes[0].key), (0, 24, 69));
+    }
+
+    #[test]
+    fn test_errors() {
+        match parse(b"RIFF") {
+            Err(MidiError::NotSmf) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse(&smf(0xe728, &[])) {
+            Err(MidiError::UnsupportedDivision(0xe728)) => (),
+            result => panic!("{:?}", result),
+        }
+        // a data byte without the running status
+        match parse(&smf(96, &[0x00, 60, 100])) {
+            Err(MidiError::UnexpectedStatus(60)) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse(&smf(96, &[0x00, 0x90, 60])) {
+            Err(MidiError::Truncated) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse(&smf(96, &[0x00, 0xff, 0x2f, 0x00]))
+            .unwrap()
+            .track(Some(2))
+        {
+            Err(MidiError::NoSuchTrack(2)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_to_pattern() {
+        let note = |start, end, key| Note {
+            start: start,
+            end: end,
+            key: key,
+            velocity: 100,
+        };
+        // 24 ticks per step in 4/4 at 96 ticks per quarter note
+        let notes = vec![
+            note(0, 48, 60),
+            note(0, 24, 64),
+            note(72, 96, 69),
+            note(100, 120, 71),
+        ];
+        let items = to_pattern(&notes, 96, &transport());
+        // the highest note of a chord is taken with its own length
+        let expected = vec!["(e4 1)", "(r 2)", "(a4 1)", "(b4 1)", "(r 11)", "loop"];
+        assert_eq!(items, expected);
+    }
+
+    #[test]
+    fn test_from_pattern() {
+        let items: Vec<String> = vec!["(a4 2 0.5)", "(r 1)", "(c4 1)", "loop"]
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect();
+        let notes = from_pattern(&items, 96, &transport());
+        assert_eq!(notes.len(), 2);
+        assert_eq!((notes[0].start, notes[0].end, notes[0].key), (0, 48, 69));
+        assert_eq!(notes[0].velocity, 64);
+        assert_eq!((notes[1].start, notes[1].end, notes[1].key), (72, 96, 60));
+        assert_eq!(notes[1].velocity, 100);
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let items: Vec<String> = vec!["(a4 2)", "(r 1)", "(c4 1)", "(r 12)", "loop"]
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect();
+        let transport = transport();
+        let file = export(&[("src0.seq".to_string(), items.clone())], &transport);
+
+        let smf = parse(&file).unwrap();
+        assert_eq!(smf.division, EXPORT_DIVISION);
+        assert_eq!(smf.tracks.len(), 2);
+        assert_eq!(smf.tracks[0].len(), 0);
+        assert_eq!(to_pattern(&smf.tracks[1], smf.division, &transport), items);
+    }
+
+    #[test]
+    fn test_export_conductor() {
+        let transport = transport();
+        let file = export(&[], &transport);
+        // the header chunk, then the conductor track with the track name "koto"
+        let conductor = &file[14 + 8..];
+        assert_eq!(
+            &conductor[..8],
+            &[0x00, 0xff, 0x03, 4, b'k', b'o', b't', b'o']
+        );
+        let tempo = (60_000_000.0 / transport.bpm as f64).round() as u32;
+        assert_eq!(&conductor[8..12], &[0x00, 0xff, 0x51, 3]);
+        assert_eq!(&conductor[12..15], &tempo.to_be_bytes()[1..]);
+        // 4/4 is written as 4 and 2, the power of two
+        assert_eq!(&conductor[15..21], &[0x00, 0xff, 0x58, 4, 4, 2]);
+    }
+}
@@ -0,0 +1,665 @@
+//! 9P2000 server, which shows the same nodes as the mount without FUSE.
+//!
+//! Symbolic links are followed, because 9P2000 doesn't have them.
+//! Written files are applied when their fids are clunked, like closing files in the mount.
+
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex};
+
+use fuse::FileType;
+
+use crate::kotofs::{KotoFS, MAX_FILE_SIZE};
+use crate::kotonode::KotoNode;
+
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const RERROR: u8 = 107;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TOPEN: u8 = 112;
+const TCREATE: u8 = 114;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+const TSTAT: u8 = 124;
+const TWSTAT: u8 = 126;
+
+const QTDIR: u8 = 0x80;
+const DMDIR: u32 = 0x8000_0000;
+const OTRUNC: u8 = 0x10;
+
+const MAX_MSIZE: u32 = 65536;
+/// The size of headers of `Rread` and `Rwrite`, which `msize` must be larger than.
+const IOHDRSZ: u32 = 24;
+
+/// A message body to read.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.data.len() {
+            return Err("message is too short".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).to_string())
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    put_u16(buf, s.len() as u16);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Follows symbolic links.
+fn resolve(node: Arc<Mutex<KotoNode>>) -> Option<Arc<Mutex<KotoNode>>> {
+    let kind = node.lock().unwrap().attr.kind;
+    match kind {
+        FileType::Symlink => KotoNode::resolve_symlink(node),
+        _ => Some(node),
+    }
+}
+
+fn qid(node: Arc<Mutex<KotoNode>>) -> Vec<u8> {
+    let node = node.lock().unwrap();
+    let mut qid = Vec::new();
+    match node.attr.kind {
+        FileType::Directory => qid.push(QTDIR),
+        _ => qid.push(0),
+    }
+    put_u32(&mut qid, 0);
+    put_u64(&mut qid, node.attr.ino);
+    qid
+}
+
+fn stat(name: &str, node: Arc<Mutex<KotoNode>>) -> Vec<u8> {
+    let qid = qid(node.clone());
+    let node = node.lock().unwrap();
+    let (mode, length) = match node.attr.kind {
+        FileType::Directory => (DMDIR | node.attr.perm as u32, 0),
+        _ => (node.attr.perm as u32, node.data.len() as u64),
+    };
+
+    let mut stat = Vec::new();
+    put_u16(&mut stat, 0);
+    put_u32(&mut stat, 0);
+    stat.extend_from_slice(&qid);
+    put_u32(&mut stat, mode);
+    put_u32(&mut stat, node.attr.atime.sec as u32);
+    put_u32(&mut stat, node.attr.mtime.sec as u32);
+    put_u64(&mut stat, length);
+    put_string(&mut stat, name);
+    for _ in 0..3 {
+        // uid, gid and muid
+        put_string(&mut stat, "koto");
+    }
+
+    let mut buf = Vec::new();
+    put_u16(&mut buf, stat.len() as u16);
+    buf.extend(stat);
+    buf
+}
+
+/// Returns the name of `node` in its parent.
+fn nodename(node: Arc<Mutex<KotoNode>>) -> String {
+    let parent = node.lock().unwrap().parent.clone();
+    match parent {
+        Some(parent) => KotoNode::get_children(parent)
+            .into_iter()
+            .find(|(_, child)| Arc::ptr_eq(child, &node))
+            .map(|(name, _)| name)
+            .unwrap_or_default(),
+        None => "/".to_string(),
+    }
+}
+
+struct Fid {
+    /// The node itself, which may be a symbolic link, for remove and rename.
+    node: Arc<Mutex<KotoNode>>,
+    opened: bool,
+    dirty: bool,
+}
+
+impl Fid {
+    /// Returns the node to read, write or stat, following symbolic links.
+    fn target(&self) -> Result<Arc<Mutex<KotoNode>>, String> {
+        resolve(self.node.clone()).ok_or("broken link".to_string())
+    }
+}
+
+/// A client connection, with its own fids.
+struct Session {
+    fs: Arc<Mutex<KotoFS>>,
+    fids: HashMap<u32, Fid>,
+    msize: u32,
+}
+
+impl Session {
+    fn fid(&self, fid: u32) -> Result<&Fid, String> {
+        self.fids.get(&fid).ok_or("unknown fid".to_string())
+    }
+
+    fn opened(&self, fid: u32) -> Result<&Fid, String> {
+        match self.fid(fid)? {
+            fid if fid.opened => Ok(fid),
+            _ => Err("fid not open".to_string()),
+        }
+    }
+
+    fn clunk(&mut self, fid: u32) {
+        if let Some(fid) = self.fids.remove(&fid) {
+            if fid.dirty {
+                if let Ok(node) = fid.target() {
+                    self.fs.lock().unwrap().apply(node);
+                }
+            }
+        }
+    }
+
+    /// Clunks all fids, so written files are applied.
+    fn clunk_all(&mut self) {
+        let fids: Vec<u32> = self.fids.keys().cloned().collect();
+        for fid in fids {
+            self.clunk(fid);
+        }
+    }
+
+    fn walk(&mut self, r: &mut Reader, out: &mut Vec<u8>) -> Result<(), String> {
+        let fid = r.u32()?;
+        let newfid = r.u32()?;
+        let nwname = r.u16()?;
+        let mut node = self.fid(fid)?.node.clone();
+
+        let mut qids = Vec::new();
+        for i in 0..nwname {
+            let name = r.string()?;
+            let next = match &name[..] {
+                ".." => node.lock().unwrap().parent.clone().or(Some(node.clone())),
+                name => resolve(node.clone()).and_then(|dir| KotoNode::get_child(dir, name)),
+            };
+            // links are kept in fids, and shown as their targets
+            match next.clone().and_then(resolve) {
+                Some(target) => {
+                    qids.push(qid(target));
+                    node = next.unwrap();
+                }
+                None if i == 0 => return Err("file not found".to_string()),
+                None => break,
+            }
+        }
+
+        if qids.len() == nwname as usize {
+            self.fids.insert(
+                newfid,
+                Fid {
+                    node: node,
+                    opened: false,
+                    dirty: false,
+                },
+            );
+        }
+        put_u16(out, qids.len() as u16);
+        for qid in qids {
+            out.extend(qid);
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, r: &mut Reader, out: &mut Vec<u8>) -> Result<(), String> {
+        let fid = r.u32()?;
+        let mode = r.u8()?;
+        let node = self.fid(fid)?.target()?;
+        self.fs.lock().unwrap().refresh(node.clone());
+
+        let mut dirty = false;
+        let is_file = match node.lock().unwrap().attr.kind {
+            FileType::Directory => false,
+            _ => true,
+        };
+        if is_file && mode & OTRUNC != 0 {
+            if KotoFS::is_generated(node.clone()) {
+                return Err("permission denied".to_string());
+            }
+            let mut node = node.lock().unwrap();
+            node.data.clear();
+            node.attr.size = 0;
+            dirty = true;
+        }
+
+        if let Some(fid) = self.fids.get_mut(&fid) {
+            fid.opened = true;
+            fid.dirty = dirty;
+        }
+        out.extend(qid(node));
+        put_u32(out, 0);
+        Ok(())
+    }
+
+    fn create(&mut self, r: &mut Reader, out: &mut Vec<u8>) -> Result<(), String> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let perm = r.u32()?;
+        let _mode = r.u8()?;
+        let parent = self.fid(fid)?.target()?;
+        let ino = parent.lock().unwrap().attr.ino;
+
+        let node = {
+            let mut fs = self.fs.lock().unwrap();
+            if perm & DMDIR != 0 {
+                fs.make_dir(ino, name)
+            } else {
+                fs.make_file(ino, name)
+            }
+        };
+        let node = node.ok_or("cannot create file".to_string())?;
+
+        // the fid becomes the new file
+        self.fids.insert(
+            fid,
+            Fid {
+                node: node.clone(),
+                opened: true,
+                dirty: false,
+            },
+        );
+        out.extend(qid(node));
+        put_u32(out, 0);
+        Ok(())
+    }
+
+    fn read(&mut self, r: &mut Reader, out: &mut Vec<u8>) -> Result<(), String> {
+        let fid = r.u32()?;
+        let offset = r.u64()?.min(usize::MAX as u64) as usize;
+        let count = (r.u32()?).min(self.msize.saturating_sub(IOHDRSZ)) as usize;
+        let node = self.opened(fid)?.target()?;
+
+        let kind = node.lock().unwrap().attr.kind;
+        let data = match kind {
+            FileType::Directory => {
+                // only whole entries are returned
+                let mut data = Vec::new();
+                let mut pos = 0;
+                for (name, child) in KotoNode::get_children(node) {
+                    let child = match resolve(child) {
+                        Some(child) => child,
+                        None => continue,
+                    };
+                    let entry = stat(&name, child);
+                    if pos >= offset {
+                        if data.len() + entry.len() > count {
+                            break;
+                        }
+                        data.extend(entry);
+                    } else {
+                        pos += entry.len();
+                    }
+                }
+                data
+            }
+            _ => {
+                let node = node.lock().unwrap();
+                let start = offset.min(node.data.len());
+                let end = offset.saturating_add(count).min(node.data.len());
+                node.data[start..end].to_vec()
+            }
+        };
+
+        put_u32(out, data.len() as u32);
+        out.extend(data);
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut Reader, out: &mut Vec<u8>) -> Result<(), String> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()? as usize;
+        let data = r.bytes(count)?;
+        let node = self.opened(fid)?.target()?;
+        if KotoFS::is_generated(node.clone()) {
+            return Err("permission denied".to_string());
+        }
+        let end = match (offset as usize).checked_add(data.len()) {
+            Some(end) if offset <= MAX_FILE_SIZE as u64 && end <= MAX_FILE_SIZE => end,
+            _ => return Err("file too large".to_string()),
+        };
+        let offset = offset as usize;
+
+        {
+            let mut node = node.lock().unwrap();
+            if node.data.len() < end {
+                node.data.resize(end, 0);
+            }
+            node.data[offset..end].copy_from_slice(data);
+            node.attr.size = node.data.len() as u64;
+        }
+        if let Some(fid) = self.fids.get_mut(&fid) {
+            fid.dirty = true;
+        }
+        put_u32(out, count as u32);
+        Ok(())
+    }
+
+    fn remove(&mut self, r: &mut Reader) -> Result<(), String> {
+        let fid = r.u32()?;
+        let node = self.fid(fid)?.node.clone();
+        self.fids.remove(&fid);
+
+        let parent = node.lock().unwrap().parent.clone();
+        let parent = parent.ok_or("cannot remove the root".to_string())?;
+        let ino = parent.lock().unwrap().attr.ino;
+        let name = nodename(node.clone());
+        let kind = node.lock().unwrap().attr.kind;
+
+        let mut fs = self.fs.lock().unwrap();
+        let result = match kind {
+            FileType::Directory => fs.remove_dir(ino, name),
+            _ => fs.remove_file(ino, name),
+        };
+        result.map_err(|_| "permission denied".to_string())
+    }
+
+    fn wstat(&mut self, r: &mut Reader) -> Result<(), String> {
+        let fid = r.u32()?;
+        let _len = r.u16()?;
+        let _size = r.u16()?;
+        let _type = r.u16()?;
+        let _dev = r.u32()?;
+        r.bytes(13)?;
+        let _mode = r.u32()?;
+        let _atime = r.u32()?;
+        let _mtime = r.u32()?;
+        let length = r.u64()?;
+        let name = r.string()?;
+        let node = self.fid(fid)?.node.clone();
+
+        // `~0` and empty strings mean "don't touch"
+        if length != !0 {
+            let target = self.fid(fid)?.target()?;
+            if KotoFS::is_generated(target.clone()) {
+                return Err("permission denied".to_string());
+            }
+            if length > MAX_FILE_SIZE as u64 {
+                return Err("file too large".to_string());
+            }
+            {
+                let mut node = target.lock().unwrap();
+                node.data.resize(length as usize, 0);
+                node.attr.size = length;
+            }
+            if let Some(fid) = self.fids.get_mut(&fid) {
+                fid.dirty = true;
+            }
+        }
+
+        let oldname = nodename(node.clone());
+        if name.len() > 0 && name != oldname {
+            let parent = node.lock().unwrap().parent.clone();
+            if let Some(parent) = parent {
+                let ino = parent.lock().unwrap().attr.ino;
+                self.fs.lock().unwrap().rename_node(ino, oldname, ino, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a message and returns the reply.
+    fn handle(&mut self, msg: &[u8]) -> Vec<u8> {
+        let mut r = Reader { data: msg, pos: 0 };
+        let kind = r.u8().unwrap_or(0);
+        let tag = r.u16().unwrap_or(!0);
+        let mut out = Vec::new();
+
+        let result = match kind {
+            TVERSION => r.u32().and_then(|msize| {
+                let version = r.string()?;
+                self.msize = msize.min(MAX_MSIZE).max(IOHDRSZ);
+                // a new version resets the session
+                self.clunk_all();
+                put_u32(&mut out, self.msize);
+                if version.starts_with("9P2000") {
+                    put_string(&mut out, "9P2000");
+                } else {
+                    put_string(&mut out, "unknown");
+                }
+                Ok(())
+            }),
+            TAUTH => Err("authentication not required".to_string()),
+            TATTACH => r.u32().map(|fid| {
+                let root = self.fs.lock().unwrap().root.clone();
+                out.extend(qid(root.clone()));
+                self.fids.insert(
+                    fid,
+                    Fid {
+                        node: root,
+                        opened: false,
+                        dirty: false,
+                    },
+                );
+            }),
+            TFLUSH => Ok(()),
+            TWALK => self.walk(&mut r, &mut out),
+            TOPEN => self.open(&mut r, &mut out),
+            TCREATE => self.create(&mut r, &mut out),
+            TREAD => self.read(&mut r, &mut out),
+            TWRITE => self.write(&mut r, &mut out),
+            TCLUNK => r.u32().map(|fid| self.clunk(fid)),
+            TREMOVE => self.remove(&mut r),
+            TSTAT => r.u32().and_then(|fid| {
+                let fid = self.fid(fid)?;
+                let node = fid.target()?;
+                let stat = stat(&nodename(fid.node.clone()), node);
+                put_u16(&mut out, stat.len() as u16);
+                out.extend(stat);
+                Ok(())
+            }),
+            TWSTAT => self.wstat(&mut r),
+            _ => Err("unknown message".to_string()),
+        };
+
+        let (kind, body) = match result {
+            Ok(()) => (kind + 1, out),
+            Err(ename) => {
+                let mut body = Vec::new();
+                put_string(&mut body, &ename);
+                (RERROR, body)
+            }
+        };
+        let mut reply = Vec::new();
+        put_u32(&mut reply, body.len() as u32 + 7);
+        reply.push(kind);
+        put_u16(&mut reply, tag);
+        reply.extend(body);
+        reply
+    }
+
+    fn serve<S: Read + Write>(&mut self, mut stream: S) {
+        loop {
+            let mut size = [0; 4];
+            if stream.read_exact(&mut size).is_err() {
+                break;
+            }
+            let size = u32::from_le_bytes(size) as usize;
+            if size < 7 || size > MAX_MSIZE as usize {
+                break;
+            }
+            let mut msg = vec![0; size - 4];
+            if stream.read_exact(&mut msg).is_err() {
+                break;
+            }
+            let reply = self.handle(&msg);
+            if stream.write_all(&reply).is_err() {
+                break;
+            }
+        }
+
+        self.clunk_all();
+    }
+}
+
+fn serve<S: Read + Write + Send + 'static>(fs: Arc<Mutex<KotoFS>>, stream: S) {
+    std::thread::spawn(move || {
+        let mut session = Session {
+            fs: fs,
+            fids: HashMap::new(),
+            msize: MAX_MSIZE,
+        };
+        session.serve(stream);
+    });
+}
+
+/// Starts the 9P server in another thread. `addr` is a TCP address like `127.0.0.1:5640`,
+/// or a path of Unix domain socket if it contains `/`.
+pub fn start(fs: Arc<Mutex<KotoFS>>, addr: &str) -> std::io::Result<()> {
+    if addr.contains('/') {
+        let listener = UnixListener::bind(addr)?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                serve(fs.clone(), stream);
+            }
+        });
+    } else {
+        let listener = TcpListener::bind(addr)?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                serve(fs.clone(), stream);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(ino: u64, data: &[u8], ftype: FileType) -> Arc<Mutex<KotoNode>> {
+        let node = KotoNode::create_node(ino, "freq.val".to_string(), data.to_vec(), ftype);
+        Arc::new(Mutex::new(node))
+    }
+
+    #[test]
+    fn test_reader() {
+        let mut buf = vec![7];
+        put_u16(&mut buf, 0x1234);
+        put_u32(&mut buf, 0xdead_beef);
+        put_u64(&mut buf, 1 << 40);
+        put_string(&mut buf, "9P2000");
+        assert_eq!(&buf[1..3], &[0x34, 0x12]);
+
+        let mut r = Reader { data: &buf, pos: 0 };
+        assert_eq!(r.u8().unwrap(), 7);
+        assert_eq!(r.u16().unwrap(), 0x1234);
+        assert_eq!(r.u32().unwrap(), 0xdead_beef);
+        assert_eq!(r.u64().unwrap(), 1 << 40);
+        assert_eq!(r.string().unwrap(), "9P2000");
+        assert_eq!(r.pos, buf.len());
+        assert!(r.u8().is_err());
+    }
+
+    #[test]
+    fn test_short_string() {
+        let mut buf = Vec::new();
+        put_u16(&mut buf, 10);
+        buf.extend_from_slice(b"abc");
+        let mut r = Reader { data: &buf, pos: 0 };
+        assert!(r.string().is_err());
+    }
+
+    #[test]
+    fn test_qid() {
+        let qid = qid(node(42, b"", FileType::Directory));
+        assert_eq!(qid.len(), 13);
+        assert_eq!(qid[0], QTDIR);
+        let mut r = Reader {
+            data: &qid[1..],
+            pos: 0,
+        };
+        assert_eq!(r.u32().unwrap(), 0);
+        assert_eq!(r.u64().unwrap(), 42);
+
+        assert_eq!(super::qid(node(42, b"", FileType::RegularFile))[0], 0);
+    }
+
+    #[test]
+    fn test_stat_layout() {
+        let file = node(5, b"440\n", FileType::RegularFile);
+        let perm = file.lock().unwrap().attr.perm as u32;
+        let buf = stat("freq.val", file);
+
+        let mut r = Reader { data: &buf, pos: 0 };
+        // the size doesn't count itself
+        assert_eq!(r.u16().unwrap() as usize, buf.len() - 2);
+        assert_eq!(r.u16().unwrap(), 0); // type
+        assert_eq!(r.u32().unwrap(), 0); // dev
+        assert_eq!(r.bytes(13).unwrap()[0], 0); // qid
+        assert_eq!(r.u32().unwrap(), perm); // mode
+        r.u32().unwrap(); // atime
+        r.u32().unwrap(); // mtime
+        assert_eq!(r.u64().unwrap(), 4); // length
+        assert_eq!(r.string().unwrap(), "freq.val");
+        for _ in 0..3 {
+            assert_eq!(r.string().unwrap(), "koto");
+        }
+        assert_eq!(r.pos, buf.len());
+    }
+
+    #[test]
+    fn test_stat_of_directory() {
+        let buf = stat("src0.seq", node(6, b"", FileType::Directory));
+        let mut r = Reader {
+            data: &buf,
+            pos: 2 + 2 + 4 + 13,
+        };
+        assert!(r.u32().unwrap() & DMDIR != 0);
+        r.u32().unwrap();
+        r.u32().unwrap();
+        // directories have no length
+        assert_eq!(r.u64().unwrap(), 0);
+        assert_eq!(r.string().unwrap(), "src0.seq");
+    }
+}
@@ -0,0 +1,373 @@
+//! Open Sound Control server, which writes arguments into files at the address.
+//!
+//! A message `/src0.seq/osc.saw/freq.val 440.0` is the same as
+//! `echo 440 > src0.seq/osc.saw/freq.val`. A message without arguments touches the file.
+
+use std::net::UdpSocket;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use fuse::FileType;
+
+use crate::kotofs::KotoFS;
+
+/// Seconds from 1900-01-01, the epoch of OSC time tags, to 1970-01-01.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Debug)]
+pub enum OscError {
+    Truncated,
+    NotString,
+    UnsupportedType(char),
+}
+
+#[derive(Debug, Clone)]
+pub enum Packet {
+    Message(String, Vec<String>),
+    /// Packets with the time tag.
+    Bundle(u64, Vec<Packet>),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], OscError> {
+        if self.pos + len > self.data.len() {
+            return Err(OscError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, OscError> {
+        let b = self.bytes(4)?;
+        Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, OscError> {
+        let b = self.bytes(8)?;
+        let mut buf = [0; 8];
+        buf.copy_from_slice(b);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    /// Reads a null-terminated string padded to 4 bytes.
+    fn string(&mut self) -> Result<String, OscError> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or(OscError::NotString)?;
+        let s = String::from_utf8_lossy(&rest[..len]).to_string();
+        self.bytes((len + 4) / 4 * 4)?;
+        Ok(s)
+    }
+
+    fn blob(&mut self) -> Result<Vec<u8>, OscError> {
+        let len = self.i32()?.max(0) as usize;
+        let blob = self.bytes(len)?.to_vec();
+        self.bytes((4 - len % 4) % 4)?;
+        Ok(blob)
+    }
+}
+
+fn parse_message(data: &[u8]) -> Result<Packet, OscError> {
+    let mut reader = Reader { data: data, pos: 0 };
+    let address = reader.string()?;
+    if reader.pos >= data.len() {
+        // old senders may omit type tags
+        return Ok(Packet::Message(address, Vec::new()));
+    }
+
+    let tags = reader.string()?;
+    let mut args = Vec::new();
+    for tag in tags.chars().skip_while(|c| *c == ',') {
+        let arg = match tag {
+            'i' => reader.i32()?.to_string(),
+            'f' => f32::from_bits(reader.i32()? as u32).to_string(),
+            'd' => f64::from_bits(reader.u64()?).to_string(),
+            'h' => (reader.u64()? as i64).to_string(),
+            's' | 'S' => reader.string()?,
+            'b' => String::from_utf8_lossy(&reader.blob()?).to_string(),
+            'T' => "1".to_string(),
+            'F' | 'N' => "0".to_string(),
+            c => return Err(OscError::UnsupportedType(c)),
+        };
+        args.push(arg);
+    }
+    Ok(Packet::Message(address, args))
+}
+
+pub fn parse(data: &[u8]) -> Result<Packet, OscError> {
+    if !data.starts_with(b"#bundle\0") {
+        return parse_message(data);
+    }
+
+    let mut reader = Reader { data: data, pos: 8 };
+    let timetag = reader.u64()?;
+    let mut packets = Vec::new();
+    while reader.pos < data.len() {
+        let len = reader.i32()?.max(0) as usize;
+        packets.push(parse(reader.bytes(len)?)?);
+    }
+    Ok(Packet::Bundle(timetag, packets))
+}
+
+/// Returns how long to wait until the time tag. The time tag 1 means immediately.
+fn delay(timetag: u64) -> Duration {
+    if timetag <= 1 {
+        return Duration::from_secs(0);
+    }
+    let secs = (timetag >> 32).saturating_sub(NTP_UNIX_OFFSET);
+    let nanos = ((timetag & 0xffff_ffff) * 1_000_000_000) >> 32;
+    let at = UNIX_EPOCH + Duration::new(secs, nanos as u32);
+    at.duration_since(SystemTime::now())
+        .unwrap_or(Duration::from_secs(0))
+}
+
+fn send(fs: &mut KotoFS, address: &str, args: &[String]) {
+    let node = match fs.lookup_path(address) {
+        Some(node) => node,
+        None => {
+            println!("OSC address {} is not found", address);
+            return;
+        }
+    };
+    let kind = node.lock().unwrap().attr.kind;
+    match kind {
+        FileType::RegularFile if args.len() > 0 => {
+            let data = format!("{}\n", args.join(" ")).into_bytes();
+            if let Err(err) = fs.replace(node, data) {
+                println!("cannot write {}", address);
+                println!("{:?}", err);
+            }
+        }
+        FileType::RegularFile | FileType::Directory => fs.apply(node),
+        _ => println!("cannot write {}", address),
+    }
+}
+
+/// Bundles in the future, in the order of their time tags.
+struct Queue {
+    bundles: Mutex<Vec<(Instant, Vec<Packet>)>>,
+    cond: Condvar,
+}
+
+impl Queue {
+    fn push(&self, at: Instant, packets: Vec<Packet>) {
+        let mut bundles = self.bundles.lock().unwrap();
+        // bundles with the same time tag are applied in the order they are received
+        let pos = bundles
+            .iter()
+            .position(|(t, _)| *t > at)
+            .unwrap_or(bundles.len());
+        bundles.insert(pos, (at, packets));
+        self.cond.notify_one();
+    }
+}
+
+/// Applies packets with the lock of `fs`, so a bundle is applied at once.
+/// Bundles in the future are put into `queue`.
+fn apply(fs: &mut KotoFS, queue: &Queue, packets: Vec<Packet>) {
+    for packet in packets {
+        match packet {
+            Packet::Message(address, args) => send(fs, &address, &args),
+            Packet::Bundle(timetag, packets) => {
+                let wait = delay(timetag);
+                if wait == Duration::from_secs(0) {
+                    apply(fs, queue, packets);
+                } else {
+                    queue.push(Instant::now() + wait, packets);
+                }
+            }
+        }
+    }
+}
+
+/// Applies bundles in `queue` at their time tags.
+fn run_timer(fs: Arc<Mutex<KotoFS>>, queue: Arc<Queue>) {
+    let mut bundles = queue.bundles.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        match bundles.first().map(|(at, _)| *at) {
+            None => bundles = queue.cond.wait(bundles).unwrap(),
+            Some(at) if at > now => bundles = queue.cond.wait_timeout(bundles, at - now).unwrap().0,
+            Some(_) => {
+                let (_, packets) = bundles.remove(0);
+                // `apply()` may push nested bundles into the queue
+                drop(bundles);
+                apply(&mut fs.lock().unwrap(), &queue, packets);
+                bundles = queue.bundles.lock().unwrap();
+            }
+        }
+    }
+}
+
+/// Starts listening OSC packets at UDP `port` in another thread.
+pub fn start(fs: Arc<Mutex<KotoFS>>, port: u16) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    let queue = Arc::new(Queue {
+        bundles: Mutex::new(Vec::new()),
+        cond: Condvar::new(),
+    });
+    let timer_fs = fs.clone();
+    let timer_queue = queue.clone();
+    std::thread::spawn(move || run_timer(timer_fs, timer_queue));
+
+    std::thread::spawn(move || {
+        let mut buf = [0; 65536];
+        loop {
+            let len = match socket.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(err) => {
+                    println!("cannot receive an OSC packet");
+                    println!("{:?}", err);
+                    continue;
+                }
+            };
+            match parse(&buf[..len]) {
+                Ok(packet) => apply(&mut fs.lock().unwrap(), &queue, vec![packet]),
+                Err(err) => {
+                    println!("invalid OSC packet");
+                    println!("{:?}", err);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a string with the null and padding to 4 bytes.
+    fn string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(s.as_bytes());
+        buf.push(0);
+        while buf.len() % 4 != 0 {
+            buf.push(0);
+        }
+    }
+
+    fn message(address: &str, tags: &str, args: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        string(&mut buf, address);
+        string(&mut buf, tags);
+        buf.extend_from_slice(args);
+        buf
+    }
+
+    fn args(packet: Packet) -> (String, Vec<String>) {
+        match packet {
+            Packet::Message(address, args) => (address, args),
+            packet => panic!("{:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_padding() {
+        // "/abc" takes 8 bytes with the null, and ",f" takes 4 bytes
+        let data = message("/abc", ",f", &440.0f32.to_be_bytes());
+        assert_eq!(data.len(), 16);
+        assert_eq!(
+            args(parse(&data).unwrap()),
+            ("/abc".to_string(), vec!["440".to_string()])
+        );
+
+        // "/abcdefg" fills 8 bytes, so the null takes 4 more bytes
+        let data = message("/abcdefg", ",i", &7i32.to_be_bytes());
+        assert_eq!(data.len(), 20);
+        assert_eq!(args(parse(&data).unwrap()).1, vec!["7"]);
+    }
+
+    #[test]
+    fn test_types() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(-3i32).to_be_bytes());
+        data.extend_from_slice(&0.5f64.to_be_bytes());
+        data.extend_from_slice(&(-1i64).to_be_bytes());
+        string(&mut data, "a4");
+        data.extend_from_slice(&3i32.to_be_bytes());
+        data.extend_from_slice(b"c4 \0");
+        let data = message("/src0.seq/pattern.pat", ",idhsbTF", &data);
+        let (_, args) = args(parse(&data).unwrap());
+        assert_eq!(args, vec!["-3", "0.5", "-1", "a4", "c4 ", "1", "0"]);
+    }
+
+    #[test]
+    fn test_without_type_tags() {
+        let mut data = Vec::new();
+        string(&mut data, "/src0.seq");
+        assert_eq!(
+            args(parse(&data).unwrap()),
+            ("/src0.seq".to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_bundles() {
+        let inner = message("/a.val", ",i", &1i32.to_be_bytes());
+        let mut nested = b"#bundle\0".to_vec();
+        nested.extend_from_slice(&2u64.to_be_bytes());
+        nested.extend_from_slice(&(inner.len() as i32).to_be_bytes());
+        nested.extend_from_slice(&inner);
+
+        let mut data = b"#bundle\0".to_vec();
+        data.extend_from_slice(&1u64.to_be_bytes());
+        for packet in &[&inner, &nested] {
+            data.extend_from_slice(&(packet.len() as i32).to_be_bytes());
+            data.extend_from_slice(packet);
+        }
+
+        match parse(&data).unwrap() {
+            Packet::Bundle(1, packets) => {
+                assert_eq!(packets.len(), 2);
+                assert_eq!(args(packets[0].clone()).1, vec!["1"]);
+                match &packets[1] {
+                    Packet::Bundle(2, packets) => assert_eq!(packets.len(), 1),
+                    packet => panic!("{:?}", packet),
+                }
+            }
+            packet => panic!("{:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        match parse(&message("/a", ",f", &[0, 0])) {
+            Err(OscError::Truncated) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse(b"/abc") {
+            Err(OscError::NotString) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse(&message("/a", ",m", &[0, 0, 0, 0])) {
+            Err(OscError::UnsupportedType('m')) => (),
+            result => panic!("{:?}", result),
+        }
+        let mut data = b"#bundle\0".to_vec();
+        data.extend_from_slice(&1u64.to_be_bytes());
+        data.extend_from_slice(&100i32.to_be_bytes());
+        match parse(&data) {
+            Err(OscError::Truncated) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(delay(1), Duration::from_secs(0));
+        // a time tag in the past is applied immediately
+        assert_eq!(delay(NTP_UNIX_OFFSET << 32), Duration::from_secs(0));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let timetag = (now.as_secs() + NTP_UNIX_OFFSET + 10) << 32;
+        let wait = delay(timetag);
+        assert!(wait > Duration::from_secs(8) && wait <= Duration::from_secs(10));
+    }
+}
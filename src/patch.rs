@@ -0,0 +1,443 @@
+//! Patches in JSON, for tools which can't read TapirLisp.
+//!
+//! A patch looks like this:
+//!
+//! ```json
+//! {
+//!   "koto": 1,
+//!   "transport": {"bpm": 120, "measure": {"beat": 4, "note": 4}},
+//!   "shared": [
+//!     {"type": "saw", "params": [{"name": "init_ph", "value": 0}, {"name": "freq", "value": 0}]}
+//!   ],
+//!   "root": {
+//!     "type": "out",
+//!     "params": [{"name": "vol", "value": 0.25}],
+//!     "rest": {"name": "src", "values": [{"shared": 0}]}
+//!   }
+//! }
+//! ```
+//!
+//! Values are numbers, `{"table": [...]}`, `{"pattern": [...]}`, `{"shared": N}`
+//! (the `N`th of `shared`) or units, which have `type`, `params` and optional `rest`.
+//! Params are matched by their names, so their order doesn't matter.
+
+use tapirus::musical_time::time::Transport;
+use tapirus::tapirlisp::eval::{eval, TYPE_NAMES};
+use tapirus::tapirlisp::sexp::read;
+use tapirus::tapirlisp::types::{Env, Value as LispValue};
+use tapirus::ugens::core::{Aug, Dump, UgNode, Value};
+use tapirus::ugens::util::collect_shared_ugs;
+
+use crate::json::Json;
+use crate::kotonode::KotoNode;
+
+/// The version of the patch format.
+pub const VERSION: f64 = 1.0;
+
+#[derive(Debug)]
+pub enum PatchError {
+    NotPatch,
+    UnsupportedVersion(f64),
+    InvalidValue(Json),
+    UnknownType(String),
+    NoSuchParam(String, String),
+    MissingParam(String, String),
+    InvalidPattern(String),
+    NoSuchShared(usize),
+    CircularShared(usize),
+}
+
+fn value_to_json(value: Value, shared: &Vec<Aug>) -> Json {
+    match value {
+        Value::Number(n) => Json::Number(n),
+        Value::Table(vec) => Json::Object(vec![(
+            "table".to_string(),
+            Json::Array(vec.into_iter().map(Json::Number).collect()),
+        )]),
+        Value::Pattern(vec) => Json::Object(vec![(
+            "pattern".to_string(),
+            Json::Array(vec.into_iter().map(Json::String).collect()),
+        )]),
+        Value::Ug(aug) => node_to_json(aug.dump(shared), shared),
+        Value::Shared(_, aug) => match shared.iter().position(|s| *s == aug) {
+            Some(idx) => Json::Object(vec![("shared".to_string(), Json::Number(idx as f64))]),
+            None => node_to_json(aug.dump(shared), shared),
+        },
+    }
+}
+
+fn slots_to_json(slots: Vec<(String, Value)>, shared: &Vec<Aug>) -> Json {
+    Json::Array(
+        slots
+            .into_iter()
+            .map(|(name, value)| {
+                Json::Object(vec![
+                    ("name".to_string(), Json::String(name)),
+                    ("value".to_string(), value_to_json(value, shared)),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn node_to_json(node: UgNode, shared: &Vec<Aug>) -> Json {
+    match node {
+        UgNode::Val(v) => value_to_json(v, shared),
+        UgNode::Ug(typename, slots) => Json::Object(vec![
+            ("type".to_string(), Json::String(typename)),
+            (
+                "params".to_string(),
+                slots_to_json(
+                    slots.into_iter().map(|s| (s.name, s.value)).collect(),
+                    shared,
+                ),
+            ),
+        ]),
+        UgNode::UgRest(typename, slots, basename, values) => Json::Object(vec![
+            ("type".to_string(), Json::String(typename)),
+            (
+                "params".to_string(),
+                slots_to_json(
+                    slots.into_iter().map(|s| (s.name, s.value)).collect(),
+                    shared,
+                ),
+            ),
+            (
+                "rest".to_string(),
+                Json::Object(vec![
+                    ("name".to_string(), Json::String(basename)),
+                    (
+                        "values".to_string(),
+                        Json::Array(
+                            values
+                                .into_iter()
+                                .map(|v| value_to_json(*v, shared))
+                                .collect(),
+                        ),
+                    ),
+                ]),
+            ),
+        ]),
+    }
+}
+
+/// Dumps the unit graph `ug` and the transport into a patch.
+pub fn to_json(ug: Aug, transport: &Transport) -> Json {
+    let shared = collect_shared_ugs(ug.clone());
+    let shared_json = shared
+        .iter()
+        .map(|s| node_to_json(s.dump(&shared), &shared))
+        .collect();
+
+    Json::Object(vec![
+        ("koto".to_string(), Json::Number(VERSION)),
+        (
+            "transport".to_string(),
+            Json::Object(vec![
+                ("bpm".to_string(), Json::Number(transport.bpm as f64)),
+                (
+                    "measure".to_string(),
+                    Json::Object(vec![
+                        (
+                            "beat".to_string(),
+                            Json::Number(transport.measure.beat as f64),
+                        ),
+                        (
+                            "note".to_string(),
+                            Json::Number(transport.measure.note as f64),
+                        ),
+                    ]),
+                ),
+            ]),
+        ),
+        ("shared".to_string(), Json::Array(shared_json)),
+        ("root".to_string(), node_to_json(ug.dump(&shared), &shared)),
+    ])
+}
+
+/// Returns names of params of `typename` in the order of TapirLisp arguments.
+fn param_names(typename: &str) -> Option<Vec<String>> {
+    if !TYPE_NAMES.contains(&typename) {
+        return None;
+    }
+    let mut env = Env::init(Transport::new(44100));
+    let form = read(KotoNode::default_form(typename).to_string()).ok()?;
+    match eval(&form[0], &mut env) {
+        Ok(LispValue::Unit(aug)) => match aug.dump(&vec![]) {
+            UgNode::Ug(name, slots) | UgNode::UgRest(name, slots, _, _) if name == typename => {
+                Some(slots.into_iter().map(|s| s.name).collect())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns true if `item` is one pattern item like `c4`, `r` or `(c4 8 100)`.
+///
+/// Items are written into TapirLisp as is, so they must not close the pattern.
+fn is_pattern_item(item: &str) -> bool {
+    let mut depth = 0;
+    for (i, c) in item.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                // `(c4 8) (def ...)` is two forms
+                if depth < 0 || (depth == 0 && i + 1 < item.len()) {
+                    return false;
+                }
+            }
+            ' ' if depth > 0 => (),
+            c if c.is_ascii_alphanumeric() || "+-#.".contains(c) => (),
+            _ => return false,
+        }
+    }
+    item.len() > 0 && depth == 0
+}
+
+fn shared_name(idx: usize) -> String {
+    format!("$shared{}", idx)
+}
+
+/// Writes a value as a TapirLisp form, and pushes indices of shared units in it into `refs`.
+fn value_to_lisp(json: &Json, form: &mut String, refs: &mut Vec<usize>) -> Result<(), PatchError> {
+    let invalid = || PatchError::InvalidValue(json.clone());
+    if let Some(n) = json.as_f64() {
+        form.push_str(&n.to_string());
+        return Ok(());
+    }
+
+    if let Some(table) = json.get("table") {
+        let vec = table.as_array().ok_or(invalid())?;
+        form.push_str("(table");
+        for v in vec {
+            form.push_str(&format!(" {}", v.as_f64().ok_or(invalid())?));
+        }
+        form.push_str(")");
+    } else if let Some(pattern) = json.get("pattern") {
+        let vec = pattern.as_array().ok_or(invalid())?;
+        form.push_str("(pat");
+        for v in vec {
+            let item = v.as_str().ok_or(invalid())?;
+            if !is_pattern_item(item) {
+                return Err(PatchError::InvalidPattern(item.to_string()));
+            }
+            form.push_str(&format!(" {}", item));
+        }
+        form.push_str(")");
+    } else if let Some(idx) = json.get("shared") {
+        let idx = idx.as_f64().ok_or(invalid())? as usize;
+        refs.push(idx);
+        form.push_str(&shared_name(idx));
+    } else if let Some(typename) = json.get("type") {
+        let typename = typename.as_str().ok_or(invalid())?;
+        let names = param_names(typename).ok_or(PatchError::UnknownType(typename.to_string()))?;
+        let params = match json.get("params") {
+            Some(params) => params.as_array().ok_or(invalid())?.clone(),
+            None => Vec::new(),
+        };
+        for param in params.iter() {
+            let name = param
+                .get("name")
+                .and_then(|n| n.as_str())
+                .ok_or(invalid())?;
+            if !names.iter().any(|n| n == name) {
+                let err = PatchError::NoSuchParam(typename.to_string(), name.to_string());
+                return Err(err);
+            }
+        }
+
+        form.push_str(&format!("({}", typename));
+        for name in names {
+            let param = params
+                .iter()
+                .find(|p| p.get("name").and_then(|n| n.as_str()) == Some(&name));
+            let param = param.ok_or(PatchError::MissingParam(typename.to_string(), name))?;
+            form.push_str(" ");
+            value_to_lisp(param.get("value").ok_or(invalid())?, form, refs)?;
+        }
+        if let Some(rest) = json.get("rest") {
+            let values = rest.get("values").and_then(|v| v.as_array());
+            for value in values.ok_or(invalid())? {
+                form.push_str(" ");
+                value_to_lisp(value, form, refs)?;
+            }
+        }
+        form.push_str(")");
+    } else {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Writes `def` forms of shared units in the order they are used.
+fn define_shared(
+    idx: usize,
+    shared: &[Json],
+    states: &mut Vec<u8>,
+    lisp: &mut String,
+) -> Result<(), PatchError> {
+    match states.get(idx) {
+        None => return Err(PatchError::NoSuchShared(idx)),
+        Some(1) => return Err(PatchError::CircularShared(idx)),
+        Some(2) => return Ok(()),
+        _ => (),
+    }
+    states[idx] = 1;
+
+    let mut form = String::new();
+    let mut refs = Vec::new();
+    value_to_lisp(&shared[idx], &mut form, &mut refs)?;
+    for r in refs {
+        define_shared(r, shared, states, lisp)?;
+    }
+    lisp.push_str(&format!("(def {} {})\n", shared_name(idx), form));
+
+    states[idx] = 2;
+    Ok(())
+}
+
+/// Converts a patch into a TapirLisp configuration.
+pub fn to_lisp(json: &Json) -> Result<String, PatchError> {
+    let version = json
+        .get("koto")
+        .and_then(|v| v.as_f64())
+        .ok_or(PatchError::NotPatch)?;
+    if version > VERSION {
+        return Err(PatchError::UnsupportedVersion(version));
+    }
+
+    let mut lisp = String::new();
+    if let Some(transport) = json.get("transport") {
+        if let Some(bpm) = transport.get("bpm").and_then(|v| v.as_f64()) {
+            lisp.push_str(&format!("(bpm {})\n", bpm));
+        }
+        if let Some(measure) = transport.get("measure") {
+            let beat = measure.get("beat").and_then(|v| v.as_f64());
+            let note = measure.get("note").and_then(|v| v.as_f64());
+            if let (Some(beat), Some(note)) = (beat, note) {
+                lisp.push_str(&format!("(measure {} {})\n", beat, note));
+            }
+        }
+    }
+
+    let shared = match json.get("shared") {
+        Some(shared) => shared
+            .as_array()
+            .ok_or(PatchError::InvalidValue(shared.clone()))?
+            .clone(),
+        None => Vec::new(),
+    };
+    let mut form = String::new();
+    let mut refs = Vec::new();
+    value_to_lisp(
+        json.get("root").ok_or(PatchError::NotPatch)?,
+        &mut form,
+        &mut refs,
+    )?;
+
+    if let Some(idx) = refs.iter().find(|idx| **idx >= shared.len()) {
+        return Err(PatchError::NoSuchShared(*idx));
+    }
+    let mut states = vec![0; shared.len()];
+    for idx in 0..shared.len() {
+        define_shared(idx, &shared, &mut states, &mut lisp)?;
+    }
+    lisp.push_str(&form);
+    lisp.push_str("\n");
+    Ok(lisp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::parse;
+
+    fn lisp(src: &str) -> Result<String, PatchError> {
+        to_lisp(&parse(src).unwrap())
+    }
+
+    #[test]
+    fn test_pattern_items() {
+        for item in &["c4", "r", "loop", "(a4 2)", "(c+4 1 0.5)", "(b-3 1)"] {
+            assert!(is_pattern_item(item), "{}", item);
+        }
+        for item in &[
+            "",
+            "(a4 2",
+            "a4)",
+            "(a4 2) (def $x 1)",
+            "a4 2",
+            "(a4 \"2\")",
+            "(a4\n2)",
+        ] {
+            assert!(!is_pattern_item(item), "{}", item);
+        }
+    }
+
+    #[test]
+    fn test_pattern_injection() {
+        let json = parse(r#"{"pattern": ["(a4 2)", "r) (out 1 (saw 0 440)"]}"#).unwrap();
+        let mut form = String::new();
+        match value_to_lisp(&json, &mut form, &mut Vec::new()) {
+            Err(PatchError::InvalidPattern(item)) => assert_eq!(item, "r) (out 1 (saw 0 440)"),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_values() {
+        let json = parse(r#"{"table": [0, 0.5, -1]}"#).unwrap();
+        let mut form = String::new();
+        value_to_lisp(&json, &mut form, &mut Vec::new()).unwrap();
+        assert_eq!(form, "(table 0 0.5 -1)");
+
+        let json = parse(r#"{"pattern": ["(a4 2)", "loop"]}"#).unwrap();
+        let mut form = String::new();
+        value_to_lisp(&json, &mut form, &mut Vec::new()).unwrap();
+        assert_eq!(form, "(pat (a4 2) loop)");
+    }
+
+    #[test]
+    fn test_shared() {
+        let src = r#"{"koto": 1, "transport": {"bpm": 90, "measure": {"beat": 3, "note": 4}},
+                      "shared": [{"shared": 1}, 0.5], "root": {"shared": 0}}"#;
+        let expected =
+            "(bpm 90)\n(measure 3 4)\n(def $shared1 0.5)\n(def $shared0 $shared1)\n$shared0\n";
+        assert_eq!(lisp(src).unwrap(), expected);
+
+        match lisp(r#"{"koto": 1, "root": {"shared": 3}}"#) {
+            Err(PatchError::NoSuchShared(3)) => (),
+            result => panic!("{:?}", result),
+        }
+        match lisp(r#"{"koto": 1, "shared": [{"shared": 1}, {"shared": 0}], "root": 1}"#) {
+            Err(PatchError::CircularShared(0)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        match lisp(r#"{"root": 1}"#) {
+            Err(PatchError::NotPatch) => (),
+            result => panic!("{:?}", result),
+        }
+        match lisp(r#"{"koto": 2, "root": 1}"#) {
+            Err(PatchError::UnsupportedVersion(v)) => assert_eq!(v, 2.0),
+            result => panic!("{:?}", result),
+        }
+        match lisp(r#"{"koto": 1, "root": {"type": "def", "params": []}}"#) {
+            Err(PatchError::UnknownType(name)) => assert_eq!(name, "def"),
+            result => panic!("{:?}", result),
+        }
+        match lisp(r#"{"koto": 1, "root": {"type": "saw) (def $x", "params": []}}"#) {
+            Err(PatchError::UnknownType(_)) => (),
+            result => panic!("{:?}", result),
+        }
+        match lisp(r#"{"koto": 1, "root": "saw"}"#) {
+            Err(PatchError::InvalidValue(_)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
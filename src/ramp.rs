@@ -0,0 +1,171 @@
+//! Glides of parameters, like `880 over 2s`.
+//!
+//! A glide is a unit which moves the value at every sample, and it's swapped for the
+//! target value when it ends.
+
+use std::sync::{Arc, Mutex};
+
+use tapirus::musical_time::time::Transport;
+use tapirus::ugens::core::{
+    Aug, Dump, Operate, OperateError, Proc, Signal, UGen, UgNode, Value, Walk, UG,
+};
+
+use crate::kotonode::{KotoNode, Ugen};
+use crate::schedule::Scheduler;
+
+/// Splits glide syntax like `880 over 2s` or `880 ~1bar` into target and duration.
+pub fn parse_ramp(s: &str) -> Option<(String, String)> {
+    let s = s.trim();
+    if let Some(pos) = s.find(" over ") {
+        Some((s[..pos].to_string(), s[pos + 6..].to_string()))
+    } else if let Some(pos) = s.find('~') {
+        Some((s[..pos].to_string(), s[pos + 1..].to_string()))
+    } else {
+        None
+    }
+}
+
+/// Returns the value of the parameter `paramname` of `aug`, or where it is if it's gliding.
+pub fn current_value(aug: &Aug, paramname: &str) -> Option<f64> {
+    value_of(&aug.get(paramname).ok()?)
+}
+
+/// Returns the value of `ug`, or where it is if it's a glide.
+pub fn value_of(ug: &Aug) -> Option<f64> {
+    if ramp_of(ug).is_some() {
+        return ug.get_str("value").ok()?.parse().ok();
+    }
+    if let UgNode::Val(Value::Number(n)) = ug.dump(&vec![]) {
+        return Some(n);
+    }
+    None
+}
+
+pub struct Ramp {
+    from: f64,
+    to: f64,
+    start: u64,
+    length: u64,
+    value: f64,
+}
+
+impl Ramp {
+    /// Glides from `from` to `to` in `length` samples since the tick `start`.
+    pub fn new(from: f64, to: f64, start: u64, length: u64) -> Aug {
+        let ramp = Ramp {
+            from: from,
+            to: to,
+            start: start,
+            length: length,
+            value: from,
+        };
+        Aug::new(UGen::new(UG::Proc(Box::new(ramp))))
+    }
+}
+
+/// Returns the tick when the glide `ug` ends and its target, or `None` if `ug` is not a glide.
+pub fn ramp_of(ug: &Aug) -> Option<(u64, f64)> {
+    let ramp = ug.get_str("ramp").ok()?;
+    let mut words = ramp.split_whitespace();
+    Some((words.next()?.parse().ok()?, words.next()?.parse().ok()?))
+}
+
+/// Swaps the glide set by `node` for its target value when it ends.
+///
+/// The swap is run in the audio callback, so the value is built here and the file is left
+/// as it is if it's busy.
+pub fn schedule_end(node: Arc<Mutex<KotoNode>>, scheduler: Arc<Mutex<Scheduler>>) {
+    let paramname = match KotoNode::get_nodename(node.clone()) {
+        Some((paramname, _)) => paramname,
+        None => return,
+    };
+    let ramp = match &node.lock().unwrap().ug {
+        Ugen::Mapped(aug) => aug.clone(),
+        _ => return,
+    };
+    let (end, to) = match ramp_of(&ramp) {
+        Some(ramp) => ramp,
+        None => return,
+    };
+    let mut aug = match &node.lock().unwrap().parent {
+        Some(parent) => match &parent.lock().unwrap().ug {
+            Ugen::Mapped(aug) => aug.clone(),
+            _ => return,
+        },
+        None => return,
+    };
+    let val = Aug::val(to);
+    let data = format!("{}\n", to).into_bytes();
+
+    Scheduler::at(
+        scheduler,
+        end,
+        Box::new(move || {
+            // the glide may be overwritten by another value before it ends
+            match aug.get(&paramname) {
+                Ok(current) if Arc::ptr_eq(&current.0, &ramp.0) => (),
+                _ => return,
+            }
+            if let Err(_) = aug.set(&paramname, val.clone()) {
+                return;
+            }
+            if let Ok(mut node) = node.try_lock() {
+                if let Ugen::Mapped(ug) = &node.ug {
+                    if !Arc::ptr_eq(&ug.0, &ramp.0) {
+                        return;
+                    }
+                }
+                node.ug = Ugen::Mapped(val);
+                node.attr.size = data.len() as u64;
+                node.data = data;
+            }
+        }),
+    );
+}
+
+impl Walk for Ramp {
+    fn walk(&self, _f: &mut dyn FnMut(&Aug) -> bool) {}
+}
+
+impl Dump for Ramp {
+    /// Saves have the target value.
+    fn dump(&self, _shared: &Vec<Aug>) -> UgNode {
+        UgNode::Val(Value::Number(self.to))
+    }
+}
+
+impl Operate for Ramp {
+    fn get(&self, pname: &str) -> Result<Aug, OperateError> {
+        Err(OperateError::ParamNotFound(pname.to_string()))
+    }
+
+    fn get_str(&self, pname: &str) -> Result<String, OperateError> {
+        match pname {
+            "ramp" => Ok(format!("{} {}", self.start + self.length, self.to)),
+            "value" => Ok(self.value.to_string()),
+            _ => Err(OperateError::ParamNotFound(pname.to_string())),
+        }
+    }
+
+    fn set(&mut self, pname: &str, _ug: Aug) -> Result<Aug, OperateError> {
+        Err(OperateError::ParamNotFound(pname.to_string()))
+    }
+
+    fn set_str(&mut self, pname: &str, _data: String) -> Result<Aug, OperateError> {
+        Err(OperateError::ParamNotFound(pname.to_string()))
+    }
+
+    fn clear(&mut self, _pname: &str) {}
+}
+
+impl Proc for Ramp {
+    fn proc(&mut self, transport: &Transport) -> Signal {
+        let elapsed = transport.tick.saturating_sub(self.start);
+        self.value = if elapsed >= self.length {
+            self.to
+        } else {
+            self.from + (self.to - self.from) * elapsed as f64 / self.length as f64
+        };
+        (self.value, self.value)
+    }
+}
@@ -0,0 +1,59 @@
+//! Interactive TapirLisp REPL in the running environment.
+//!
+//! A form can span lines; it's evaluated when all parentheses are closed.
+
+use std::io::prelude::*;
+
+/// Returns how many parentheses are not closed yet. Comments and strings are skipped.
+fn depth(code: &str) -> i32 {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut in_comment = false;
+    for c in code.chars() {
+        match c {
+            '\n' => in_comment = false,
+            _ if in_comment => (),
+            '"' => in_string = !in_string,
+            _ if in_string => (),
+            ';' => in_comment = true,
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => (),
+        }
+    }
+    depth
+}
+
+/// Reads forms from stdin and prints results of `eval`, until the end of stdin.
+pub fn run<F: FnMut(&str) -> Result<String, String>>(mut eval: F) {
+    let stdin = std::io::stdin();
+    let mut code = String::new();
+    loop {
+        print!("{}", if code.len() == 0 { "koto> " } else { "... " });
+        let _ = std::io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => code.push_str(&line),
+            Err(err) => {
+                println!("cannot read stdin");
+                println!("{:?}", err);
+                break;
+            }
+        }
+        if depth(&code) > 0 {
+            continue;
+        }
+
+        let form = std::mem::replace(&mut code, String::new());
+        if form.trim().len() == 0 {
+            continue;
+        }
+        match eval(&form) {
+            Ok(text) => print!("{}", text),
+            Err(message) => println!("error: {}", message),
+        }
+    }
+    println!();
+}
@@ -0,0 +1,206 @@
+//! The canonical save format, which is the same for the same unit graph.
+//!
+//! Shared units and units bound in the configuration are `def`ed in the order they first
+//! appear, with their names in the configuration if they have ones. Each top-level form
+//! is in one line.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+
+use tapirus::tapirlisp as tlisp;
+use tapirus::tapirlisp::types::{Env, Value as LispValue};
+use tapirus::ugens::core::{Aug, Dump, UgNode, Value};
+use tapirus::ugens::util::collect_shared_ugs;
+
+use crate::graph::Graph;
+
+struct Saver {
+    shared: Vec<Aug>,
+    /// Names of shared units in `shared`.
+    names: Vec<Option<String>>,
+    binds: Vec<(String, Aug)>,
+    /// Units used once but `def`ed with their names in `binds`.
+    bound: Vec<(Aug, String)>,
+    defs: Vec<String>,
+}
+
+impl Saver {
+    fn used_names(&self) -> Vec<String> {
+        let shared = self.names.iter().filter_map(|n| n.clone());
+        shared
+            .chain(self.bound.iter().map(|(_, n)| n.clone()))
+            .collect()
+    }
+
+    /// Returns the name of `aug` `def`ed with, if it's bound in the configuration.
+    fn bound_name(&mut self, aug: &Aug) -> Option<String> {
+        if let Some((_, name)) = self.bound.iter().find(|(ug, _)| ug == aug) {
+            return Some(name.clone());
+        }
+        let used = self.used_names();
+        let name = self
+            .binds
+            .iter()
+            .find(|(name, ug)| ug == aug && !used.contains(name))?
+            .0
+            .clone();
+        self.bound.push((aug.clone(), name.clone()));
+
+        let form = self.node(aug.dump(&self.shared.clone()));
+        self.defs.push(format!("(def {} {})", name, form));
+        Some(name)
+    }
+
+    fn name(&mut self, idx: usize) -> String {
+        if let Some(name) = &self.names[idx] {
+            return name.clone();
+        }
+        let aug = self.shared[idx].clone();
+        let used = self.used_names();
+        // binds are sorted, so the first one is taken from several names
+        let name = match self
+            .binds
+            .iter()
+            .find(|(name, ug)| *ug == aug && !used.contains(name))
+        {
+            Some((name, _)) => name.clone(),
+            None => {
+                let taken = |name: &String| {
+                    used.contains(name) || self.binds.iter().any(|(bind, _)| bind == name)
+                };
+                let mut n = 0;
+                while taken(&format!("$shared{}", n)) {
+                    n += 1;
+                }
+                format!("$shared{}", n)
+            }
+        };
+        self.names[idx] = Some(name.clone());
+
+        // units used in this unit are defined before it
+        let form = self.node(aug.dump(&self.shared.clone()));
+        self.defs.push(format!("(def {} {})", name, form));
+        name
+    }
+
+    fn value(&mut self, value: Value) -> String {
+        match value {
+            Value::Number(n) => n.to_string(),
+            Value::Table(vec) => {
+                let vals: Vec<String> = vec.iter().map(|v| v.to_string()).collect();
+                format!("(table {})", vals.join(" "))
+            }
+            Value::Pattern(vec) => format!("(pat {})", vec.join(" ")),
+            Value::Ug(aug) => match self.bound_name(&aug) {
+                Some(name) => name,
+                None => self.node(aug.dump(&self.shared.clone())),
+            },
+            Value::Shared(_, aug) => match self.shared.iter().position(|s| *s == aug) {
+                Some(idx) => self.name(idx),
+                None => self.node(aug.dump(&self.shared.clone())),
+            },
+        }
+    }
+
+    fn node(&mut self, node: UgNode) -> String {
+        let (typename, values) = match node {
+            UgNode::Val(v) => return self.value(v),
+            UgNode::Ug(typename, slots) => (
+                typename,
+                slots.into_iter().map(|s| s.value).collect::<Vec<_>>(),
+            ),
+            UgNode::UgRest(typename, slots, _, values) => {
+                let mut vals: Vec<Value> = slots.into_iter().map(|s| s.value).collect();
+                vals.extend(values.into_iter().map(|v| *v));
+                (typename, vals)
+            }
+        };
+
+        let mut form = format!("({}", typename);
+        for v in values {
+            form.push_str(" ");
+            form.push_str(&self.value(v));
+        }
+        form.push_str(")");
+        form
+    }
+}
+
+/// Returns `def` forms of shared and bound units, and the form of `ug` itself, one for each line.
+fn forms(ug: Aug, env: &Env) -> Vec<String> {
+    let mut binds: Vec<(String, Aug)> = env
+        .binds
+        .iter()
+        .filter_map(|(name, value)| match &**value {
+            LispValue::Unit(aug) => Some((name.clone(), aug.clone())),
+            _ => None,
+        })
+        .collect();
+    binds.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let shared = collect_shared_ugs(ug.clone());
+    let mut saver = Saver {
+        names: shared.iter().map(|_| None).collect(),
+        shared: shared,
+        binds: binds,
+        bound: Vec::new(),
+        defs: Vec::new(),
+    };
+    let root = saver.node(ug.dump(&saver.shared.clone()));
+    saver.defs.push(root);
+    saver.defs
+}
+
+/// Prints the unit `ug` as TapirLisp forms, with names of `$vars` in `env`.
+pub fn to_form(ug: Aug, env: &Env) -> String {
+    let mut lines = forms(ug, env);
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// Dumps the unit graph `ug` in the canonical save format.
+pub fn dump(ug: Aug, env: &Env) -> String {
+    let transport = &env.transport;
+    let mut lines = vec![
+        format!(";; koto {}", env!("CARGO_PKG_VERSION")),
+        format!(";; sample-rate {}", transport.sample_rate),
+        format!("(bpm {})", transport.bpm),
+        format!(
+            "(measure {} {})",
+            transport.measure.beat, transport.measure.note
+        ),
+    ];
+    lines.append(&mut forms(ug, env));
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// Writes the unit graph into `koto.TIMESTAMP.lisp` and returns the file name.
+/// `saved` becomes the unit graph, to show differences from the saved one.
+pub fn save_file(
+    ug: Aug,
+    env: &Env,
+    lock: Arc<Mutex<bool>>,
+    saved: Arc<Mutex<Graph>>,
+    canonical: bool,
+) -> std::io::Result<String> {
+    let filename = format!(
+        "koto.{}.lisp",
+        time::strftime("%Y%m%dT%H%M%S", &time::now()).unwrap()
+    );
+    let mut f = File::create(&filename)?;
+    let mut config = None;
+    if let Ok(_) = lock.lock() {
+        config = Some(if canonical {
+            dump(ug.clone(), env)
+        } else {
+            tlisp::dump(ug.clone(), env)
+        });
+        *saved.lock().unwrap() = Graph::build(ug.clone());
+    }
+    if let Some(config) = config {
+        f.write_all(config.as_bytes())?;
+    }
+    Ok(filename)
+}
@@ -0,0 +1,373 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use tapirus::musical_time::time::Transport;
+use tapirus::ugens::core::{
+    Aug, Dump, Operate, OperateError, Proc, Signal, UGen, UgNode, Walk, UG,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantize {
+    Off,
+    Beat,
+    Bar(u64),
+}
+
+impl Quantize {
+    pub fn parse(s: &str) -> Option<Quantize> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match &words[..] {
+            ["off"] => Some(Quantize::Off),
+            ["beat"] => Some(Quantize::Beat),
+            ["bar"] => Some(Quantize::Bar(1)),
+            [n, "bar"] | [n, "bars"] => match n.parse::<u64>() {
+                Ok(n) if n > 0 => Some(Quantize::Bar(n)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the length of one quantization unit in samples.
+    fn period(&self, transport: &Transport) -> Option<u64> {
+        let beat = transport.sample_rate as f64 * 60.0 / transport.bpm as f64;
+        let period = match self {
+            Quantize::Off => return None,
+            Quantize::Beat => beat,
+            Quantize::Bar(n) => beat * transport.measure.beat as f64 * *n as f64,
+        };
+        if period >= 1.0 {
+            Some(period as u64)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Quantize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Quantize::Off => write!(f, "off"),
+            Quantize::Beat => write!(f, "beat"),
+            Quantize::Bar(1) => write!(f, "bar"),
+            Quantize::Bar(n) => write!(f, "{} bars", n),
+        }
+    }
+}
+
+pub type Pending = Box<dyn FnOnce() + Send>;
+
+thread_local! {
+    /// Swaps staged by the edit being prepared on this thread, if any.
+    static STAGED: RefCell<Option<Vec<Pending>>> = RefCell::new(None);
+}
+
+/// Runs `prepare` on this thread and returns the swaps it staged by `set()` and `clear()`.
+fn stage(prepare: Box<dyn FnOnce()>) -> Vec<Pending> {
+    STAGED.with(|staged| *staged.borrow_mut() = Some(Vec::new()));
+    prepare();
+    STAGED.with(|staged| staged.borrow_mut().take().unwrap_or(Vec::new()))
+}
+
+/// Sets `ug` as the parameter `pname` of `aug`, or stages it while an edit is prepared.
+pub fn set(aug: &Aug, pname: &str, ug: Aug) -> Result<(), OperateError> {
+    let mut ug = Some(ug);
+    STAGED.with(|staged| {
+        if let Some(swaps) = &mut *staged.borrow_mut() {
+            let (mut aug, pname, ug) = (aug.clone(), pname.to_string(), ug.take());
+            swaps.push(Box::new(move || {
+                if let Some(ug) = ug {
+                    let _ = aug.set(&pname, ug);
+                }
+            }));
+        }
+    });
+    match ug {
+        Some(ug) => aug.clone().set(pname, ug).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Disconnects the parameter `pname` of `aug`, or stages it while an edit is prepared.
+pub fn clear(aug: &Aug, pname: &str) {
+    let staged = STAGED.with(|staged| match &mut *staged.borrow_mut() {
+        Some(swaps) => {
+            let (mut aug, pname) = (aug.clone(), pname.to_string());
+            swaps.push(Box::new(move || aug.clear(&pname)));
+            true
+        }
+        None => false,
+    });
+    if !staged {
+        aug.clone().clear(pname);
+    }
+}
+
+/// Holds graph edits which wait for the next beat or bar of the transport,
+/// or for the commit of a transaction.
+///
+/// Edits are built on the thread which makes them, and only swaps of ready-built units
+/// are queued, so the audio callback never builds units nor waits for other locks.
+pub struct Scheduler {
+    pub quantize: Quantize,
+    pub pending: Vec<Pending>,
+    pub txn: Option<Vec<Pending>>,
+    /// Edits at ticks of the transport, like ends of glides.
+    pub timed: Vec<(u64, Pending)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            quantize: Quantize::Off,
+            pending: Vec::new(),
+            txn: None,
+            timed: Vec::new(),
+        }
+    }
+
+    pub fn begin(&mut self) -> bool {
+        if self.txn.is_some() {
+            false
+        } else {
+            self.txn = Some(Vec::new());
+            true
+        }
+    }
+
+    pub fn abort(&mut self) -> bool {
+        self.txn.take().is_some()
+    }
+
+    /// Copies `transport` as it will be when an edit made now is applied, that is, at the
+    /// next boundary if quantization is on. In a transaction, it's the transport of now.
+    pub fn transport(
+        scheduler: Arc<Mutex<Scheduler>>,
+        transport: Arc<Mutex<Transport>>,
+    ) -> Arc<Mutex<Transport>> {
+        let mut copy = transport.lock().unwrap().clone();
+        let scheduler = scheduler.lock().unwrap();
+        if scheduler.txn.is_none() {
+            if let Some(period) = scheduler.quantize.period(&copy) {
+                copy.tick = (copy.tick / period + 1) * period;
+            }
+        }
+        Arc::new(Mutex::new(copy))
+    }
+
+    /// Applies all edits in the transaction at once, under one acquisition of `lock`.
+    pub fn commit(scheduler: Arc<Mutex<Scheduler>>, lock: Arc<Mutex<bool>>) -> bool {
+        let swaps = match scheduler.lock().unwrap().txn.take() {
+            Some(swaps) => swaps,
+            None => return false,
+        };
+        Scheduler::queue(
+            scheduler,
+            lock,
+            Box::new(move || {
+                for swap in swaps {
+                    swap();
+                }
+            }),
+        );
+        true
+    }
+
+    /// Runs `op` at the tick `tick` of the transport, regardless of quantization.
+    ///
+    /// `op` is run in the audio callback, so it should only swap ready-built units.
+    pub fn at(scheduler: Arc<Mutex<Scheduler>>, tick: u64, op: Pending) {
+        scheduler.lock().unwrap().timed.push((tick, op));
+    }
+
+    /// Prepares an edit by `prepare` now, and applies swaps of units built by it now or at
+    /// the next boundary if quantization is on. In a transaction, they wait for the commit.
+    pub fn run(
+        scheduler: Arc<Mutex<Scheduler>>,
+        lock: Arc<Mutex<bool>>,
+        prepare: Box<dyn FnOnce()>,
+    ) {
+        let swaps = stage(prepare);
+        if swaps.len() == 0 {
+            return;
+        }
+        Scheduler::queue(
+            scheduler,
+            lock,
+            Box::new(move || {
+                for swap in swaps {
+                    swap();
+                }
+            }),
+        );
+    }
+
+    fn queue(scheduler: Arc<Mutex<Scheduler>>, lock: Arc<Mutex<bool>>, swap: Pending) {
+        {
+            let mut scheduler = scheduler.lock().unwrap();
+            if let Some(txn) = &mut scheduler.txn {
+                txn.push(swap);
+                return;
+            }
+            if scheduler.quantize != Quantize::Off {
+                scheduler.pending.push(swap);
+                return;
+            }
+        }
+        if let Ok(_) = lock.lock() {
+            swap();
+        }
+    }
+}
+
+/// A unit which plays the root module and applies queued edits of the scheduler at the
+/// exact sample where the transport crosses a quantization boundary.
+///
+/// It's procced in the audio callback, so queued edits are only swaps of ready-built units,
+/// applied while the sound system holds the lock of the unit graph.
+pub struct Clock {
+    ug: Aug,
+    scheduler: Arc<Mutex<Scheduler>>,
+    last_quantize: Quantize,
+    last_boundary: Option<u64>,
+}
+
+impl Clock {
+    pub fn new(ug: Aug, scheduler: Arc<Mutex<Scheduler>>) -> Aug {
+        let clock = Clock {
+            ug: ug,
+            scheduler: scheduler,
+            last_quantize: Quantize::Off,
+            last_boundary: None,
+        };
+        Aug::new(UGen::new(UG::Proc(Box::new(clock))))
+    }
+
+    /// Returns queued edits which are due at this sample.
+    fn due(&mut self, transport: &Transport) -> Vec<Pending> {
+        // the audio callback never waits; edits are checked again at the next sample
+        let mut scheduler = match self.scheduler.try_lock() {
+            Ok(scheduler) => scheduler,
+            Err(_) => return Vec::new(),
+        };
+
+        if scheduler.quantize != self.last_quantize {
+            self.last_quantize = scheduler.quantize;
+            self.last_boundary = None;
+        }
+        let boundary = scheduler
+            .quantize
+            .period(transport)
+            .map(|period| transport.tick / period);
+        let due = match (boundary, self.last_boundary) {
+            (None, _) => true,
+            (Some(b), Some(last)) => b != last,
+            (Some(_), None) => false,
+        };
+        self.last_boundary = boundary;
+
+        let mut ops = Vec::new();
+        if due {
+            ops.extend(scheduler.pending.drain(..));
+        }
+        if scheduler.timed.len() > 0 {
+            let (now, later) = scheduler
+                .timed
+                .drain(..)
+                .partition(|(tick, _)| *tick <= transport.tick);
+            scheduler.timed = later;
+            ops.extend(now.into_iter().map(|(_, op): (u64, Pending)| op));
+        }
+        ops
+    }
+}
+
+impl Walk for Clock {
+    fn walk(&self, f: &mut dyn FnMut(&Aug) -> bool) {
+        if f(&self.ug) {
+            self.ug.walk(f);
+        }
+    }
+}
+
+impl Dump for Clock {
+    fn dump(&self, shared: &Vec<Aug>) -> UgNode {
+        self.ug.dump(shared)
+    }
+}
+
+impl Operate for Clock {
+    fn get(&self, pname: &str) -> Result<Aug, OperateError> {
+        self.ug.get(pname)
+    }
+
+    fn get_str(&self, pname: &str) -> Result<String, OperateError> {
+        self.ug.get_str(pname)
+    }
+
+    fn set(&mut self, pname: &str, ug: Aug) -> Result<Aug, OperateError> {
+        self.ug.set(pname, ug)
+    }
+
+    fn set_str(&mut self, pname: &str, data: String) -> Result<Aug, OperateError> {
+        self.ug.set_str(pname, data)
+    }
+
+    fn clear(&mut self, pname: &str) {
+        self.ug.clear(pname)
+    }
+}
+
+impl Proc for Clock {
+    fn proc(&mut self, transport: &Transport) -> Signal {
+        for op in self.due(transport) {
+            op();
+        }
+        self.ug.proc(transport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport_at(scheduler: &Arc<Mutex<Scheduler>>, tick: u64) -> u64 {
+        let mut transport = Transport::new(44100);
+        transport.tick = tick;
+        let transport = Scheduler::transport(scheduler.clone(), Arc::new(Mutex::new(transport)));
+        let tick = transport.lock().unwrap().tick;
+        tick
+    }
+
+    #[test]
+    fn test_transport() {
+        let scheduler = Arc::new(Mutex::new(Scheduler::new()));
+        assert_eq!(transport_at(&scheduler, 100), 100);
+
+        // one beat is 22050 samples at 120 BPM
+        scheduler.lock().unwrap().quantize = Quantize::Beat;
+        assert_eq!(transport_at(&scheduler, 100), 22050);
+        assert_eq!(transport_at(&scheduler, 22050), 44100);
+
+        scheduler.lock().unwrap().begin();
+        assert_eq!(transport_at(&scheduler, 100), 100);
+    }
+
+    #[test]
+    fn test_run() {
+        let scheduler = Arc::new(Mutex::new(Scheduler::new()));
+        scheduler.lock().unwrap().quantize = Quantize::Bar(1);
+        let prepared = Arc::new(Mutex::new(false));
+        let flag = prepared.clone();
+        Scheduler::run(
+            scheduler.clone(),
+            Arc::new(Mutex::new(true)),
+            Box::new(move || *flag.lock().unwrap() = true),
+        );
+
+        // edits are built at once, and nothing is queued without swaps
+        assert!(*prepared.lock().unwrap());
+        assert_eq!(scheduler.lock().unwrap().pending.len(), 0);
+    }
+}
@@ -0,0 +1,157 @@
+use std::f64::consts::PI;
+
+use tapirus::ugens::core::{Aug, Table, UGen, UG};
+
+/// The length of tables synthesized into an empty table.
+pub const DEFAULT_LENGTH: usize = 256;
+
+#[derive(Debug)]
+pub enum TableError {
+    UnknownWaveform(String),
+    InvalidAmplitude(String),
+    NoHarmonics,
+    InvalidLength(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interp {
+    None,
+    Linear,
+    Cubic,
+}
+
+impl Interp {
+    pub fn parse(s: &str) -> Option<Interp> {
+        match s.trim() {
+            "none" => Some(Interp::None),
+            "linear" => Some(Interp::Linear),
+            "cubic" => Some(Interp::Cubic),
+            _ => None,
+        }
+    }
+}
+
+/// Makes a table unit of `table`, to set it as a parameter without converting it to text.
+pub fn table_ug(table: Vec<f64>) -> Aug {
+    Aug::new(UGen::new(UG::Tab(Table::new(table))))
+}
+
+fn at(table: &[f64], idx: isize) -> f64 {
+    let idx = idx.max(0).min(table.len() as isize - 1);
+    table[idx as usize]
+}
+
+/// Stretches or shrinks `table` into `len` samples.
+pub fn resize(table: &[f64], len: usize, interp: Interp) -> Vec<f64> {
+    if table.len() == 0 {
+        return vec![0.0; len];
+    }
+
+    let ratio = table.len() as f64 / len as f64;
+    let mut resized = Vec::with_capacity(len);
+    for i in 0..len {
+        let pos = i as f64 * ratio;
+        let idx = pos.floor() as isize;
+        let t = pos - idx as f64;
+        let y1 = at(table, idx);
+        let y2 = at(table, idx + 1);
+        let v = match interp {
+            Interp::None => y1,
+            Interp::Linear => y1 + (y2 - y1) * t,
+            Interp::Cubic => {
+                // Catmull-Rom spline
+                let y0 = at(table, idx - 1);
+                let y3 = at(table, idx + 2);
+                let a = -0.5 * y0 + 1.5 * y1 - 1.5 * y2 + 0.5 * y3;
+                let b = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+                let c = -0.5 * y0 + 0.5 * y2;
+                ((a * t + b) * t + c) * t + y1
+            }
+        };
+        resized.push(v);
+    }
+    resized
+}
+
+pub fn parse_length(s: &str) -> Result<usize, TableError> {
+    match s.trim().parse::<usize>() {
+        Ok(len) if len > 0 => Ok(len),
+        _ => Err(TableError::InvalidLength(s.trim().to_string())),
+    }
+}
+
+fn waveform(name: &str, ph: f64) -> f64 {
+    match name {
+        "sine" => (2.0 * PI * ph).sin(),
+        "tri" => {
+            if ph < 0.25 {
+                4.0 * ph
+            } else if ph < 0.75 {
+                2.0 - 4.0 * ph
+            } else {
+                4.0 * ph - 4.0
+            }
+        }
+        "saw" => {
+            if ph < 0.5 {
+                2.0 * ph
+            } else {
+                2.0 * ph - 2.0
+            }
+        }
+        "pulse" => {
+            if ph < 0.5 {
+                1.0
+            } else {
+                -1.0
+            }
+        }
+        _ => 0.0,
+    }
+}
+
+/// Synthesizes an additive waveform from a spec like `sine 1 0.5 0.33`.
+///
+/// The first word is the waveform of partials and the rest are amplitudes of
+/// the 1st, 2nd, 3rd... harmonics.
+pub fn harmonics(spec: &str, len: usize) -> Result<Vec<f64>, TableError> {
+    let mut words = spec.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name.to_string(),
+        None => return Err(TableError::NoHarmonics),
+    };
+    if !["sine", "tri", "saw", "pulse"].contains(&&name[..]) {
+        return Err(TableError::UnknownWaveform(name));
+    }
+
+    let mut amps = Vec::new();
+    for word in words {
+        match word.parse::<f64>() {
+            Ok(amp) => amps.push(amp),
+            Err(_) => return Err(TableError::InvalidAmplitude(word.to_string())),
+        }
+    }
+    if amps.len() == 0 {
+        return Err(TableError::NoHarmonics);
+    }
+
+    let mut table = Vec::with_capacity(len);
+    for i in 0..len {
+        let ph = i as f64 / len as f64;
+        let mut v = 0.0;
+        for (n, amp) in amps.iter().enumerate() {
+            v += amp * waveform(&name, (ph * (n + 1) as f64).fract());
+        }
+        table.push(v);
+    }
+    Ok(table)
+}
+
+/// Rescales `table` so that its peak is `peak`.
+pub fn normalize(table: &[f64], peak: f64) -> Vec<f64> {
+    let max = table.iter().fold(0.0, |m: f64, v| m.max(v.abs()));
+    if max == 0.0 {
+        return table.to_vec();
+    }
+    table.iter().map(|v| v * peak / max).collect()
+}
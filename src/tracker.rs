@@ -0,0 +1,243 @@
+//! Converter between `.pat` and tracker-style `.trk` pattern files.
+//!
+//! A `.trk` file has one step per line, with columns of note, length and velocity.
+//! Text after `;` is a comment. Velocity `-` means the note has no velocity.
+//!
+//! ```text
+//! ; note  len   vel
+//! a4      2     -     ; root
+//! r       2     -
+//! loop
+//! ```
+
+const HEADER: &str = "; note  len   vel";
+
+#[derive(Debug)]
+pub enum TrackerError {
+    TooManyColumns(usize),
+}
+
+/// Splits pattern text into its items like `(a4 2)` or `loop`.
+pub fn split_pattern(pat: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut depth = 0;
+    for c in pat.chars() {
+        match c {
+            '(' => {
+                depth += 1;
+                item.push(c);
+            }
+            ')' => {
+                depth -= 1;
+                item.push(c);
+                if depth == 0 {
+                    items.push(item.clone());
+                    item.clear();
+                }
+            }
+            c if c.is_whitespace() && depth == 0 => {
+                if item.len() > 0 {
+                    items.push(item.clone());
+                    item.clear();
+                }
+            }
+            c => item.push(c),
+        }
+    }
+    if item.len() > 0 {
+        items.push(item);
+    }
+    items
+}
+
+pub fn join_pattern(items: &[String]) -> String {
+    let mut pat = String::new();
+    for item in items {
+        pat.push_str(item);
+        pat.push_str(" ");
+    }
+    pat.push_str("\n");
+    pat
+}
+
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match line.find(';') {
+        Some(pos) => (&line[..pos], Some(&line[pos..])),
+        None => (line, None),
+    }
+}
+
+/// Parses a step of `.trk` text into a pattern item, or `None` if it's empty.
+fn parse_step(step: &str, lineno: usize) -> Result<Option<String>, TrackerError> {
+    let step = step.trim();
+    if step.starts_with('(') {
+        // an item which cannot be put in columns
+        return Ok(Some(step.to_string()));
+    }
+    let cols: Vec<&str> = step.split_whitespace().collect();
+    match &cols[..] {
+        [] => Ok(None),
+        [word] => Ok(Some(word.to_string())),
+        [note, len] | [note, len, "-"] => Ok(Some(format!("({} {})", note, len))),
+        [note, len, vel] => Ok(Some(format!("({} {} {})", note, len, vel))),
+        _ => Err(TrackerError::TooManyColumns(lineno + 1)),
+    }
+}
+
+/// Returns steps and their comments in `.trk` text, to keep them over regeneration.
+pub fn comments(trk: &str) -> Vec<(String, Option<String>)> {
+    let mut comments = Vec::new();
+    for (lineno, line) in trk.lines().enumerate() {
+        let (step, comment) = split_comment(line);
+        if let Ok(Some(item)) = parse_step(step, lineno) {
+            comments.push((item, comment.map(|c| c.to_string())));
+        }
+    }
+    comments
+}
+
+/// Finds the comment of each item in `comments` of old steps.
+///
+/// Comments follow their steps in order, so they stay on the same notes when steps
+/// are inserted or removed.
+fn align_comments(items: &[String], comments: &[(String, Option<String>)]) -> Vec<Option<String>> {
+    let mut aligned = Vec::new();
+    let mut next = 0;
+    for item in items {
+        match comments[next..].iter().position(|(step, _)| step == item) {
+            Some(pos) => {
+                aligned.push(comments[next + pos].1.clone());
+                next += pos + 1;
+            }
+            None => aligned.push(None),
+        }
+    }
+    aligned
+}
+
+/// Formats pattern items as `.trk` text with `comments` of old steps.
+pub fn to_trk(items: &[String], comments: &[(String, Option<String>)]) -> String {
+    let comments = align_comments(items, comments);
+    let mut trk = format!("{}\n", HEADER);
+    for (i, item) in items.iter().enumerate() {
+        let cols: Vec<&str> = if item.starts_with('(') && item.ends_with(')') {
+            item[1..item.len() - 1].split_whitespace().collect()
+        } else {
+            vec![]
+        };
+        let mut line = match &cols[..] {
+            [note, len] => format!("{:<8}{:<6}{:<6}", note, len, "-"),
+            [note, len, vel] => format!("{:<8}{:<6}{:<6}", note, len, vel),
+            _ => format!("{:<20}", item),
+        };
+        if let Some(Some(comment)) = comments.get(i) {
+            line.push_str(comment);
+        }
+        trk.push_str(line.trim_end());
+        trk.push_str("\n");
+    }
+    trk
+}
+
+/// Parses `.trk` text into pattern items.
+pub fn from_trk(trk: &str) -> Result<Vec<String>, TrackerError> {
+    let mut items = Vec::new();
+    for (lineno, line) in trk.lines().enumerate() {
+        let (step, _) = split_comment(line);
+        if let Some(item) = parse_step(step, lineno)? {
+            items.push(item);
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split_pattern() {
+        let pat = "(a4 2) r\n(c4 1 0.5)  loop ";
+        assert_eq!(
+            split_pattern(pat),
+            items(&["(a4 2)", "r", "(c4 1 0.5)", "loop"])
+        );
+        assert_eq!(
+            join_pattern(&split_pattern(pat)),
+            "(a4 2) r (c4 1 0.5) loop \n"
+        );
+    }
+
+    #[test]
+    fn test_to_trk() {
+        let trk = to_trk(&items(&["(a4 2)", "(c4 1 0.5)", "loop"]), &[]);
+        let expected = "; note  len   vel
+a4      2     -
+c4      1     0.5
+loop
+";
+        assert_eq!(trk, expected);
+    }
+
+    #[test]
+    fn test_from_trk() {
+        let trk = "; note  len   vel
+a4      2     -     ; root
+
+c4 1 0.5
+(e4 1) ; not in columns
+loop
+";
+        let expected = items(&["(a4 2)", "(c4 1 0.5)", "(e4 1)", "loop"]);
+        assert_eq!(from_trk(trk).unwrap(), expected);
+        assert_eq!(from_trk(&to_trk(&expected, &[])).unwrap(), expected);
+
+        match from_trk("a4 2 0.5 extra") {
+            Err(TrackerError::TooManyColumns(1)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_comments_stay_on_their_steps() {
+        let trk = "; note  len   vel
+a4      2     -     ; root
+c4      1     -     ; third
+loop
+";
+        let comments = comments(trk);
+        // a step is inserted before the commented ones
+        let trk = to_trk(&items(&["(g4 1)", "(a4 2)", "(c4 1)", "loop"]), &comments);
+        let expected = "; note  len   vel
+g4      1     -
+a4      2     -     ; root
+c4      1     -     ; third
+loop
+";
+        assert_eq!(trk, expected);
+
+        // a commented step is removed
+        let trk = to_trk(&items(&["(c4 1)", "loop"]), &comments);
+        assert_eq!(
+            trk,
+            "; note  len   vel\nc4      1     -     ; third\nloop\n"
+        );
+    }
+
+    #[test]
+    fn test_repeated_steps_keep_their_comments() {
+        let trk = "a4 1 ; first\na4 1 ; second\n";
+        let trk = to_trk(&items(&["(a4 1)", "(a4 1)", "(a4 1)"]), &comments(trk));
+        let expected = "; note  len   vel
+a4      1     -     ; first
+a4      1     -     ; second
+a4      1     -
+";
+        assert_eq!(trk, expected);
+    }
+}
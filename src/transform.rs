@@ -0,0 +1,257 @@
+use tapirus::musical_time::time::Transport;
+use tapirus::ugens::core::{
+    Aug, Dump, Operate, OperateError, Proc, Signal, Slot, UGen, UgNode, Value, Walk, UG,
+};
+
+use crate::expr::{lift_octaves, midi_to_note, note_to_midi};
+
+/// The parameter name which returns the sequencer, to find `Transformed` in the graph.
+const TRANSFORMED: &str = "transformed";
+
+/// Names of the control files of transformations, in the order of `Transform::parse()`.
+pub const SETTING_NAMES: [&str; 4] = ["reverse", "rotate", "transpose", "scale"];
+
+const SCALES: [(&str, &[i32]); 10] = [
+    ("major", &[0, 2, 4, 5, 7, 9, 11]),
+    ("minor", &[0, 2, 3, 5, 7, 8, 10]),
+    ("dorian", &[0, 2, 3, 5, 7, 9, 10]),
+    ("phrygian", &[0, 1, 3, 5, 7, 8, 10]),
+    ("lydian", &[0, 2, 4, 6, 7, 9, 11]),
+    ("mixolydian", &[0, 2, 4, 5, 7, 9, 10]),
+    ("locrian", &[0, 1, 3, 5, 6, 8, 10]),
+    ("harmonic-minor", &[0, 2, 3, 5, 7, 8, 11]),
+    ("pentatonic", &[0, 2, 4, 7, 9]),
+    ("minor-pentatonic", &[0, 3, 5, 7, 10]),
+];
+
+#[derive(Debug)]
+pub enum TransformError {
+    InvalidNumber(String),
+    InvalidScale(String),
+}
+
+/// Non-destructive transformations of a sequencer pattern, applied in this order.
+#[derive(Debug, Clone)]
+pub struct Transform {
+    pub reverse: bool,
+    pub rotate: i64,
+    pub transpose: i32,
+    pub scale: Option<(i32, Vec<i32>)>,
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str) -> Result<T, TransformError> {
+    s.trim()
+        .parse()
+        .map_err(|_| TransformError::InvalidNumber(s.trim().to_string()))
+}
+
+/// Parses scales like `c major` or `f# minor`. `off` or empty means no scale.
+fn parse_scale(s: &str) -> Result<Option<(i32, Vec<i32>)>, TransformError> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    match &words[..] {
+        [] | ["off"] => Ok(None),
+        [root, name] => {
+            let root = note_to_midi(&format!("{}0", root))
+                .ok_or(TransformError::InvalidScale(s.trim().to_string()))?;
+            match SCALES.iter().find(|(n, _)| n == name) {
+                Some((_, degrees)) => Ok(Some((root.rem_euclid(12), degrees.to_vec()))),
+                None => Err(TransformError::InvalidScale(s.trim().to_string())),
+            }
+        }
+        _ => Err(TransformError::InvalidScale(s.trim().to_string())),
+    }
+}
+
+impl Transform {
+    pub fn parse(
+        reverse: &str,
+        rotate: &str,
+        transpose: &str,
+        scale: &str,
+    ) -> Result<Transform, TransformError> {
+        Ok(Transform {
+            reverse: parse_number::<i32>(reverse)? != 0,
+            rotate: parse_number(rotate)?,
+            transpose: parse_number(transpose)?,
+            scale: parse_scale(scale)?,
+        })
+    }
+
+    fn map_note(&self, note: &str) -> String {
+        let mut midi = match note_to_midi(note) {
+            Some(midi) => midi + self.transpose,
+            // rests and unpitched notes
+            None => return note.to_string(),
+        };
+        if let Some((root, degrees)) = &self.scale {
+            // snaps down to the nearest note in the scale
+            while !degrees.contains(&(midi - root).rem_euclid(12)) {
+                midi -= 1;
+            }
+        }
+        // notes transposed below c0 are raised by octaves
+        midi_to_note(lift_octaves(midi)).unwrap_or(note.to_string())
+    }
+
+    fn map_item(&self, item: &str) -> String {
+        if !(item.starts_with('(') && item.ends_with(')')) {
+            return item.to_string();
+        }
+        let mut cols: Vec<String> = item[1..item.len() - 1]
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect();
+        if let Some(note) = cols.get_mut(0) {
+            *note = self.map_note(note);
+        }
+        format!("({})", cols.join(" "))
+    }
+
+    /// Returns true if this transformation doesn't change patterns.
+    pub fn is_identity(&self) -> bool {
+        !self.reverse && self.rotate == 0 && self.transpose == 0 && self.scale.is_none()
+    }
+
+    /// Applies this transformation to pattern items. `loop` stays at the end.
+    pub fn apply(&self, items: &[String]) -> Vec<String> {
+        if self.is_identity() {
+            return items.to_vec();
+        }
+
+        let (mut steps, tail): (Vec<String>, Vec<String>) =
+            items.iter().cloned().partition(|item| item != "loop");
+        if self.reverse {
+            steps.reverse();
+        }
+        if steps.len() > 0 {
+            let n = self.rotate.rem_euclid(steps.len() as i64) as usize;
+            steps.rotate_right(n);
+        }
+        let mut transformed: Vec<String> = steps.iter().map(|item| self.map_item(item)).collect();
+        transformed.extend(tail);
+        transformed
+    }
+}
+
+/// A unit which plays the sequencer `ug` with a transformed pattern.
+///
+/// It dumps as the sequencer with the source pattern, so saves and the node tree have
+/// the pattern before transformation.
+pub struct Transformed {
+    ug: Aug,
+    source: Vec<String>,
+    /// Contents of the control files, like `("transpose", "5")`.
+    settings: Vec<(String, String)>,
+}
+
+impl Transformed {
+    pub fn new(ug: Aug, source: Vec<String>, settings: Vec<(String, String)>) -> Aug {
+        let transformed = Transformed {
+            ug: transformed_of(&ug).unwrap_or(ug),
+            source: source,
+            settings: settings,
+        };
+        Aug::new(UGen::new(UG::Proc(Box::new(transformed))))
+    }
+}
+
+/// Returns the sequencer in `ug`, or `None` if `ug` is not transformed.
+pub fn transformed_of(ug: &Aug) -> Option<Aug> {
+    ug.get(TRANSFORMED).ok()
+}
+
+/// Returns the content of the control file `name` of the transformed sequencer `ug`.
+pub fn setting_of(ug: &Aug, name: &str) -> Option<String> {
+    transformed_of(ug)?;
+    ug.get_str(name).ok()
+}
+
+impl Walk for Transformed {
+    fn walk(&self, f: &mut dyn FnMut(&Aug) -> bool) {
+        if f(&self.ug) {
+            self.ug.walk(f);
+        }
+    }
+}
+
+impl Dump for Transformed {
+    fn dump(&self, shared: &Vec<Aug>) -> UgNode {
+        match self.ug.dump(shared) {
+            UgNode::Ug(name, slots) => {
+                let slots = slots
+                    .into_iter()
+                    .map(|s| match &s.name[..] {
+                        "pattern" => Slot {
+                            ug: s.ug,
+                            name: s.name,
+                            value: Value::Pattern(self.source.clone()),
+                        },
+                        _ => s,
+                    })
+                    .collect();
+                UgNode::Ug(name, slots)
+            }
+            node => node,
+        }
+    }
+}
+
+impl Operate for Transformed {
+    fn get(&self, pname: &str) -> Result<Aug, OperateError> {
+        match pname {
+            TRANSFORMED => Ok(self.ug.clone()),
+            _ => self.ug.get(pname),
+        }
+    }
+
+    fn get_str(&self, pname: &str) -> Result<String, OperateError> {
+        match self.settings.iter().find(|(name, _)| name == pname) {
+            Some((_, value)) => Ok(value.clone()),
+            None => self.ug.get_str(pname),
+        }
+    }
+
+    fn set(&mut self, pname: &str, ug: Aug) -> Result<Aug, OperateError> {
+        self.ug.set(pname, ug)
+    }
+
+    fn set_str(&mut self, pname: &str, data: String) -> Result<Aug, OperateError> {
+        self.ug.set_str(pname, data)
+    }
+
+    fn clear(&mut self, pname: &str) {
+        self.ug.clear(pname)
+    }
+}
+
+impl Proc for Transformed {
+    fn proc(&mut self, transport: &Transport) -> Signal {
+        self.ug.proc(transport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transpose(items: &[&str], transpose: &str) -> Vec<String> {
+        let items: Vec<String> = items.iter().map(|s| s.to_string()).collect();
+        Transform::parse("0", "0", transpose, "off")
+            .unwrap()
+            .apply(&items)
+    }
+
+    #[test]
+    fn test_transpose() {
+        assert_eq!(
+            transpose(&["(a4 2)", "(r 2)", "loop"], "3"),
+            vec!["(c5 2)", "(r 2)", "loop"]
+        );
+        assert_eq!(transpose(&["(d0 1)"], "-2"), vec!["(c0 1)"]);
+        assert_eq!(transpose(&["(d0 1)"], "-3"), vec!["(b0 1)"]);
+        assert_eq!(
+            transpose(&["(c+0 1)", "(c1 1)"], "-14"),
+            vec!["(b0 1)", "(a+0 1)"]
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Real directory trees with the same layout as the mount, to edit patches offline.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use fuse::FileType;
+
+use tapirus::musical_time::time::Transport;
+use tapirus::ugens::core::Aug;
+
+use crate::kotonode::KotoNode;
+
+/// Hidden files like `.koto` or `.git` are not a part of patches.
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Removes files in `dir` except hidden ones.
+pub fn clean(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if is_hidden(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if `dir` has files except hidden ones.
+pub fn has_files(dir: &Path) -> io::Result<bool> {
+    if !dir.exists() {
+        return Ok(false);
+    }
+    for entry in fs::read_dir(dir)? {
+        if !is_hidden(&entry?.file_name().to_string_lossy()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Writes children of `node` into `dir`.
+///
+/// Settings and views like `.koto`, `xxx.trk` or `mute` are not written.
+pub fn export(node: Arc<Mutex<KotoNode>>, dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let children = KotoNode::get_children(node);
+    let names: Vec<String> = children.iter().map(|(name, _)| name.clone()).collect();
+    for (name, child) in children {
+        if KotoNode::is_control(child.clone()) || KotoNode::is_view(&name, &names) {
+            continue;
+        }
+        let path = dir.join(&name);
+        let (kind, data, link) = {
+            let child = child.lock().unwrap();
+            (child.attr.kind, child.data.clone(), child.link.clone())
+        };
+        match kind {
+            FileType::Directory => export(child, &path)?,
+            FileType::RegularFile => fs::write(&path, &data)?,
+            FileType::Symlink => {
+                if let Some(link) = link {
+                    std::os::unix::fs::symlink(link, &path)?;
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Sorts entries by parameter names, as `src2` comes before `src10`.
+fn sort_key(name: &str) -> (String, u64, String) {
+    let paramname = name.split('.').next().unwrap_or("");
+    let base = paramname.trim_end_matches(|c: char| c.is_ascii_digit());
+    let n = paramname[base.len()..].parse().unwrap_or(0);
+    (base.to_string(), n, name.to_string())
+}
+
+fn read_node(path: &Path, name: String) -> io::Result<Arc<Mutex<KotoNode>>> {
+    let meta = fs::symlink_metadata(path)?;
+    let node = if meta.file_type().is_symlink() {
+        let mut node = KotoNode::create_node(0, name, Vec::new(), FileType::Symlink);
+        node.link = Some(fs::read_link(path)?);
+        node
+    } else if meta.is_dir() {
+        KotoNode::create_node(0, name, Vec::new(), FileType::Directory)
+    } else {
+        KotoNode::create_node(0, name, fs::read(path)?, FileType::RegularFile)
+    };
+    let node = Arc::new(Mutex::new(node));
+
+    if meta.is_dir() {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if !is_hidden(&name) {
+                names.push(name);
+            }
+        }
+        names.sort_by_key(|name| sort_key(name));
+
+        // views are rebuilt from their sources, and stale ones must not overwrite them
+        let sources: Vec<String> = names
+            .iter()
+            .filter(|name| !KotoNode::is_view(name, &names))
+            .cloned()
+            .collect();
+        for name in sources {
+            let child = read_node(&path.join(&name), name.clone())?;
+            child.lock().unwrap().parent = Some(node.clone());
+            node.lock().unwrap().children.push((name, child));
+        }
+    }
+    Ok(node)
+}
+
+fn sync_symlinks(node: Arc<Mutex<KotoNode>>) {
+    for (_, child) in KotoNode::get_children(node) {
+        let kind = child.lock().unwrap().attr.kind;
+        match kind {
+            FileType::Symlink => KotoNode::sync_symlink(child),
+            FileType::Directory => sync_symlinks(child),
+            _ => (),
+        }
+    }
+}
+
+/// Builds a unit graph from a directory tree, whose root module is `typename`.
+pub fn import(
+    dir: &Path,
+    typename: &str,
+    transport: Arc<Mutex<Transport>>,
+) -> io::Result<Option<Aug>> {
+    // `KotoNode::build_ug_from_node()` takes module types from names like `xxx.yyy`
+    let root = read_node(dir, format!("root.{}", typename))?;
+    let ug = KotoNode::build_ug_from_node(root.clone(), transport);
+    // symlinks are set after all modules are built, because they may point forward
+    sync_symlinks(root);
+    Ok(ug)
+}
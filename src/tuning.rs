@@ -0,0 +1,460 @@
+//! Microtonal tuning by Scala scale (`.scl`) and keyboard mapping (`.kbm`) files.
+//!
+//! See http://www.huygens-fokker.org/scala/scl_format.html and
+//! http://www.huygens-fokker.org/scala/help.htm#mappings for these formats.
+//!
+//! A tuned sequencer plays its oscillator through `Tuned`, which retunes frequencies
+//! of 12-TET notes from the sequencer.
+
+use tapirus::musical_time::time::Transport;
+use tapirus::ugens::core::{
+    Aug, Dump, Operate, OperateError, Osc, Proc, Signal, UGen, UgNode, Walk, UG,
+};
+
+use crate::gate;
+
+/// Keyboard mappings are for MIDI notes, so they have 128 entries at most.
+const MAX_MAP_SIZE: usize = 128;
+
+/// The parameter name which returns the tuned oscillator, to find `Tuned` in the graph.
+const TUNED: &str = "tuned";
+
+#[derive(Debug)]
+pub enum TuningError {
+    MissingLine(&'static str),
+    InvalidPitch(String),
+    InvalidNumber(String),
+    MapTooLarge(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Scale {
+    /// Ratios of degrees from the 1st. `1/1` is not included and the last is the period.
+    pub ratios: Vec<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    pub size: usize,
+    pub first: i32,
+    pub last: i32,
+    pub middle: i32,
+    pub reference: i32,
+    pub freq: f64,
+    pub octave_degree: usize,
+    pub mapping: Vec<Option<i64>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    pub scale: Scale,
+    pub keymap: Keymap,
+}
+
+/// Returns lines except comments, which begin with `!`.
+fn lines(s: &str) -> impl Iterator<Item = &str> {
+    s.lines().filter(|line| !line.starts_with('!'))
+}
+
+fn parse_pitch(s: &str) -> Result<f64, TuningError> {
+    let word = match s.split_whitespace().next() {
+        Some(word) => word,
+        None => return Err(TuningError::InvalidPitch(s.to_string())),
+    };
+    let err = || TuningError::InvalidPitch(word.to_string());
+
+    if word.contains('.') {
+        // cents
+        let cents: f64 = word.parse().map_err(|_| err())?;
+        Ok(2.0f64.powf(cents / 1200.0))
+    } else if let Some(pos) = word.find('/') {
+        let num: f64 = word[..pos].parse().map_err(|_| err())?;
+        let den: f64 = word[pos + 1..].parse().map_err(|_| err())?;
+        if den == 0.0 {
+            return Err(err());
+        }
+        Ok(num / den)
+    } else {
+        word.parse().map_err(|_| err())
+    }
+}
+
+pub fn parse_scl(s: &str) -> Result<Scale, TuningError> {
+    let mut lines = lines(s);
+    lines
+        .next()
+        .ok_or(TuningError::MissingLine("description"))?;
+    let count = lines
+        .next()
+        .ok_or(TuningError::MissingLine("number of notes"))?;
+    let count: usize = count
+        .trim()
+        .parse()
+        .map_err(|_| TuningError::InvalidNumber(count.trim().to_string()))?;
+
+    let mut ratios = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = lines.next().ok_or(TuningError::MissingLine("pitch"))?;
+        ratios.push(parse_pitch(line.trim())?);
+    }
+    if ratios.len() == 0 {
+        // a scale of only 1/1
+        ratios.push(2.0);
+    }
+    Ok(Scale { ratios: ratios })
+}
+
+fn next_number<'a, T: std::str::FromStr>(
+    lines: &mut impl Iterator<Item = &'a str>,
+    name: &'static str,
+) -> Result<T, TuningError> {
+    let line = lines.next().ok_or(TuningError::MissingLine(name))?;
+    let word = line.split_whitespace().next().unwrap_or("");
+    word.parse()
+        .map_err(|_| TuningError::InvalidNumber(word.to_string()))
+}
+
+pub fn parse_kbm(s: &str) -> Result<Keymap, TuningError> {
+    let mut lines = lines(s);
+    let size = next_number(&mut lines, "map size")?;
+    let first = next_number(&mut lines, "first note")?;
+    let last = next_number(&mut lines, "last note")?;
+    let middle = next_number(&mut lines, "middle note")?;
+    let reference = next_number(&mut lines, "reference note")?;
+    let freq = next_number(&mut lines, "reference frequency")?;
+    let octave_degree = next_number(&mut lines, "octave degree")?;
+
+    if size > MAX_MAP_SIZE {
+        return Err(TuningError::MapTooLarge(size));
+    }
+    let mut mapping = Vec::with_capacity(size);
+    for _ in 0..size {
+        let line = lines.next().ok_or(TuningError::MissingLine("mapping"))?;
+        let word = line.split_whitespace().next().unwrap_or("x");
+        if word == "x" {
+            mapping.push(None);
+        } else {
+            let degree = word
+                .parse()
+                .map_err(|_| TuningError::InvalidNumber(word.to_string()))?;
+            mapping.push(Some(degree));
+        }
+    }
+
+    Ok(Keymap {
+        size: size,
+        first: first,
+        last: last,
+        middle: middle,
+        reference: reference,
+        freq: freq,
+        octave_degree: octave_degree,
+        mapping: mapping,
+    })
+}
+
+impl Keymap {
+    /// The linear mapping from the middle C, with A4 = 440 Hz.
+    pub fn linear(scale: &Scale) -> Keymap {
+        Keymap {
+            size: 0,
+            first: 0,
+            last: 127,
+            middle: 60,
+            reference: 69,
+            freq: 440.0,
+            octave_degree: scale.ratios.len(),
+            mapping: Vec::new(),
+        }
+    }
+}
+
+impl Tuning {
+    pub fn new(scale: Scale, keymap: Option<Keymap>) -> Tuning {
+        let keymap = keymap.unwrap_or(Keymap::linear(&scale));
+        Tuning {
+            scale: scale,
+            keymap: keymap,
+        }
+    }
+
+    fn degree(&self, midi: i32) -> Option<i64> {
+        let km = &self.keymap;
+        let offset = (midi - km.middle) as i64;
+        if km.size == 0 {
+            return Some(offset);
+        }
+        let size = km.size as i64;
+        let octave = offset.div_euclid(size);
+        let degree = km.mapping[offset.rem_euclid(size) as usize]?;
+        let octave_degree = if km.octave_degree == 0 {
+            self.scale.ratios.len() as i64
+        } else {
+            km.octave_degree as i64
+        };
+        Some(octave * octave_degree + degree)
+    }
+
+    fn ratio(&self, degree: i64) -> f64 {
+        let n = self.scale.ratios.len() as i64;
+        let period = self.scale.ratios[n as usize - 1];
+        let idx = degree.rem_euclid(n);
+        let ratio = if idx == 0 {
+            1.0
+        } else {
+            self.scale.ratios[idx as usize - 1]
+        };
+        period.powi(degree.div_euclid(n) as i32) * ratio
+    }
+
+    /// Returns the frequency of the MIDI note `midi`, or `None` if it's not mapped.
+    pub fn freq(&self, midi: i32) -> Option<f64> {
+        let km = &self.keymap;
+        if midi < km.first || midi > km.last {
+            return None;
+        }
+        let degree = self.degree(midi)?;
+        // the reference note may be unmapped, so it's computed as the linear mapping
+        let reference = self
+            .degree(km.reference)
+            .unwrap_or((km.reference - km.middle) as i64);
+        Some(km.freq * self.ratio(degree) / self.ratio(reference))
+    }
+}
+
+/// Returns the nearest MIDI note number of the 12-TET frequency `freq`.
+fn freq_to_midi(freq: f64) -> i32 {
+    (69.0 + 12.0 * (freq / 440.0).log2()).round() as i32
+}
+
+/// A unit which retunes the frequencies given to the oscillator `ug`.
+///
+/// It dumps as the oscillator itself, so saves don't have tunings.
+pub struct Tuned {
+    ug: Aug,
+    name: String,
+    tuning: Tuning,
+}
+
+impl Tuned {
+    pub fn new(ug: Aug, name: String, tuning: Tuning) -> Aug {
+        let tuned = Tuned {
+            ug: tuned_of(&ug).unwrap_or(ug),
+            name: name,
+            tuning: tuning,
+        };
+        Aug::new(UGen::new(UG::Osc(Box::new(tuned))))
+    }
+}
+
+/// Returns the oscillator in `ug`, or `None` if `ug` is not tuned.
+pub fn tuned_of(ug: &Aug) -> Option<Aug> {
+    ug.get(TUNED).ok()
+}
+
+/// Returns the name of the tuning of `ug`, or `None` if `ug` is not tuned.
+pub fn name_of(ug: &Aug) -> Option<String> {
+    tuned_of(ug)?;
+    ug.get_str("tuning").ok()
+}
+
+impl Walk for Tuned {
+    fn walk(&self, f: &mut dyn FnMut(&Aug) -> bool) {
+        if f(&self.ug) {
+            self.ug.walk(f);
+        }
+    }
+}
+
+impl Dump for Tuned {
+    fn dump(&self, shared: &Vec<Aug>) -> UgNode {
+        self.ug.dump(shared)
+    }
+}
+
+impl Operate for Tuned {
+    fn get(&self, pname: &str) -> Result<Aug, OperateError> {
+        match pname {
+            TUNED => Ok(self.ug.clone()),
+            _ => self.ug.get(pname),
+        }
+    }
+
+    fn get_str(&self, pname: &str) -> Result<String, OperateError> {
+        match pname {
+            "tuning" => Ok(self.name.clone()),
+            _ => self.ug.get_str(pname),
+        }
+    }
+
+    fn set(&mut self, pname: &str, ug: Aug) -> Result<Aug, OperateError> {
+        self.ug.set(pname, ug)
+    }
+
+    fn set_str(&mut self, pname: &str, data: String) -> Result<Aug, OperateError> {
+        self.ug.set_str(pname, data)
+    }
+
+    fn clear(&mut self, pname: &str) {
+        self.ug.clear(pname)
+    }
+}
+
+impl Proc for Tuned {
+    fn proc(&mut self, transport: &Transport) -> Signal {
+        self.ug.proc(transport)
+    }
+}
+
+impl Osc for Tuned {
+    fn set_freq(&mut self, freq: Aug) {
+        let hz = if let UG::Val(hz) = &freq.0.lock().unwrap().ug {
+            Some(*hz)
+        } else {
+            None
+        };
+        // unmapped notes and modulated frequencies are played as they are
+        let tuned = hz
+            .filter(|hz| *hz > 0.0)
+            .and_then(|hz| self.tuning.freq(freq_to_midi(hz)));
+        match tuned {
+            Some(hz) => gate::set_freq(&self.ug, Aug::val(hz)),
+            None => gate::set_freq(&self.ug, freq),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEANTONE: &str = "! meanquar.scl
+!
+1/4-comma meantone scale. Pietro Aaron's temperament (1523)
+ 12
+!
+ 76.04900
+ 193.15686
+ 310.26471
+ 5/4
+ 503.42157
+ 579.47057
+ 696.57843
+ 25/16
+ 889.73529
+ 1006.84314
+ 1082.89214
+ 2/1
+";
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_parse_pitch() {
+        assert_near(parse_pitch("1200.0").unwrap(), 2.0);
+        assert_near(parse_pitch("3/2 fifth").unwrap(), 1.5);
+        assert_near(parse_pitch("2").unwrap(), 2.0);
+        assert!(parse_pitch("1/0").is_err());
+        assert!(parse_pitch("").is_err());
+    }
+
+    #[test]
+    fn test_parse_scl() {
+        let scale = parse_scl(MEANTONE).unwrap();
+        assert_eq!(scale.ratios.len(), 12);
+        assert_near(scale.ratios[3], 1.25);
+        assert_near(scale.ratios[11], 2.0);
+
+        let scale = parse_scl("only the unison\n0\n").unwrap();
+        assert_eq!(scale.ratios, vec![2.0]);
+
+        match parse_scl("two notes\n2\n3/2\n") {
+            Err(TuningError::MissingLine("pitch")) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse_scl("no count\nmany\n") {
+            Err(TuningError::InvalidNumber(word)) => assert_eq!(word, "many"),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_linear_tuning() {
+        let tuning = Tuning::new(parse_scl(MEANTONE).unwrap(), None);
+        assert_near(tuning.freq(69).unwrap(), 440.0);
+        assert_near(tuning.freq(81).unwrap(), 880.0);
+        // C4 is a major third (5/4) below E4
+        let e4 = tuning.freq(64).unwrap();
+        assert_near(tuning.freq(60).unwrap() * 1.25, e4);
+        assert_eq!(tuning.freq(128), None);
+    }
+
+    #[test]
+    fn test_parse_kbm() {
+        // a white-key mapping of a 7-note scale, A4 = 432 Hz
+        let kbm = "! white keys
+12
+0
+127
+60
+69
+432.0
+7
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+        let keymap = parse_kbm(kbm).unwrap();
+        assert_eq!(keymap.size, 12);
+        assert_eq!(keymap.mapping[0], Some(0));
+        assert_eq!(keymap.mapping[1], None);
+        assert_eq!(keymap.mapping[11], Some(6));
+
+        let scale = parse_scl(
+            "7-TET\n7\n171.42857\n342.85714\n514.28571\n685.71429\n857.14286\n1028.57143\n2/1\n",
+        )
+        .unwrap();
+        let tuning = Tuning::new(scale, Some(keymap));
+        assert_near(tuning.freq(69).unwrap(), 432.0);
+        assert_near(tuning.freq(81).unwrap(), 864.0);
+        assert_eq!(tuning.freq(61), None);
+        // B4 is the next degree of A4
+        assert_near(tuning.freq(71).unwrap(), 432.0 * 2.0f64.powf(1.0 / 7.0));
+    }
+
+    #[test]
+    fn test_short_kbm() {
+        match parse_kbm("12\n0\n127\n60\n") {
+            Err(TuningError::MissingLine("reference note")) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse_kbm("3\n0\n127\n60\n69\n440\n3\n0\n") {
+            Err(TuningError::MissingLine("mapping")) => (),
+            result => panic!("{:?}", result),
+        }
+        match parse_kbm("4294967295\n0\n127\n60\n69\n440\n3\n") {
+            Err(TuningError::MapTooLarge(4294967295)) => (),
+            result => panic!("{:?}", result),
+        }
+        let keymap = parse_kbm("3\n0\n127\n60\n69\n440\n3\n0\nx\n2\n").unwrap();
+        assert_eq!(keymap.mapping, vec![Some(0), None, Some(2)]);
+    }
+
+    #[test]
+    fn test_freq_to_midi() {
+        assert_eq!(freq_to_midi(440.0), 69);
+        assert_eq!(freq_to_midi(261.6256), 60);
+        assert_eq!(freq_to_midi(450.0), 69);
+    }
+}
@@ -0,0 +1,245 @@
+#[derive(Debug)]
+pub enum WavError {
+    NotRiffWave,
+    MissingChunk(&'static str),
+    UnsupportedFormat(u16, u16),
+    Truncated,
+}
+
+struct Format {
+    format: u16,
+    channels: u16,
+    sample_rate: u32,
+    bits: u16,
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, WavError> {
+    if pos + 2 > data.len() {
+        return Err(WavError::Truncated);
+    }
+    Ok(u16::from_le_bytes([data[pos], data[pos + 1]]))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, WavError> {
+    if pos + 4 > data.len() {
+        return Err(WavError::Truncated);
+    }
+    Ok(u32::from_le_bytes([
+        data[pos],
+        data[pos + 1],
+        data[pos + 2],
+        data[pos + 3],
+    ]))
+}
+
+fn read_format(chunk: &[u8]) -> Result<Format, WavError> {
+    let mut format = read_u16(chunk, 0)?;
+    // WAVE_FORMAT_EXTENSIBLE has its actual format in the first two bytes of the sub format GUID
+    if format == 0xfffe {
+        format = read_u16(chunk, 24)?;
+    }
+    Ok(Format {
+        format: format,
+        channels: read_u16(chunk, 2)?,
+        sample_rate: read_u32(chunk, 4)?,
+        bits: read_u16(chunk, 14)?,
+    })
+}
+
+fn decode_sample(fmt: &Format, bytes: &[u8]) -> Result<f64, WavError> {
+    let sample = match (fmt.format, fmt.bits) {
+        (1, 8) => (bytes[0] as f64 - 128.0) / 128.0,
+        (1, 16) => i16::from_le_bytes([bytes[0], bytes[1]]) as f64 / 32768.0,
+        (1, 24) => {
+            let n = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+            n as f64 / 8388608.0
+        }
+        (1, 32) => {
+            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 / 2147483648.0
+        }
+        (3, 32) => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+        (3, 64) => {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&bytes[..8]);
+            f64::from_le_bytes(b)
+        }
+        (format, bits) => return Err(WavError::UnsupportedFormat(format, bits)),
+    };
+    Ok(sample)
+}
+
+/// Linear interpolating resampler.
+pub fn resample(samples: &[f64], from: u32, to: u32) -> Vec<f64> {
+    if from == to || samples.len() == 0 {
+        return samples.to_vec();
+    }
+
+    let ratio = from as f64 / to as f64;
+    let len = (samples.len() as f64 / ratio).round() as usize;
+    let mut resampled = Vec::with_capacity(len);
+    for i in 0..len {
+        let pos = i as f64 * ratio;
+        let idx = pos.floor() as usize;
+        let frac = pos - idx as f64;
+        let a = samples[idx.min(samples.len() - 1)];
+        let b = samples[(idx + 1).min(samples.len() - 1)];
+        resampled.push(a + (b - a) * frac);
+    }
+    resampled
+}
+
+/// Decodes a RIFF WAVE file, mixes it down to mono and resamples it at `sample_rate`.
+pub fn decode(data: &[u8], sample_rate: u32) -> Result<Vec<f64>, WavError> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(WavError::NotRiffWave);
+    }
+
+    let mut fmt = None;
+    let mut samples = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = read_u32(data, pos + 4)? as usize;
+        let body = pos + 8;
+        let end = (body + size).min(data.len());
+        match id {
+            b"fmt " => fmt = Some(read_format(&data[body..end])?),
+            b"data" => samples = Some(&data[body..end]),
+            _ => (),
+        }
+        // chunks are aligned to two bytes
+        pos = body + size + size % 2;
+    }
+
+    let fmt = fmt.ok_or(WavError::MissingChunk("fmt "))?;
+    let samples = samples.ok_or(WavError::MissingChunk("data"))?;
+    if fmt.channels == 0 || fmt.sample_rate == 0 || fmt.bits == 0 || fmt.bits % 8 != 0 {
+        return Err(WavError::UnsupportedFormat(fmt.format, fmt.bits));
+    }
+
+    let width = (fmt.bits / 8) as usize;
+    let frame_width = width * fmt.channels as usize;
+    let mut mono = Vec::with_capacity(samples.len() / frame_width);
+    for frame in samples.chunks_exact(frame_width) {
+        let mut sum = 0.0;
+        for ch in frame.chunks_exact(width) {
+            sum += decode_sample(&fmt, ch)?;
+        }
+        mono.push(sum / fmt.channels as f64);
+    }
+
+    Ok(resample(&mono, fmt.sample_rate, sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(format: u16, channels: u16, sample_rate: u32, bits: u16, data: &[u8]) -> Vec<u8> {
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&format.to_le_bytes());
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&sample_rate.to_le_bytes());
+        let block = channels as u32 * bits as u32 / 8;
+        fmt.extend_from_slice(&(sample_rate * block).to_le_bytes());
+        fmt.extend_from_slice(&(block as u16).to_le_bytes());
+        fmt.extend_from_slice(&bits.to_le_bytes());
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&((4 + 8 + fmt.len() + 8 + data.len()) as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&fmt);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(data);
+        wav
+    }
+
+    #[test]
+    fn test_pcm8() {
+        let data = wav(1, 1, 44100, 8, &[128, 192, 64]);
+        assert_eq!(decode(&data, 44100).unwrap(), vec![0.0, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn test_pcm16() {
+        let mut data = Vec::new();
+        for s in &[0i16, 16384, -32768] {
+            data.extend_from_slice(&s.to_le_bytes());
+        }
+        let data = wav(1, 1, 44100, 16, &data);
+        assert_eq!(decode(&data, 44100).unwrap(), vec![0.0, 0.5, -1.0]);
+    }
+
+    #[test]
+    fn test_pcm24() {
+        let data = wav(1, 1, 44100, 24, &[0x00, 0x00, 0x40, 0x00, 0x00, 0xc0]);
+        assert_eq!(decode(&data, 44100).unwrap(), vec![0.5, -0.5]);
+    }
+
+    #[test]
+    fn test_float() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0.25f32.to_le_bytes());
+        data.extend_from_slice(&(-0.75f32).to_le_bytes());
+        let data = wav(3, 1, 44100, 32, &data);
+        assert_eq!(decode(&data, 44100).unwrap(), vec![0.25, -0.75]);
+
+        let data = wav(3, 1, 44100, 64, &0.125f64.to_le_bytes());
+        assert_eq!(decode(&data, 44100).unwrap(), vec![0.125]);
+    }
+
+    #[test]
+    fn test_stereo_is_mixed() {
+        let mut data = Vec::new();
+        for s in &[16384i16, 0, -16384, -16384] {
+            data.extend_from_slice(&s.to_le_bytes());
+        }
+        let data = wav(1, 2, 44100, 16, &data);
+        assert_eq!(decode(&data, 44100).unwrap(), vec![0.25, -0.5]);
+    }
+
+    #[test]
+    fn test_resample() {
+        assert_eq!(
+            resample(&[0.0, 1.0], 22050, 44100),
+            vec![0.0, 0.5, 1.0, 1.0]
+        );
+        assert_eq!(
+            resample(&[0.0, 1.0, 2.0, 3.0], 44100, 22050),
+            vec![0.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        match decode(b"RIFX\0\0\0\0WAVE", 44100) {
+            Err(WavError::NotRiffWave) => (),
+            result => panic!("{:?}", result),
+        }
+        match decode(&wav(2, 1, 44100, 4, &[]), 44100) {
+            Err(WavError::UnsupportedFormat(2, 4)) => (),
+            result => panic!("{:?}", result),
+        }
+        match decode(&wav(2, 1, 44100, 16, &[0, 0]), 44100) {
+            Err(WavError::UnsupportedFormat(2, 16)) => (),
+            result => panic!("{:?}", result),
+        }
+        match decode(&wav(1, 1, 0, 16, &[0, 0]), 44100) {
+            Err(WavError::UnsupportedFormat(1, 16)) => (),
+            result => panic!("{:?}", result),
+        }
+        match decode(&wav(1, 0, 44100, 16, &[0, 0]), 44100) {
+            Err(WavError::UnsupportedFormat(1, 16)) => (),
+            result => panic!("{:?}", result),
+        }
+        let mut data = wav(1, 1, 44100, 16, &[]);
+        data.truncate(12);
+        match decode(&data, 44100) {
+            Err(WavError::MissingChunk("fmt ")) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}